@@ -1,157 +1,2664 @@
-use crate::agent::client::tailer::Tailer;
+use crate::agent::client::checkpoint::{Checkpoint, CheckpointStore};
+use crate::agent::client::config::{AgentConfig, FileConfig};
+use crate::agent::client::filter::EventFilter;
+use crate::agent::client::identity::AgentIdentity;
+use crate::agent::client::key_pool::{KeyPool, ServiceKeySource};
+use crate::agent::client::rate_limit::{
+    Clock, OverLimit, RateLimitMetrics, RateLimiter, SystemClock,
+};
+use crate::agent::client::redact::Redactor;
+use crate::agent::client::source::Source;
+use crate::agent::client::spool::{Spool, SpoolMetrics};
+use crate::agent::client::tailer::{path_identity, Tailer};
 use crate::agent::client::uploader::Uploader;
+use crate::agent::client::watcher::{Changes, Watcher};
 use crate::agent::protobuf::{
-    agent_service_client::AgentServiceClient, CreateKeysRequest, CreateKeysResponse,
-    DeleteKeysRequest, DeleteKeysResponse,
+    agent_service_client::AgentServiceClient, DeleteKeysRequest, DeleteKeysResponse,
+    GetAgentConfigRequest, HeartbeatRequest, RegisterAgentRequest,
 };
-use crate::error::Result;
+use crate::codec::Codec;
+use crate::error::{woodpecker_error, Result, WoodpeckerError};
+use crate::event::EventSplitter;
+use crate::ingress::schema::Schema;
+use crate::serde::envelope::{encode_segment, encode_upload, SegmentHeader, UploadHeader};
 use async_trait::async_trait;
-use log::debug;
-use tonic::transport::Channel;
+use glob::{MatchOptions, Pattern};
+use log::{debug, info, warn};
+use reqwest::StatusCode;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use tonic::transport::{Channel, Endpoint};
 
-// TODO: dynamic config from agent service
-/// Configure the behaviour of the agent.
-#[derive(Clone)]
-pub struct AgentConfig {
-    /// Where are the log files
-    // TODO: support multiple files
-    pub file: String,
-    /// How much buffer per file
-    pub buffer_size: usize,
-}
+/// Keys to create with the agent service at a time.
+const KEYS_PER_REQUEST: u32 = 10;
+/// Leave time for slow uploads before a presigned url expires.
+const KEY_EXPIRY_MARGIN: Duration = Duration::from_secs(5 * 60);
+/// How often to retry uploading spooled buffers.
+const DRAIN_INTERVAL: Duration = Duration::from_secs(5);
+/// How long to wait while all files are at end-of-file, doubling up to the max.
+const MIN_IDLE_BACKOFF: Duration = Duration::from_millis(50);
+const MAX_IDLE_BACKOFF: Duration = Duration::from_secs(2);
+/// Local failures in a row, e.g. to save checkpoints, after which the agent stops.
+const MAX_LOCAL_FAILURES: u32 = 5;
+/// Extensions of compressed files, e.g. rotated siblings compressed by logrotate.
+const COMPRESSED_EXTENSIONS: [&str; 5] = ["gz", "zst", "bz2", "xz", "zip"];
 
 #[async_trait]
-trait BufferHandler {
+trait BufferHandler: Send {
     async fn consume(&mut self, buffer: &[u8]) -> Result<()>;
+    /// Consume a buffer to upload along with others of the same key, e.g. of a schema.
+    async fn consume_keyed(&mut self, _key: &str, buffer: &[u8]) -> Result<()> {
+        self.consume(buffer).await
+    }
+    /// Act on time on every unit of work, e.g. flush a batch.
+    async fn poll(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// Handle everything consumed so far, e.g. before shutdown.
+    async fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// Whether some consumed buffers are not handled yet, so the agent should not
+    /// checkpoint past them.
+    fn has_pending(&self) -> bool {
+        false
+    }
+    /// Apply settings of a reloaded config, e.g. the flush interval.
+    fn reconfigure(&mut self, _config: &AgentConfig) {}
+}
+
+/// How the agent checks in with the agent service.
+#[async_trait]
+trait ControlPlane: Send {
+    /// Tell the service the agent is alive.
+    async fn heartbeat(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// Return the latest configuration, or None if nothing is configured for the agent.
+    async fn get_config(&mut self, current: &AgentConfig) -> Result<Option<AgentConfig>>;
 }
 
 /// The agent tails log files and upload them.
+/// Each call to work serves the next tailer in a round-robin fashion.
 // TODO: use a Consumer<Buffer> for better testability.
 // TODO: add test case around rotation.
 pub struct Agent {
-    tailer: Tailer,
+    config: AgentConfig,
+    splitter: EventSplitter,
+    tailers: Vec<Tailer>,
+    /// Sources other than files, e.g. stdin. Served after the tailers in turn.
+    sources: Vec<Box<dyn Source>>,
+    next: usize,
+    last_scan: Option<Instant>,
+    /// Last scan upon the creation of a file, which happen at most once per rescan interval.
+    last_woken_scan: Option<Instant>,
+    /// Tailers of files removed from the config, dropped once they reach end-of-file.
+    draining: HashSet<String>,
+    /// Device and inode of files left behind by rotation, not to tail again by another name.
+    rotated: HashSet<(u64, u64)>,
+    /// Wakes tailers upon changes to their files. None to poll them.
+    watcher: Option<Watcher>,
+    /// Paths of changed files to serve before the others.
+    woken: VecDeque<String>,
+    /// Limit across all files, if any.
+    rate_limiter: Option<RateLimiter>,
+    /// Limits of files by path.
+    file_rate_limiters: HashMap<String, RateLimiter>,
+    /// Fair shares of the global limit by path or source name, when dropping over it.
+    /// A file within its share keeps its events, so the others are dropped first.
+    share_limiters: HashMap<String, RateLimiter>,
+    /// Time of rate limiters.
+    clock: Arc<dyn Clock>,
+    /// When the first file held back by a rate limit may be read again.
+    throttled_until: Option<Instant>,
+    rate_limit_metrics: RateLimitMetrics,
+    /// Dropped buffers already reported.
+    reported_drops: u64,
+    redactor: Redactor,
+    /// Filters by index of the file config, None if without rules.
+    filters: Vec<Option<EventFilter>>,
+    /// Index of the first file config matching each tailed path.
+    file_index: HashMap<String, usize>,
+    /// Events dropped by filters by path.
+    filter_metrics: HashMap<String, u64>,
+    /// Schema of each tailed path, kept for files draining after removal from the config.
+    schema_ids: HashMap<String, String>,
     handler: Box<dyn BufferHandler>,
+    checkpoints: CheckpointStore,
+    /// Checkpoints to save once the handler has nothing pending.
+    pending_checkpoints: HashMap<String, Checkpoint>,
+    /// A segment the handler refused, to hand over again before reading anything else.
+    refused: Option<Refused>,
+    /// Spool of the handler and the task draining it, if any.
+    spool: Option<Arc<Mutex<Spool>>>,
+    drainer: Option<JoinHandle<()>>,
+    control_plane: Option<Box<dyn ControlPlane>>,
+    last_check_in: Instant,
+}
+
+/// A segment refused by the handler, e.g. while the spool is full,
+/// with the checkpoint to save once it is accepted.
+struct Refused {
+    /// Key to batch the segment by.
+    key: String,
+    segment: Vec<u8>,
+    name: String,
+    checkpoint: Option<Checkpoint>,
+}
+
+struct ServiceControlPlane {
+    client: AgentServiceClient<Channel>,
+    identity: AgentIdentity,
+    /// Whether the service knows the agent. Registration is retried upon heartbeat until then.
+    registered: bool,
+}
+
+impl ServiceControlPlane {
+    async fn register(&mut self) -> Result<()> {
+        let request = RegisterAgentRequest {
+            agent_id: self.identity.agent_id.clone(),
+            hostname: self.identity.hostname.clone(),
+            labels: self.identity.labels.clone(),
+            enrollment_token: self.identity.enrollment_token.clone(),
+        };
+        self.client.register_agent(request).await?;
+        self.registered = true;
+        info!("Registered as agent {}", self.identity.agent_id);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ControlPlane for ServiceControlPlane {
+    async fn heartbeat(&mut self) -> Result<()> {
+        if !self.registered {
+            self.register().await?;
+        }
+        let request = HeartbeatRequest {
+            agent_id: self.identity.agent_id.clone(),
+        };
+        let response = self.client.heartbeat(request).await?.into_inner();
+        if !response.registered {
+            // The service forgot about us, e.g. after a restart.
+            self.register().await?;
+        }
+        Ok(())
+    }
+
+    async fn get_config(&mut self, current: &AgentConfig) -> Result<Option<AgentConfig>> {
+        let request = GetAgentConfigRequest {
+            agent_id: self.identity.agent_id.clone(),
+        };
+        let response = self.client.get_agent_config(request).await?.into_inner();
+        Ok(response.config.map(|remote| current.merge(remote)))
+    }
 }
 
+#[derive(Clone)]
 struct BufferConsumer {
     client: AgentServiceClient<Channel>,
+    keys: KeyPool<ServiceKeySource>,
+    codec: Codec,
     uploader: Uploader,
+    agent_id: String,
+    hostname: String,
+    /// Put the upload header in front of each upload.
+    attach_metadata: bool,
 }
 
 #[async_trait]
 impl BufferHandler for BufferConsumer {
     async fn consume(&mut self, buffer: &[u8]) -> Result<()> {
         debug!("Buffer received: {:?}", buffer);
-        // Note: if I refactor the following snippet to another function,
-        // Rust compiler complains that there are two mutable references,
-        // one from read() and one from the other function.
-        // TODO: request specific number of keys, don't waste them.
-        // TODO: can we reuse keys across tailers?
-        let request = CreateKeysRequest {};
-        let response: CreateKeysResponse = self.client.create_keys(request).await?.into_inner();
-
-        let keys: Vec<String> = response.keys;
-        self.uploader.upload(&keys[0], buffer).await?;
+        let buffer = if self.attach_metadata {
+            let header = UploadHeader {
+                agent_id: self.agent_id.clone(),
+                hostname: self.hostname.clone(),
+                upload_time_ms: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |time| time.as_millis() as i64),
+            };
+            Cow::Owned(encode_upload(&header, buffer)?)
+        } else {
+            Cow::Borrowed(buffer)
+        };
+        let buffer = self.codec.compress(&buffer)?;
+        let buffer = buffer.as_slice();
+        let mut key = self.keys.take().await?;
+        match self.uploader.upload(&key, buffer).await {
+            Err(WoodpeckerError::UploadError(StatusCode::FORBIDDEN)) => {
+                // The url expired or its signature is no longer valid, so are the others.
+                warn!("Upload to {} is forbidden, retry with fresh keys", key);
+                self.keys.clear();
+                key = self.keys.take().await?;
+                self.uploader.upload(&key, buffer).await?;
+            }
+            result => result?,
+        }
 
         let request = DeleteKeysRequest {
-            keys: vec![keys[0].clone()],
+            keys: vec![key],
+            agent_id: self.agent_id.clone(),
         };
         let _response: DeleteKeysResponse = self.client.delete_keys(request).await?.into_inner();
         Ok(())
     }
+
+    /// Switch to keys for the new codec. Keys left in the old pool expire unused.
+    fn reconfigure(&mut self, config: &AgentConfig) {
+        if config.compression == self.codec {
+            return;
+        }
+        info!("Compress uploads with {:?}", config.compression);
+        self.codec = config.compression;
+        self.keys = KeyPool::new(
+            ServiceKeySource::new(self.client.clone(), self.agent_id.clone(), self.codec),
+            KEYS_PER_REQUEST,
+            KEY_EXPIRY_MARGIN,
+        );
+    }
+}
+
+/// Upload buffers with a handler, or spool them while uploads fail.
+/// Once anything is spooled, later buffers are spooled too to keep the order,
+/// until the drainer catches up.
+struct SpoolingHandler<H: BufferHandler> {
+    handler: H,
+    spool: Arc<Mutex<Spool>>,
+}
+
+#[async_trait]
+impl<H: BufferHandler> BufferHandler for SpoolingHandler<H> {
+    async fn consume(&mut self, buffer: &[u8]) -> Result<()> {
+        let is_spooling = !self.spool.lock().unwrap().is_empty();
+        if !is_spooling {
+            match self.handler.consume(buffer).await {
+                Ok(()) => return Ok(()),
+                Err(e) => warn!("Failed to upload, spool the buffer instead: {}", e),
+            }
+        }
+        self.spool.lock().unwrap().push(buffer)
+    }
+
+    fn reconfigure(&mut self, config: &AgentConfig) {
+        self.handler.reconfigure(config);
+    }
+}
+
+/// Upload spooled buffers in order, stopping at the first failure.
+async fn drain<H: BufferHandler>(spool: &Mutex<Spool>, handler: &mut H) -> Result<()> {
+    loop {
+        let next = spool.lock().unwrap().peek()?;
+        let (seq, buffer) = match next {
+            Some(next) => next,
+            None => return Ok(()),
+        };
+        handler.consume(&buffer).await?;
+        let mut spool = spool.lock().unwrap();
+        spool.pop(seq)?;
+        if spool.is_empty() {
+            info!("Drained spool: {:?}", spool.metrics());
+        }
+    }
+}
+
+/// Drain the spool in the background whenever the backend is reachable.
+async fn drain_forever<H: BufferHandler>(spool: Arc<Mutex<Spool>>, mut handler: H) {
+    loop {
+        if let Err(e) = drain(&spool, &mut handler).await {
+            warn!("Failed to drain spool: {}", e);
+        }
+        sleep(DRAIN_INTERVAL).await;
+    }
+}
+
+/// Accumulate buffers by key and hand each batch over once it is large enough
+/// or old enough, so that neither busy nor quiet files produce many small uploads,
+/// and an upload holds buffers of a single schema or source.
+/// Buffers are appended as they are: the agent ends every event with a new line,
+/// and framed segments must stay intact.
+/// While the handler fails, a batch grows to at most max_bytes and one more buffer,
+/// then further buffers of its key are refused.
+struct BatchingHandler<H: BufferHandler> {
+    handler: H,
+    batches: BTreeMap<String, Batch>,
+    max_bytes: usize,
+    flush_interval: Duration,
+}
+
+struct Batch {
+    bytes: Vec<u8>,
+    /// When the first buffer of the batch arrived.
+    started: Instant,
+}
+
+impl<H: BufferHandler> BatchingHandler<H> {
+    fn new(handler: H, max_bytes: usize, flush_interval: Duration) -> BatchingHandler<H> {
+        BatchingHandler {
+            handler,
+            batches: BTreeMap::new(),
+            max_bytes,
+            flush_interval,
+        }
+    }
+
+    fn is_due(&self, batch: &Batch) -> bool {
+        batch.bytes.len() >= self.max_bytes || batch.started.elapsed() >= self.flush_interval
+    }
+
+    fn is_full(&self, key: &str) -> bool {
+        matches!(self.batches.get(key), Some(batch) if batch.bytes.len() >= self.max_bytes)
+    }
+
+    /// Hand the batch of key over, keeping it if the handler fails.
+    async fn flush_batch(&mut self, key: &str) -> Result<()> {
+        let batch = match self.batches.get(key) {
+            Some(batch) => batch,
+            None => return Ok(()),
+        };
+        debug!("Flush a batch of {} bytes of {:?}", batch.bytes.len(), key);
+        self.handler.consume(&batch.bytes).await?;
+        self.batches.remove(key);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<H: BufferHandler> BufferHandler for BatchingHandler<H> {
+    async fn consume(&mut self, buffer: &[u8]) -> Result<()> {
+        self.consume_keyed("", buffer).await
+    }
+
+    async fn consume_keyed(&mut self, key: &str, buffer: &[u8]) -> Result<()> {
+        // Refuse the buffer unless a full batch makes room first.
+        if self.is_full(key) {
+            self.flush_batch(key).await?;
+        }
+        let batch = self
+            .batches
+            .entry(key.to_string())
+            .or_insert_with(|| Batch {
+                bytes: vec![],
+                started: Instant::now(),
+            });
+        batch.bytes.extend_from_slice(buffer);
+        // The buffer is accepted, so retry a failed flush later instead of failing.
+        if self.is_due(&self.batches[key]) {
+            if let Err(e) = self.flush_batch(key).await {
+                warn!("Failed to flush a batch, retry later: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn poll(&mut self) -> Result<()> {
+        let due: Vec<String> = self
+            .batches
+            .iter()
+            .filter(|(_, batch)| self.is_due(batch))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in due {
+            self.flush_batch(&key).await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        let keys: Vec<String> = self.batches.keys().cloned().collect();
+        for key in keys {
+            self.flush_batch(&key).await?;
+        }
+        Ok(())
+    }
+
+    fn has_pending(&self) -> bool {
+        !self.batches.is_empty()
+    }
+
+    fn reconfigure(&mut self, config: &AgentConfig) {
+        self.max_bytes = config.batch_size;
+        self.flush_interval = config.flush_interval;
+        self.handler.reconfigure(config);
+    }
 }
 
 impl Agent {
     pub async fn try_new(config: AgentConfig) -> Result<Agent> {
-        let tailer = Tailer::try_new(&config.file, config.buffer_size)?;
+        let identity = AgentIdentity::load_or_create(
+            &config.identity_file,
+            config.labels.clone(),
+            &config.enrollment_token,
+        )?;
+        // Connect upon first request, so the agent starts while the service is unavailable.
+        let url = config.agent_service_url.clone();
+        let channel = Endpoint::from_shared(url.clone())
+            .map_err(|e| {
+                woodpecker_error(format!("Invalid agent service url {}: {}", url, e).as_str())
+            })?
+            .connect_lazy()?;
+        let client = AgentServiceClient::new(channel);
+        let mut control_plane = ServiceControlPlane {
+            client: client.clone(),
+            identity: identity.clone(),
+            registered: false,
+        };
+        if let Err(e) = control_plane.register().await {
+            warn!("Failed to register, retry upon check-in: {}", e);
+        }
 
-        // TODO: service discovery
-        let client = AgentServiceClient::connect("http://[::1]:50051").await?;
+        let keys = KeyPool::new(
+            ServiceKeySource::new(
+                client.clone(),
+                identity.agent_id.clone(),
+                config.compression,
+            ),
+            KEYS_PER_REQUEST,
+            KEY_EXPIRY_MARGIN,
+        );
+        let consumer = BufferConsumer {
+            client,
+            keys,
+            codec: config.compression,
+            uploader: Uploader::try_new(config.upload_retry.clone())?,
+            agent_id: identity.agent_id,
+            hostname: identity.hostname,
+            attach_metadata: config.attach_metadata,
+        };
+        let spool = Spool::try_new(&config.spool_dir, config.spool_max_bytes)?;
+        let spool = Arc::new(Mutex::new(spool));
+        let drainer = tokio::spawn(drain_forever(spool.clone(), consumer.clone()));
+        let handler = Box::new(BatchingHandler::new(
+            SpoolingHandler {
+                handler: consumer,
+                spool: spool.clone(),
+            },
+            config.batch_size,
+            config.flush_interval,
+        ));
+        let mut agent = Agent::new(config, handler)?;
+        agent.spool = Some(spool);
+        agent.drainer = Some(drainer);
+        agent.control_plane = Some(Box::new(control_plane));
+        Ok(agent)
+    }
 
-        Ok(Agent {
-            tailer,
-            handler: Box::new(BufferConsumer {
-                client,
-                uploader: Uploader::default(),
-            }),
-        })
+    fn new(config: AgentConfig, handler: Box<dyn BufferHandler>) -> Result<Agent> {
+        let checkpoints = CheckpointStore::try_new(&config.checkpoint_file)?;
+        let splitter = EventSplitter::try_new(&config.event_boundary)?;
+        let redactor = Redactor::try_new(&config.redactions, &config.redaction_key)?;
+        let filters = compile_filters(&config.files)?;
+        let watcher = if config.watch_files {
+            match Watcher::try_new() {
+                Ok(watcher) => Some(watcher),
+                Err(e) => {
+                    warn!("Failed to watch files, poll them instead: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let sources = config
+            .sources
+            .iter()
+            .map(|source| {
+                info!("Read {}", source.name());
+                source.open(
+                    splitter.clone(),
+                    config.buffer_size,
+                    checkpoints.get(&source.name()),
+                )
+            })
+            .collect::<Result<_>>()?;
+        let mut agent = Agent {
+            config,
+            splitter,
+            tailers: vec![],
+            sources,
+            next: 0,
+            last_scan: None,
+            last_woken_scan: None,
+            draining: HashSet::new(),
+            rotated: HashSet::new(),
+            watcher,
+            woken: VecDeque::new(),
+            rate_limiter: None,
+            file_rate_limiters: HashMap::new(),
+            share_limiters: HashMap::new(),
+            clock: Arc::new(SystemClock),
+            throttled_until: None,
+            rate_limit_metrics: RateLimitMetrics::default(),
+            reported_drops: 0,
+            redactor,
+            filters,
+            file_index: HashMap::new(),
+            filter_metrics: HashMap::new(),
+            schema_ids: HashMap::new(),
+            handler,
+            checkpoints,
+            pending_checkpoints: HashMap::new(),
+            refused: None,
+            spool: None,
+            drainer: None,
+            control_plane: None,
+            last_check_in: Instant::now(),
+        };
+        agent.scan()?;
+        Ok(agent)
+    }
+
+    /// Start a tailer for every file matching the patterns that is not tailed yet.
+    /// Skip compressed files, and files already tailed or read to the end under another name,
+    /// e.g. app.log.1 renamed from app.log.
+    fn scan(&mut self) -> Result<()> {
+        let tailed: HashSet<String> = self
+            .tailers
+            .iter()
+            .map(|tailer| tailer.path().to_string())
+            .collect();
+        let mut identities = HashSet::new();
+        for tailer in self.tailers.iter() {
+            identities.insert(tailer.identity()?);
+        }
+        let mut matched = vec![];
+        for pattern in self.config.files.iter().map(|file| &file.pattern) {
+            let paths = glob::glob(pattern).map_err(|e| {
+                woodpecker_error(format!("Invalid pattern {}: {}", pattern, e).as_str())
+            })?;
+            // Skip paths we cannot access, e.g. deleted during the scan.
+            for path in paths.flatten() {
+                if let Some(path) = path.to_str() {
+                    if path.is_empty() || !Path::new(path).is_file() {
+                        continue;
+                    }
+                    matched.push(path.to_string());
+                }
+            }
+        }
+        matched.sort();
+        matched.dedup();
+        let mut seen = HashSet::new();
+        for path in matched {
+            if tailed.contains(&path) {
+                continue;
+            }
+            if is_compressed(&path) {
+                debug!("Skip compressed file {}", path);
+                continue;
+            }
+            let identity = match path_identity(&path) {
+                Ok(identity) => identity,
+                // Deleted during the scan.
+                Err(_) => continue,
+            };
+            seen.insert(identity);
+            if identities.contains(&identity) || self.rotated.contains(&identity) {
+                debug!("Skip {}, tailed under another name", path);
+                continue;
+            }
+            info!("Start tailing {}", path);
+            let tailer = match Tailer::try_new(
+                &path,
+                self.config.buffer_size,
+                self.splitter.clone(),
+                self.checkpoints.get(&path),
+            ) {
+                Ok(tailer) => tailer,
+                // Try again on the next scan, e.g. once permissions are fixed.
+                Err(e) => {
+                    warn!("Failed to tail {}: {}", path, e);
+                    continue;
+                }
+            };
+            let tailer = tailer
+                .with_quiet_period(self.config.rotation_quiet_period)
+                .with_event_timeout(self.config.event_timeout);
+            self.tailers.push(tailer);
+            watch(&mut self.watcher, &path);
+        }
+        // Forget rotated files once they no longer match, e.g. compressed or deleted.
+        self.rotated.retain(|identity| seen.contains(identity));
+        // Forget checkpoints of deleted files, but not of sources other than files.
+        let sources: HashSet<String> = self.config.sources.iter().map(|s| s.name()).collect();
+        self.checkpoints.retain(|checkpoint| {
+            checkpoint.cursor.is_some()
+                || tailed.contains(&checkpoint.path)
+                || sources.contains(&checkpoint.path)
+                || Path::new(&checkpoint.path).exists()
+        })?;
+        self.update_file_settings()?;
+        self.last_scan = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Apply the config of the first pattern each file matches, e.g. its rate limit.
+    /// Create limiters for new files and changed limits. Keep the others with their state.
+    fn update_file_settings(&mut self) -> Result<()> {
+        let clock = self.clock.clone();
+        self.rate_limiter = match self.rate_limiter.take() {
+            _ if self.config.rate_limit == 0 => None,
+            Some(limiter) if limiter.bytes_per_second() == self.config.rate_limit => Some(limiter),
+            _ => Some(RateLimiter::new(self.config.rate_limit).with_clock(clock.clone())),
+        };
+
+        let mut shares = HashMap::new();
+        if self.config.rate_limit > 0 && self.config.over_limit == OverLimit::Drop {
+            let names: Vec<String> = self
+                .tailers
+                .iter()
+                .map(|tailer| tailer.path().to_string())
+                .chain(self.sources.iter().map(|source| source.name().to_string()))
+                .collect();
+            let share = (self.config.rate_limit / names.len().max(1) as u64).max(1);
+            for name in names {
+                let limiter = match self.share_limiters.remove(&name) {
+                    Some(limiter) if limiter.bytes_per_second() == share => limiter,
+                    _ => RateLimiter::new(share).with_clock(clock.clone()),
+                };
+                shares.insert(name, limiter);
+            }
+        }
+        self.share_limiters = shares;
+
+        let mut patterns = Vec::with_capacity(self.config.files.len());
+        for file in self.config.files.iter() {
+            let pattern = Pattern::new(&file.pattern).map_err(|e| {
+                woodpecker_error(format!("Invalid pattern {}: {}", file.pattern, e).as_str())
+            })?;
+            patterns.push(pattern);
+        }
+        let mut limiters = HashMap::new();
+        let mut schema_ids = HashMap::new();
+        self.file_index.clear();
+        for tailer in self.tailers.iter() {
+            let index = patterns
+                .iter()
+                .position(|pattern| pattern.matches_with(tailer.path(), match_options()));
+            let index = match index {
+                Some(index) => index,
+                // Draining after removal from the config.
+                None => {
+                    if let Some(schema_id) = self.schema_ids.remove(tailer.path()) {
+                        schema_ids.insert(tailer.path().to_string(), schema_id);
+                    }
+                    continue;
+                }
+            };
+            self.file_index.insert(tailer.path().to_string(), index);
+            schema_ids.insert(
+                tailer.path().to_string(),
+                self.config.files[index].schema_id.clone(),
+            );
+            let limit = self.config.files[index].rate_limit;
+            if limit == 0 {
+                continue;
+            }
+            let limiter = match self.file_rate_limiters.remove(tailer.path()) {
+                Some(limiter) if limiter.bytes_per_second() == limit => limiter,
+                _ => RateLimiter::new(limit).with_clock(clock.clone()),
+            };
+            limiters.insert(tailer.path().to_string(), limiter);
+        }
+        self.file_rate_limiters = limiters;
+        self.schema_ids = schema_ids;
+        Ok(())
+    }
+
+    /// How long until the file may be read again under its limit and the global one.
+    /// A file within its fair share is not held to the global limit.
+    fn rate_limit_wait(&mut self, path: &str) -> Duration {
+        let mut global = self
+            .rate_limiter
+            .as_mut()
+            .map_or(Duration::from_secs(0), |limiter| limiter.wait_time());
+        if let Some(share) = self.share_limiters.get_mut(path) {
+            global = global.min(share.wait_time());
+        }
+        let file = self
+            .file_rate_limiters
+            .get_mut(path)
+            .map_or(Duration::from_secs(0), |limiter| limiter.wait_time());
+        global.max(file)
+    }
+
+    pub fn rate_limit_metrics(&self) -> RateLimitMetrics {
+        self.rate_limit_metrics.clone()
+    }
+
+    /// Counters of the spool, empty without one.
+    pub fn spool_metrics(&self) -> SpoolMetrics {
+        self.spool
+            .as_ref()
+            .map_or_else(SpoolMetrics::default, |spool| {
+                spool.lock().unwrap().metrics()
+            })
+    }
+
+    /// Events dropped by filters by path.
+    pub fn filter_metrics(&self) -> HashMap<String, u64> {
+        self.filter_metrics.clone()
+    }
+
+    /// Matches redacted by rule.
+    pub fn redaction_metrics(&self) -> HashMap<String, u64> {
+        self.redactor.metrics()
+    }
+
+    fn should_scan(&self) -> bool {
+        match self.last_scan {
+            Some(last_scan) => last_scan.elapsed() >= self.config.rescan_interval,
+            None => true,
+        }
     }
 
     // Diff new config with existing one.
     // For any added file, create a new tailer.
     // For any removed file, only remove once current tailer reads fully.
-    fn reload(&self, _config: AgentConfig) {
-        todo!()
+    // Validate the new config before applying any of it, so an invalid one leaves the
+    // current config in place. Live tailers switch to the new event boundary.
+    fn reload(&mut self, config: AgentConfig) -> Result<()> {
+        let mut patterns = Vec::with_capacity(config.files.len());
+        for pattern in config.files.iter().map(|file| &file.pattern) {
+            patterns.push(Pattern::new(pattern).map_err(|e| {
+                woodpecker_error(format!("Invalid pattern {}: {}", pattern, e).as_str())
+            })?);
+        }
+        let splitter = EventSplitter::try_new(&config.event_boundary)?;
+        let filters = compile_filters(&config.files)?;
+        if config.redactions != self.config.redactions
+            || config.redaction_key != self.config.redaction_key
+        {
+            self.redactor
+                .update(&config.redactions, &config.redaction_key)?;
+        }
+        self.draining.clear();
+        for tailer in &self.tailers {
+            if !patterns
+                .iter()
+                .any(|pattern| pattern.matches_with(tailer.path(), match_options()))
+            {
+                info!("Drain {} removed from config", tailer.path());
+                self.draining.insert(tailer.path().to_string());
+            }
+        }
+        for tailer in self.tailers.iter_mut() {
+            tailer.set_splitter(splitter.clone());
+        }
+        self.splitter = splitter;
+        self.filters = filters;
+        self.handler.reconfigure(&config);
+        if config.sources != self.config.sources {
+            warn!("Changes to sources take effect upon restart");
+        }
+        self.config = config;
+        self.scan()
     }
 
-    // TODO: return something to indicate end of file or not.
-    pub async fn work(&mut self) -> Result<()> {
-        match self.tailer.read()? {
-            Some(buffer) => {
-                self.handler.consume(buffer).await?;
+    /// Send a heartbeat, then poll for configuration and reload upon changes.
+    /// Keep the current config if the agent service is unavailable.
+    async fn check_in(&mut self) -> Result<()> {
+        self.last_check_in = Instant::now();
+        if self.rate_limit_metrics.dropped_buffers > self.reported_drops {
+            warn!("Dropped over rate limits: {:?}", self.rate_limit_metrics);
+            self.reported_drops = self.rate_limit_metrics.dropped_buffers;
+        }
+        let control_plane = match &mut self.control_plane {
+            Some(control_plane) => control_plane,
+            None => return Ok(()),
+        };
+        if let Err(e) = control_plane.heartbeat().await {
+            warn!("Failed to send heartbeat: {}", e);
+            return Ok(());
+        }
+        match control_plane.get_config(&self.config).await {
+            Ok(Some(config)) => {
+                if config != self.config {
+                    info!("Reload config with files: {:?}", config.files);
+                    if let Err(e) = self.reload(config) {
+                        warn!("Keep the current config, failed to reload: {}", e);
+                    }
+                }
                 Ok(())
             }
-            None => {
-                debug!("Reached end of file");
-                if self.tailer.is_rotated()? {
-                    debug!("Rotate to new file");
-                    self.tailer.rotate()?;
-                }
+            Ok(None) => Ok(()),
+            Err(e) => {
+                warn!("Failed to get agent config: {}", e);
                 Ok(())
             }
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::agent::client::agent::{Agent, BufferHandler};
-    use crate::agent::client::tailer::Tailer;
-    use crate::error::Result;
-    use async_trait::async_trait;
-    use std::io::Write;
-    use std::sync::{Arc, Mutex};
-    use tempfile::NamedTempFile;
+    /// Save checkpoints only once their buffers are handled, e.g. uploaded in a batch.
+    fn save_checkpoints(&mut self) -> Result<()> {
+        if self.handler.has_pending() {
+            return Ok(());
+        }
+        // Keep a checkpoint pending until it is saved, to retry upon failure.
+        while let Some(path) = self.pending_checkpoints.keys().next().cloned() {
+            self.checkpoints
+                .save(self.pending_checkpoints[&path].clone())?;
+            self.pending_checkpoints.remove(&path);
+        }
+        Ok(())
+    }
 
-    /// Collect all buffer consumed for comparison later.
-    struct BufferCollector {
-        buffer: Arc<Mutex<Vec<u8>>>,
+    /// Work until shutdown completes, backing off while all files are at end-of-file.
+    /// With a watcher, wait for changes to the files instead, polling only as a fallback.
+    /// Upon shutdown, flush pending buffers and save checkpoints before returning.
+    /// Retry failures to reach the backend for as long as they last, but return the error
+    /// once local failures keep repeating.
+    pub async fn run<F: Future<Output = ()>>(&mut self, shutdown: F) -> Result<()> {
+        tokio::pin!(shutdown);
+        let mut idle_rounds = 0;
+        let mut backoff = MIN_IDLE_BACKOFF;
+        let mut local_failures = 0;
+        loop {
+            if futures::poll!(&mut shutdown).is_ready() {
+                break;
+            }
+            match self.work().await {
+                Ok(true) => {
+                    idle_rounds = 0;
+                    local_failures = 0;
+                    backoff = MIN_IDLE_BACKOFF;
+                    continue;
+                }
+                Ok(false) => {
+                    idle_rounds += 1;
+                    local_failures = 0;
+                }
+                Err(e) if is_backend_error(&e) => {
+                    warn!("Failed to reach the backend, retry: {}", e);
+                    idle_rounds = self.tailers.len();
+                }
+                Err(e) => {
+                    local_failures += 1;
+                    if local_failures >= MAX_LOCAL_FAILURES {
+                        if let Some(drainer) = self.drainer.take() {
+                            drainer.abort();
+                        }
+                        return Err(e);
+                    }
+                    warn!("Failed to work, retry: {}", e);
+                    idle_rounds = self.tailers.len();
+                }
+            }
+            // Only back off after every tailer had a turn without data.
+            if idle_rounds < self.tailers.len() {
+                continue;
+            }
+            let mut wait = if self.watcher.is_some() {
+                MAX_IDLE_BACKOFF
+            } else {
+                backoff
+            };
+            // Come back in time for files held back by rate limits.
+            if let Some(until) = self.throttled_until.take() {
+                wait = wait.min(until.saturating_duration_since(Instant::now()));
+            }
+            tokio::select! {
+                _ = &mut shutdown => break,
+                changed = changed(&mut self.watcher) => match changed {
+                    Ok(Changes::Paths(paths)) => {
+                        self.woken.extend(paths);
+                        idle_rounds = 0;
+                        backoff = MIN_IDLE_BACKOFF;
+                        continue;
+                    }
+                    Ok(Changes::Overflow) => {
+                        warn!("Lost file events, scan and read all files");
+                        self.woken.extend(self.tailers.iter().map(|tailer| tailer.path().to_string()));
+                        self.last_scan = None;
+                        idle_rounds = 0;
+                        backoff = MIN_IDLE_BACKOFF;
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!("Failed to watch files, poll them instead: {}", e);
+                        self.watcher = None;
+                    }
+                },
+                _ = sleep(wait) => {}
+            }
+            backoff = (backoff * 2).min(MAX_IDLE_BACKOFF);
+        }
+        info!("Shutting down");
+        if let Some(drainer) = self.drainer.take() {
+            drainer.abort();
+        }
+        self.handler.flush().await?;
+        self.save_checkpoints()
     }
 
-    #[async_trait]
-    impl BufferHandler for BufferCollector {
-        async fn consume(&mut self, buffer: &[u8]) -> Result<()> {
-            let mut buf = self.buffer.lock().unwrap();
-            buf.extend_from_slice(buffer);
-            Ok(())
+    /// Hand a segment over to the handler, then checkpoint past it.
+    /// Hold the segment if the handler refuses it, e.g. while the spool is full,
+    /// so that nothing is lost nor read past until it is accepted.
+    async fn hand_over(
+        &mut self,
+        key: String,
+        segment: Option<Vec<u8>>,
+        name: String,
+        checkpoint: Option<Checkpoint>,
+    ) -> Result<()> {
+        if let Some(segment) = segment {
+            if let Err(e) = self.handler.consume_keyed(&key, &segment).await {
+                warn!("Hold back {} until the handler accepts it: {}", name, e);
+                self.refused = Some(Refused {
+                    key,
+                    segment,
+                    name,
+                    checkpoint,
+                });
+                return Ok(());
+            }
         }
+        if let Some(checkpoint) = checkpoint {
+            self.pending_checkpoints.insert(name, checkpoint);
+        }
+        Ok(())
     }
 
-    fn init() {
-        let _ = env_logger::builder().is_test(true).try_init();
+    /// Hand the refused segment, if any, over again. Return whether it is accepted.
+    async fn retry_refused(&mut self) -> bool {
+        let refused = match self.refused.take() {
+            Some(refused) => refused,
+            None => return true,
+        };
+        if let Err(e) = self
+            .handler
+            .consume_keyed(&refused.key, &refused.segment)
+            .await
+        {
+            debug!("Still hold back {}: {}", refused.name, e);
+            self.refused = Some(refused);
+            return false;
+        }
+        info!("Resume reading after {} was accepted", refused.name);
+        if let Some(checkpoint) = refused.checkpoint {
+            self.pending_checkpoints.insert(refused.name, checkpoint);
+        }
+        true
     }
 
-    #[tokio::test]
-    async fn read_all() -> Result<()> {
-        init();
+    /// Index of the tailer of the next changed file, if any.
+    fn next_woken(&mut self) -> Option<usize> {
+        while let Some(path) = self.woken.pop_front() {
+            match self.tailers.iter().position(|tailer| tailer.path() == path) {
+                Some(i) => return Some(i),
+                None => self.scan_for(&path),
+            }
+        }
+        None
+    }
 
-        let content = b"Mary had a little lamb\nLittle lamb, little lamb";
-        let mut temp_file = NamedTempFile::new()?;
-        temp_file.write(content)?;
+    /// Scan now for a new file matching a pattern, unless a new file already triggered
+    /// a scan within the rescan interval, e.g. while many files are created.
+    /// The periodic scan picks up the others.
+    fn scan_for(&mut self, path: &str) {
+        let is_match = self.config.files.iter().any(|file| {
+            Pattern::new(&file.pattern)
+                .is_ok_and(|pattern| pattern.matches_with(path, match_options()))
+        });
+        let is_due = match self.last_woken_scan {
+            Some(last) => last.elapsed() >= self.config.rescan_interval,
+            None => true,
+        };
+        if is_match && is_due {
+            debug!("Scan for new file {}", path);
+            self.last_scan = None;
+            self.last_woken_scan = Some(Instant::now());
+        }
+    }
 
-        let path_str = temp_file.path().to_str().unwrap();
-        let tailer = Tailer::try_new(path_str, 10)?;
-        let buf = Arc::new(Mutex::new(vec![]));
-        let mut agent = Agent {
-            tailer,
-            handler: Box::new(BufferCollector {
-                buffer: buf.clone(),
-            }),
+    /// Keep the round-robin on the tailer or source after the one removed at index i.
+    fn removed(&mut self, i: usize) {
+        if i < self.next {
+            self.next -= 1;
+        }
+    }
+
+    /// Serve a source other than a file, like a tailer without filters or a limit of its own.
+    /// Drop the source once it is closed.
+    async fn work_source(&mut self, i: usize, over_limit: bool) -> Result<bool> {
+        let source = &mut self.sources[i];
+        let name = source.name().to_string();
+        let redactor = &self.redactor;
+        let read = source.read_split_by(&|buffer| redactor.split_point(buffer))?;
+        match read {
+            Some(buffer) if over_limit => {
+                debug!("Drop {} bytes of {} over rate limit", buffer.len(), name);
+                self.rate_limit_metrics.dropped_buffers += 1;
+                self.rate_limit_metrics.dropped_bytes += buffer.len() as u64;
+                if let Some(checkpoint) = source.checkpoint() {
+                    self.pending_checkpoints.insert(name, checkpoint);
+                }
+                self.save_checkpoints()?;
+                Ok(true)
+            }
+            Some(buffer) => {
+                let raw_len = buffer.len();
+                if let Some(limiter) = self.rate_limiter.as_mut() {
+                    limiter.consume(raw_len);
+                }
+                if let Some(limiter) = self.share_limiters.get_mut(&name) {
+                    limiter.consume(raw_len);
+                }
+                let payload = self.redactor.redact(buffer).into_owned();
+                let schema_id = source.schema_id();
+                let segment = segment(&self.config, &name, schema_id, None, raw_len, payload)?;
+                let checkpoint = source.checkpoint();
+                self.hand_over(name.clone(), Some(segment), name, checkpoint)
+                    .await?;
+                self.save_checkpoints()?;
+                Ok(true)
+            }
+            None => {
+                if !source.is_closed() {
+                    return Ok(false);
+                }
+                // End the last event with a new line, if it lacks one.
+                let segment = match source.flush() {
+                    Some(buffer) => {
+                        let raw_len = buffer.len();
+                        let mut event = self.redactor.redact(buffer).into_owned();
+                        if !event.ends_with(b"\n") {
+                            event.push(b'\n');
+                        }
+                        let schema_id = source.schema_id();
+                        Some(segment(
+                            &self.config,
+                            &name,
+                            schema_id,
+                            None,
+                            raw_len,
+                            event,
+                        )?)
+                    }
+                    None => None,
+                };
+                let checkpoint = source.checkpoint();
+                self.hand_over(name.clone(), segment, name.clone(), checkpoint)
+                    .await?;
+                info!("Stop reading {}", name);
+                self.sources.remove(i);
+                self.removed(self.tailers.len() + i);
+                self.save_checkpoints()?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Serve the next tailer, or a woken one first. Return whether it had new data.
+    pub async fn work(&mut self) -> Result<bool> {
+        if self.last_check_in.elapsed() >= self.config.check_in_interval {
+            self.check_in().await?;
+        }
+        if self.should_scan() {
+            self.scan()?;
+        }
+        self.handler.poll().await?;
+        if !self.retry_refused().await {
+            return Ok(false);
+        }
+        self.save_checkpoints()?;
+        let count = self.tailers.len() + self.sources.len();
+        if count == 0 {
+            return Ok(false);
+        }
+
+        let i = match self.next_woken() {
+            Some(i) => i,
+            None => {
+                let i = self.next % count;
+                self.next = i + 1;
+                i
+            }
         };
-        for _ in 0..10 {
-            agent.work().await?;
+        let path = match self.tailers.get(i) {
+            Some(tailer) => tailer.path().to_string(),
+            None => self.sources[i - self.tailers.len()].name().to_string(),
+        };
+        let wait = self.rate_limit_wait(&path);
+        let over_limit = !wait.is_zero();
+        if over_limit && self.config.over_limit == OverLimit::Backpressure {
+            debug!("Hold back {} for {:?} by rate limit", path, wait);
+            self.rate_limit_metrics.throttled += 1;
+            let until = Instant::now() + wait;
+            self.throttled_until = Some(self.throttled_until.map_or(until, |t| t.min(until)));
+            return Ok(false);
         }
-        assert_eq!(content, buf.lock().unwrap().as_slice());
+        if i >= self.tailers.len() {
+            return self.work_source(i - self.tailers.len(), over_limit).await;
+        }
+
+        let tailer = &mut self.tailers[i];
+        let redactor = &self.redactor;
+        let read = tailer.read_split_by(&|buffer| redactor.split_point(buffer))?;
+        match read {
+            Some(buffer) if over_limit => {
+                debug!("Drop {} bytes of {} over rate limit", buffer.len(), path);
+                self.rate_limit_metrics.dropped_buffers += 1;
+                self.rate_limit_metrics.dropped_bytes += buffer.len() as u64;
+                self.pending_checkpoints.insert(path, tailer.checkpoint()?);
+                self.save_checkpoints()?;
+                Ok(true)
+            }
+            Some(buffer) => {
+                let raw_len = buffer.len();
+                let filter = match self.file_index.get(&path) {
+                    Some(&i) => self.filters[i].as_ref(),
+                    None => None,
+                };
+                let kept = filter_events(
+                    filter,
+                    &self.splitter,
+                    buffer,
+                    &mut self.filter_metrics,
+                    &path,
+                );
+                if let Some(limiter) = self.rate_limiter.as_mut() {
+                    limiter.consume(kept.len());
+                }
+                if let Some(limiter) = self.file_rate_limiters.get_mut(&path) {
+                    limiter.consume(kept.len());
+                }
+                if let Some(limiter) = self.share_limiters.get_mut(&path) {
+                    limiter.consume(kept.len());
+                }
+                let schema_id = self.schema_ids.get(&path).cloned().unwrap_or_default();
+                let segment = if kept.is_empty() {
+                    None
+                } else {
+                    let payload = self.redactor.redact(&kept).into_owned();
+                    Some(segment(
+                        &self.config,
+                        &path,
+                        &schema_id,
+                        Some(tailer.offset()),
+                        raw_len,
+                        payload,
+                    )?)
+                };
+                let checkpoint = tailer.checkpoint()?;
+                self.hand_over(schema_id, segment, path, Some(checkpoint))
+                    .await?;
+                self.save_checkpoints()?;
+                Ok(true)
+            }
+            None => {
+                debug!("Reached end of file {}", tailer.path());
+                let is_removed = self.draining.contains(tailer.path());
+                let is_deleted = tailer.is_deleted();
+                if !is_removed {
+                    if !is_deleted && !tailer.is_rotated()? {
+                        return Ok(false);
+                    }
+                    // The writer may still append to the old file until it reopens the path.
+                    if !tailer.is_drained()? {
+                        return Ok(false);
+                    }
+                }
+                // No more data to complete the last event in the old file.
+                // End it with a new line, to keep it apart from what comes next.
+                if let Some(buffer) = tailer.flush() {
+                    let raw_len = buffer.len();
+                    let filter = match self.file_index.get(&path) {
+                        Some(&i) => self.filters[i].as_ref(),
+                        None => None,
+                    };
+                    let kept = filter_events(
+                        filter,
+                        &self.splitter,
+                        buffer,
+                        &mut self.filter_metrics,
+                        &path,
+                    );
+                    let schema_id = self.schema_ids.get(&path).cloned().unwrap_or_default();
+                    let segment = if kept.is_empty() {
+                        None
+                    } else {
+                        let mut event = self.redactor.redact(&kept).into_owned();
+                        event.push(b'\n');
+                        Some(segment(
+                            &self.config,
+                            &path,
+                            &schema_id,
+                            Some(tailer.offset()),
+                            raw_len,
+                            event,
+                        )?)
+                    };
+                    let checkpoint = tailer.checkpoint()?;
+                    self.hand_over(schema_id, segment, path.clone(), Some(checkpoint))
+                        .await?;
+                }
+                let tailer = &mut self.tailers[i];
+                if is_removed || is_deleted {
+                    info!("Stop tailing {}", tailer.path());
+                    self.draining.remove(tailer.path());
+                    unwatch(&mut self.watcher, tailer.path());
+                    self.tailers.remove(i);
+                    self.removed(i);
+                } else {
+                    debug!("Rotate to new file");
+                    let identity = tailer.identity()?;
+                    if tailer.rotate()? {
+                        self.rotated.insert(identity);
+                        unwatch(&mut self.watcher, tailer.path());
+                        watch(&mut self.watcher, tailer.path());
+                    }
+                }
+                self.save_checkpoints()?;
+                Ok(false)
+            }
+        }
+    }
+}
+
+/// Whether the error comes from the agent service or the blob store, which may
+/// recover from an outage.
+fn is_backend_error(e: &WoodpeckerError) -> bool {
+    matches!(
+        e,
+        WoodpeckerError::GrpcError(_)
+            | WoodpeckerError::TonicError(_)
+            | WoodpeckerError::ReqwestError(_)
+            | WoodpeckerError::UploadError(_)
+    )
+}
+
+fn compile_filters(files: &[FileConfig]) -> Result<Vec<Option<EventFilter>>> {
+    let mut filters = Vec::with_capacity(files.len());
+    for file in files {
+        if file.include.is_empty() && file.exclude.is_empty() {
+            filters.push(None);
+        } else {
+            // Built-in schemas need no agent service to resolve their regex.
+            let schema_regex = match Schema::builtin(&file.schema_id) {
+                Some(schema) if file.schema_regex.is_empty() => schema.regex,
+                _ => file.schema_regex.clone(),
+            };
+            filters.push(Some(EventFilter::try_new(
+                &file.include,
+                &file.exclude,
+                &schema_regex,
+            )?));
+        }
+    }
+    Ok(filters)
+}
+
+/// Frame the payload with the range of the file it was read from and the schema to parse
+/// it with, if metadata is attached.
+/// Without metadata, frame it with the schema alone, unless it is the default one.
+/// The range covers the raw bytes just read, before filtering and redaction.
+/// Sources other than files have no range, as their bytes are not those of a file.
+fn segment(
+    config: &AgentConfig,
+    path: &str,
+    schema_id: &str,
+    end_offset: Option<u64>,
+    raw_len: usize,
+    payload: Vec<u8>,
+) -> Result<Vec<u8>> {
+    if !config.attach_metadata {
+        if schema_id.is_empty() {
+            return Ok(payload);
+        }
+        let header = SegmentHeader {
+            schema_id: schema_id.to_string(),
+            ..Default::default()
+        };
+        return encode_segment(header, &payload);
+    }
+    let header = match end_offset {
+        Some(end_offset) => {
+            SegmentHeader::new(path, schema_id, end_offset - raw_len as u64, end_offset)
+        }
+        None => SegmentHeader::of_source(path, schema_id),
+    };
+    encode_segment(header, &payload)
+}
+
+/// Keep the events passing the filter, if any, and count the others.
+fn filter_events<'a>(
+    filter: Option<&EventFilter>,
+    splitter: &EventSplitter,
+    buffer: &'a [u8],
+    metrics: &mut HashMap<String, u64>,
+    path: &str,
+) -> Cow<'a, [u8]> {
+    let filter = match filter {
+        Some(filter) => filter,
+        None => return Cow::Borrowed(buffer),
+    };
+    let (kept, dropped) = filter.apply(splitter, buffer);
+    if dropped > 0 {
+        debug!("Filter out {} events of {}", dropped, path);
+        *metrics.entry(path.to_string()).or_insert(0) += dropped;
+    }
+    kept
+}
+
+/// Match paths of tailers against patterns, e.g. upon reload.
+fn match_options() -> MatchOptions {
+    MatchOptions {
+        require_literal_separator: true,
+        ..Default::default()
+    }
+}
+
+/// Watch the file, or leave it to polling if it cannot be watched.
+fn watch(watcher: &mut Option<Watcher>, path: &str) {
+    if let Some(watcher) = watcher {
+        if let Err(e) = watcher.watch(path) {
+            warn!("Failed to watch {}, poll it instead: {}", path, e);
+        }
+    }
+}
+
+/// Stop watching the file, e.g. once its tailer is dropped.
+fn unwatch(watcher: &mut Option<Watcher>, path: &str) {
+    if let Some(watcher) = watcher {
+        if let Err(e) = watcher.unwatch(path) {
+            warn!("Failed to unwatch {}: {}", path, e);
+        }
+    }
+}
+
+/// Wait for changes to watched files, or forever without a watcher.
+async fn changed(watcher: &mut Option<Watcher>) -> Result<Changes> {
+    match watcher {
+        Some(watcher) => watcher.changed().await,
+        None => futures::future::pending().await,
+    }
+}
+
+fn is_compressed(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| COMPRESSED_EXTENSIONS.contains(&ext))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::agent::client::agent::{
+        drain, Agent, BatchingHandler, BufferHandler, ControlPlane, SpoolingHandler,
+    };
+    use crate::agent::client::checkpoint::CheckpointStore;
+    use crate::agent::client::config::{AgentConfig, FileConfig};
+    use crate::agent::client::filter::FilterRule;
+    use crate::agent::client::rate_limit::{ManualClock, OverLimit};
+    use crate::agent::client::redact::RedactionRule;
+    use crate::agent::client::source::{SourceConfig, StreamSource};
+    use crate::agent::client::spool::Spool;
+    use crate::agent::client::syslog::Protocol;
+    use crate::agent::client::uploader::RetryPolicy;
+    use crate::codec::Codec;
+    use crate::error::{woodpecker_error, Result, WoodpeckerError};
+    use crate::event::{EventBoundary, EventSplitter};
+    use crate::ingress::schema::SYSLOG_SCHEMA_ID;
+    use crate::serde::envelope;
+    use async_trait::async_trait;
+    use std::collections::HashMap;
+    use std::fs::{rename, File, OpenOptions};
+    use std::io::{Cursor, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tempfile::{tempdir, NamedTempFile};
+    use tokio::time::sleep;
+
+    /// Collect all buffer consumed for comparison later.
+    struct BufferCollector {
+        buffer: Arc<Mutex<Vec<u8>>>,
+    }
+
+    #[async_trait]
+    impl BufferHandler for BufferCollector {
+        async fn consume(&mut self, buffer: &[u8]) -> Result<()> {
+            let mut buf = self.buffer.lock().unwrap();
+            buf.extend_from_slice(buffer);
+            Ok(())
+        }
+    }
+
+    fn init() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    /// Collect buffers like BufferCollector, but fail while the backend is down.
+    struct FlakyHandler {
+        buffer: Arc<Mutex<Vec<u8>>>,
+        down: Arc<AtomicBool>,
+    }
+
+    #[async_trait]
+    impl BufferHandler for FlakyHandler {
+        async fn consume(&mut self, buffer: &[u8]) -> Result<()> {
+            if self.down.load(Ordering::SeqCst) {
+                return Err(woodpecker_error("Backend is down"));
+            }
+            self.buffer.lock().unwrap().extend_from_slice(buffer);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn read_all() -> Result<()> {
+        init();
+
+        let content = b"Mary had a little lamb\nLittle lamb, little lamb\n";
+        let mut temp_file = NamedTempFile::new()?;
+        temp_file.write(content)?;
+
+        let path_str = temp_file.path().to_str().unwrap();
+        let checkpoint_dir = tempdir()?;
+        let checkpoint_file = checkpoint_dir.path().join("checkpoints");
+        let buf = Arc::new(Mutex::new(vec![]));
+        let mut agent = new_agent(path_str, checkpoint_file.to_str().unwrap(), buf.clone())?;
+        for _ in 0..10 {
+            agent.work().await?;
+        }
+        assert_eq!(content, buf.lock().unwrap().as_slice());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resume_after_restart() -> Result<()> {
+        init();
+
+        let content = b"Mary had a little lamb\nLittle lamb, little lamb\n";
+        let mut temp_file = NamedTempFile::new()?;
+        temp_file.write_all(content)?;
+
+        let path_str = temp_file.path().to_str().unwrap();
+        let checkpoint_dir = tempdir()?;
+        let checkpoint_file = checkpoint_dir.path().join("checkpoints");
+        let checkpoint_str = checkpoint_file.to_str().unwrap();
+        let buf = Arc::new(Mutex::new(vec![]));
+        let mut agent = new_agent(path_str, checkpoint_str, buf.clone())?;
+        agent.work().await?;
+        drop(agent);
+
+        // A restarted agent continues where the previous one stopped.
+        let mut agent = new_agent(path_str, checkpoint_str, buf.clone())?;
+        for _ in 0..10 {
+            agent.work().await?;
+        }
+        assert_eq!(content, buf.lock().unwrap().as_slice());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn forget_checkpoints_of_deleted_files() -> Result<()> {
+        init();
+
+        let dir = tempdir()?;
+        let path = dir.path().join("app.log");
+        File::create(&path)?.write_all(b"Mary had a little lamb\n")?;
+        let path_str = path.to_str().unwrap();
+        let checkpoint_file = dir.path().join("checkpoints");
+        let checkpoint_str = checkpoint_file.to_str().unwrap();
+        let buf = Arc::new(Mutex::new(vec![]));
+        let mut agent = new_agent(path_str, checkpoint_str, buf)?;
+        for _ in 0..5 {
+            agent.work().await?;
+        }
+        assert!(CheckpointStore::try_new(checkpoint_str)?
+            .get(path_str)
+            .is_some());
+
+        std::fs::remove_file(&path)?;
+        for _ in 0..5 {
+            agent.work().await?;
+        }
+        agent.scan()?;
+        assert_eq!(
+            None,
+            CheckpointStore::try_new(checkpoint_str)?.get(path_str)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_all_matching_files() -> Result<()> {
+        init();
+
+        let dir = tempdir()?;
+        File::create(dir.path().join("a.log"))?.write_all(b"Mary had a little lamb\n")?;
+        File::create(dir.path().join("b.log"))?.write_all(b"Little lamb, little lamb\n")?;
+        File::create(dir.path().join("c.txt"))?.write_all(b"Not a log\n")?;
+
+        let pattern = dir.path().join("*.log");
+        let checkpoint_file = dir.path().join("checkpoints");
+        let buf = Arc::new(Mutex::new(vec![]));
+        let config = AgentConfig {
+            agent_service_url: "http://[::1]:50051".to_string(),
+            files: vec![FileConfig::new(pattern.to_str().unwrap())],
+            buffer_size: 10,
+            checkpoint_file: checkpoint_file.to_str().unwrap().to_string(),
+            rescan_interval: Duration::from_secs(0),
+            rotation_quiet_period: Duration::from_secs(0),
+            watch_files: true,
+            event_boundary: EventBoundary::NewLine,
+            event_timeout: Duration::from_secs(60),
+            check_in_interval: Duration::from_secs(60),
+            identity_file: "agent_id".to_string(),
+            labels: HashMap::new(),
+            enrollment_token: "token".to_string(),
+            spool_dir: "spool".to_string(),
+            spool_max_bytes: 1024,
+            compression: Codec::None,
+            batch_size: 1024 * 1024,
+            flush_interval: Duration::from_secs(0),
+            upload_retry: RetryPolicy::default(),
+            rate_limit: 0,
+            over_limit: OverLimit::Backpressure,
+            redactions: vec![],
+            redaction_key: String::new(),
+            attach_metadata: false,
+            sources: vec![],
+        };
+        let mut agent = Agent::new(
+            config,
+            Box::new(BufferCollector {
+                buffer: buf.clone(),
+            }),
+        )?;
+        assert_eq!(2, agent.tailers.len());
+        for _ in 0..10 {
+            agent.work().await?;
+        }
+        assert_eq!(
+            b"Mary had a little lamb\nLittle lamb, little lamb\n".len(),
+            buf.lock().unwrap().len()
+        );
+
+        // A file created later is picked up on the next scan.
+        File::create(dir.path().join("d.log"))?.write_all(b"Its fleece was white as snow\n")?;
+        for _ in 0..10 {
+            agent.work().await?;
+        }
+        assert_eq!(3, agent.tailers.len());
+        assert_eq!(
+            b"Mary had a little lamb\nLittle lamb, little lamb\nIts fleece was white as snow\n"
+                .len(),
+            buf.lock().unwrap().len()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rotate_after_draining_old_file() -> Result<()> {
+        init();
+
+        let dir = tempdir()?;
+        let path = dir.path().join("app.log");
+        File::create(&path)?.write_all(b"Mary had a little lamb\n")?;
+        let mut old = OpenOptions::new().append(true).open(&path)?;
+
+        // The pattern also matches rotated siblings.
+        let pattern = dir.path().join("app.log*");
+        let checkpoint_file = dir.path().join("checkpoints");
+        let buf = Arc::new(Mutex::new(vec![]));
+        let config = AgentConfig {
+            agent_service_url: "http://[::1]:50051".to_string(),
+            files: vec![FileConfig::new(pattern.to_str().unwrap())],
+            buffer_size: 64,
+            checkpoint_file: checkpoint_file.to_str().unwrap().to_string(),
+            rescan_interval: Duration::from_secs(0),
+            rotation_quiet_period: Duration::from_secs(0),
+            watch_files: true,
+            event_boundary: EventBoundary::NewLine,
+            event_timeout: Duration::from_secs(60),
+            check_in_interval: Duration::from_secs(60),
+            identity_file: "agent_id".to_string(),
+            labels: HashMap::new(),
+            enrollment_token: "token".to_string(),
+            spool_dir: "spool".to_string(),
+            spool_max_bytes: 1024,
+            compression: Codec::None,
+            batch_size: 1024 * 1024,
+            flush_interval: Duration::from_secs(0),
+            upload_retry: RetryPolicy::default(),
+            rate_limit: 0,
+            over_limit: OverLimit::Backpressure,
+            redactions: vec![],
+            redaction_key: String::new(),
+            attach_metadata: false,
+            sources: vec![],
+        };
+        let mut agent = Agent::new(
+            config,
+            Box::new(BufferCollector {
+                buffer: buf.clone(),
+            }),
+        )?;
+        for _ in 0..3 {
+            agent.work().await?;
+        }
+
+        // Rotate by rename and create, while the writer still appends to the old file.
+        rename(&path, dir.path().join("app.log.1"))?;
+        old.write_all(b"Little lamb, little lamb\n")?;
+        File::create(&path)?.write_all(b"Its fleece was white as snow\n")?;
+        File::create(dir.path().join("app.log.2.gz"))?.write_all(b"Compressed\n")?;
+        for _ in 0..10 {
+            agent.work().await?;
+        }
+        assert_eq!(1, agent.tailers.len());
+        assert_eq!(
+            b"Mary had a little lamb\nLittle lamb, little lamb\nIts fleece was white as snow\n"
+                .to_vec(),
+            *buf.lock().unwrap()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn work_fairly_across_files() -> Result<()> {
+        init();
+
+        let dir = tempdir()?;
+        File::create(dir.path().join("a.log"))?.write_all(&[b'a'; 100])?;
+        File::create(dir.path().join("b.log"))?.write_all(&[b'b'; 100])?;
+
+        let pattern = dir.path().join("*.log");
+        let checkpoint_file = dir.path().join("checkpoints");
+        let buf = Arc::new(Mutex::new(vec![]));
+        let config = AgentConfig {
+            agent_service_url: "http://[::1]:50051".to_string(),
+            files: vec![FileConfig::new(pattern.to_str().unwrap())],
+            buffer_size: 10,
+            checkpoint_file: checkpoint_file.to_str().unwrap().to_string(),
+            rescan_interval: Duration::from_secs(60),
+            rotation_quiet_period: Duration::from_secs(0),
+            watch_files: true,
+            event_boundary: EventBoundary::NewLine,
+            event_timeout: Duration::from_secs(60),
+            check_in_interval: Duration::from_secs(60),
+            identity_file: "agent_id".to_string(),
+            labels: HashMap::new(),
+            enrollment_token: "token".to_string(),
+            spool_dir: "spool".to_string(),
+            spool_max_bytes: 1024,
+            compression: Codec::None,
+            batch_size: 1024 * 1024,
+            flush_interval: Duration::from_secs(0),
+            upload_retry: RetryPolicy::default(),
+            rate_limit: 0,
+            over_limit: OverLimit::Backpressure,
+            redactions: vec![],
+            redaction_key: String::new(),
+            attach_metadata: false,
+            sources: vec![],
+        };
+        let mut agent = Agent::new(
+            config,
+            Box::new(BufferCollector {
+                buffer: buf.clone(),
+            }),
+        )?;
+        for _ in 0..4 {
+            agent.work().await?;
+        }
+        assert_eq!(
+            [[b'a'; 10], [b'b'; 10], [b'a'; 10], [b'b'; 10]].concat(),
+            *buf.lock().unwrap()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn serve_next_after_removed_file() -> Result<()> {
+        init();
+
+        let dir = tempdir()?;
+        File::create(dir.path().join("a.log"))?.write_all(b"aaaaaaaaa\n")?;
+        File::create(dir.path().join("b.log"))?.write_all(&[b'b'; 20])?;
+        File::create(dir.path().join("c.log"))?.write_all(&[b'c'; 20])?;
+
+        let pattern = dir.path().join("*.log");
+        let checkpoint_file = dir.path().join("checkpoints");
+        let buf = Arc::new(Mutex::new(vec![]));
+        let mut agent = new_agent(
+            pattern.to_str().unwrap(),
+            checkpoint_file.to_str().unwrap(),
+            buf.clone(),
+        )?;
+        for _ in 0..3 {
+            agent.work().await?;
+        }
+        buf.lock().unwrap().clear();
+
+        // Removing a.log, the first in turn, leaves b.log next.
+        std::fs::remove_file(dir.path().join("a.log"))?;
+        agent.work().await?;
+        assert_eq!(2, agent.tailers.len());
+        agent.work().await?;
+        assert_eq!(vec![b'b'; 10], *buf.lock().unwrap());
+        Ok(())
+    }
+
+    fn new_agent(path: &str, checkpoint_file: &str, buf: Arc<Mutex<Vec<u8>>>) -> Result<Agent> {
+        let config = AgentConfig {
+            agent_service_url: "http://[::1]:50051".to_string(),
+            files: vec![FileConfig::new(path)],
+            buffer_size: 10,
+            checkpoint_file: checkpoint_file.to_string(),
+            rescan_interval: Duration::from_secs(60),
+            rotation_quiet_period: Duration::from_secs(0),
+            watch_files: true,
+            event_boundary: EventBoundary::NewLine,
+            event_timeout: Duration::from_secs(60),
+            check_in_interval: Duration::from_secs(60),
+            identity_file: "agent_id".to_string(),
+            labels: HashMap::new(),
+            enrollment_token: "token".to_string(),
+            spool_dir: "spool".to_string(),
+            spool_max_bytes: 1024,
+            compression: Codec::None,
+            batch_size: 1024 * 1024,
+            flush_interval: Duration::from_secs(0),
+            upload_retry: RetryPolicy::default(),
+            rate_limit: 0,
+            over_limit: OverLimit::Backpressure,
+            redactions: vec![],
+            redaction_key: String::new(),
+            attach_metadata: false,
+            sources: vec![],
+        };
+        Agent::new(config, Box::new(BufferCollector { buffer: buf }))
+    }
+
+    /// Provide a fixed config.
+    struct StaticControlPlane {
+        config: AgentConfig,
+    }
+
+    #[async_trait]
+    impl ControlPlane for StaticControlPlane {
+        async fn get_config(&mut self, _current: &AgentConfig) -> Result<Option<AgentConfig>> {
+            Ok(Some(self.config.clone()))
+        }
+    }
+
+    #[tokio::test]
+    async fn reload() -> Result<()> {
+        init();
+
+        let dir = tempdir()?;
+        let mut a = File::create(dir.path().join("a.log"))?;
+        a.write_all(b"Mary had a little lamb\n")?;
+        let mut b = File::create(dir.path().join("b.log"))?;
+        b.write_all(b"Little lamb, little lamb\n")?;
+
+        let checkpoint_file = dir.path().join("checkpoints");
+        let buf = Arc::new(Mutex::new(vec![]));
+        let mut agent = new_agent(
+            dir.path().join("a.log").to_str().unwrap(),
+            checkpoint_file.to_str().unwrap(),
+            buf.clone(),
+        )?;
+        assert_eq!(1, agent.tailers.len());
+
+        // Data written before the reload is still read from the removed file.
+        a.write_all(b"Its fleece was white as snow\n")?;
+        let mut config = agent.config.clone();
+        config.files = vec![FileConfig::new(dir.path().join("b.log").to_str().unwrap())];
+        agent.reload(config)?;
+        assert_eq!(2, agent.tailers.len());
+
+        for _ in 0..20 {
+            agent.work().await?;
+        }
+        assert_eq!(1, agent.tailers.len());
+        assert_eq!(
+            dir.path().join("b.log").to_str().unwrap(),
+            agent.tailers[0].path()
+        );
+        assert_eq!(
+            b"Mary had a little lamb\nIts fleece was white as snow\nLittle lamb, little lamb\n"
+                .len(),
+            buf.lock().unwrap().len()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_in() -> Result<()> {
+        init();
+
+        let dir = tempdir()?;
+        File::create(dir.path().join("a.log"))?.write_all(b"Mary had a little lamb\n")?;
+        File::create(dir.path().join("b.log"))?.write_all(b"Little lamb, little lamb\n")?;
+
+        let checkpoint_file = dir.path().join("checkpoints");
+        let buf = Arc::new(Mutex::new(vec![]));
+        let mut agent = new_agent(
+            dir.path().join("a.log").to_str().unwrap(),
+            checkpoint_file.to_str().unwrap(),
+            buf.clone(),
+        )?;
+        let mut config = agent.config.clone();
+        config.files = vec![FileConfig::new(dir.path().join("*.log").to_str().unwrap())];
+        agent.control_plane = Some(Box::new(StaticControlPlane { config }));
+        agent.config.check_in_interval = Duration::from_secs(0);
+
+        agent.work().await?;
+        assert_eq!(2, agent.tailers.len());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keep_config_upon_invalid_one() -> Result<()> {
+        init();
+
+        let dir = tempdir()?;
+        let path = dir.path().join("a.log");
+        File::create(&path)?.write_all(b"Mary had a little lamb\n")?;
+
+        let checkpoint_file = dir.path().join("checkpoints");
+        let buf = Arc::new(Mutex::new(vec![]));
+        let mut agent = new_agent(
+            path.to_str().unwrap(),
+            checkpoint_file.to_str().unwrap(),
+            buf.clone(),
+        )?;
+        let current = agent.config.clone();
+        let mut config = agent.config.clone();
+        config.files = vec![FileConfig::new("[")];
+        config.event_boundary = EventBoundary::Continuation;
+        agent.control_plane = Some(Box::new(StaticControlPlane { config }));
+        agent.config.check_in_interval = Duration::from_secs(0);
+
+        for _ in 0..3 {
+            agent.work().await?;
+        }
+        agent.config.check_in_interval = current.check_in_interval;
+        assert_eq!(current, agent.config);
+        assert_eq!(b"Mary had a little lamb\n".to_vec(), *buf.lock().unwrap());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn hold_back_file_over_rate_limit() -> Result<()> {
+        init();
+
+        let dir = tempdir()?;
+        let noisy = b"Mary had a little lamb\n".repeat(10);
+        File::create(dir.path().join("noisy.log"))?.write_all(&noisy)?;
+        File::create(dir.path().join("quiet.log"))?.write_all(b"Little lamb, little lamb\n")?;
+
+        let checkpoint_file = dir.path().join("checkpoints");
+        let buf = Arc::new(Mutex::new(vec![]));
+        let mut agent = new_agent(
+            dir.path().join("quiet.log").to_str().unwrap(),
+            checkpoint_file.to_str().unwrap(),
+            buf.clone(),
+        )?;
+        let mut config = agent.config.clone();
+        config.files = vec![
+            FileConfig {
+                rate_limit: 50,
+                ..FileConfig::new(dir.path().join("noisy.log").to_str().unwrap())
+            },
+            FileConfig::new(dir.path().join("*.log").to_str().unwrap()),
+        ];
+        let clock = ManualClock::new();
+        agent.clock = Arc::new(clock.clone());
+        agent.reload(config)?;
+        assert_eq!(2, agent.tailers.len());
+
+        for _ in 0..40 {
+            agent.work().await?;
+        }
+        // The quiet file is read in full, the noisy one until a buffer overdraws its limit:
+        // buffers of 10 bytes end at line breaks, 10 + 10 + 3 + 10 + 10 + 3 + 10 = 56.
+        assert_eq!(25 + 56, buf.lock().unwrap().len());
+        assert!(agent.rate_limit_metrics().throttled > 0);
+        assert_eq!(0, agent.rate_limit_metrics().dropped_buffers);
+
+        // A second later, the noisy file goes on.
+        clock.advance(Duration::from_secs(1));
+        for _ in 0..40 {
+            agent.work().await?;
+        }
+        // The bucket refills to 44 after the debt of 6: 10 + 3 + 10 + 10 + 3 + 10 = 46.
+        assert_eq!(25 + 56 + 46, buf.lock().unwrap().len());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn drop_over_global_rate_limit() -> Result<()> {
+        init();
+
+        let content = b"Mary had a little lamb\n".repeat(10);
+        let mut temp_file = NamedTempFile::new()?;
+        temp_file.write_all(&content)?;
+
+        let path_str = temp_file.path().to_str().unwrap();
+        let checkpoint_dir = tempdir()?;
+        let checkpoint_file = checkpoint_dir.path().join("checkpoints");
+        let buf = Arc::new(Mutex::new(vec![]));
+        let mut agent = new_agent(path_str, checkpoint_file.to_str().unwrap(), buf.clone())?;
+        let mut config = agent.config.clone();
+        config.rate_limit = 50;
+        config.over_limit = OverLimit::Drop;
+        agent.reload(config)?;
+
+        for _ in 0..40 {
+            agent.work().await?;
+        }
+        // Read all, but keep only up to a burst of the limit.
+        let metrics = agent.rate_limit_metrics();
+        let kept = buf.lock().unwrap().len() as u64;
+        assert!(kept <= 50 + 10, "{}", kept);
+        assert_eq!(content.len() as u64, kept + metrics.dropped_bytes);
+        assert_eq!(0, metrics.throttled);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn drop_from_files_over_their_share() -> Result<()> {
+        init();
+
+        let dir = tempdir()?;
+        let noisy = b"Mary had a little lamb\n".repeat(10);
+        File::create(dir.path().join("noisy.log"))?.write_all(&noisy)?;
+        let mut quiet = File::create(dir.path().join("quiet.log"))?;
+
+        let checkpoint_file = dir.path().join("checkpoints");
+        let buf = Arc::new(Mutex::new(vec![]));
+        let mut agent = new_agent(
+            dir.path().join("*.log").to_str().unwrap(),
+            checkpoint_file.to_str().unwrap(),
+            buf.clone(),
+        )?;
+        let mut config = agent.config.clone();
+        config.rate_limit = 60;
+        config.over_limit = OverLimit::Drop;
+        agent.clock = Arc::new(ManualClock::new());
+        agent.reload(config)?;
+        assert_eq!(2, agent.tailers.len());
+
+        for _ in 0..100 {
+            agent.work().await?;
+        }
+        // The noisy file used up the global limit, and then some.
+        let kept = buf.lock().unwrap().len() as u64;
+        let dropped = agent.rate_limit_metrics().dropped_bytes;
+        assert_eq!(noisy.len() as u64, kept + dropped);
+        assert!(dropped > 0);
+
+        // The quiet file is within its share of 30 bytes, so it is kept.
+        quiet.write_all(b"Little lamb\n")?;
+        for _ in 0..10 {
+            agent.work().await?;
+        }
+        assert!(buf.lock().unwrap().ends_with(b"Little lamb\n"));
+        assert_eq!(dropped, agent.rate_limit_metrics().dropped_bytes);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn redact_before_upload() -> Result<()> {
+        init();
+
+        let dir = tempdir()?;
+        let path = dir.path().join("a.log");
+        File::create(&path)?.write_all(b"to mary@example.com\nfrom bob@example.com\n")?;
+
+        // Start without files, to tail the file with a buffer large enough for whole events.
+        let checkpoint_file = dir.path().join("checkpoints");
+        let buf = Arc::new(Mutex::new(vec![]));
+        let mut agent = new_agent(
+            dir.path().join("none.log").to_str().unwrap(),
+            checkpoint_file.to_str().unwrap(),
+            buf.clone(),
+        )?;
+        let mut config = agent.config.clone();
+        config.files = vec![FileConfig::new(path.to_str().unwrap())];
+        config.buffer_size = 64;
+        config.redactions = vec![RedactionRule::builtin("email")];
+        agent.reload(config)?;
+
+        for _ in 0..10 {
+            agent.work().await?;
+        }
+        assert_eq!(
+            b"to [REDACTED:email]\nfrom [REDACTED:email]\n".to_vec(),
+            *buf.lock().unwrap()
+        );
+        assert_eq!(Some(&2), agent.redaction_metrics().get("email"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn redact_across_split() -> Result<()> {
+        init();
+
+        // An event larger than the buffer, with a secret across the buffer size.
+        let dir = tempdir()?;
+        let path = dir.path().join("a.log");
+        let event = [
+            b" ".repeat(56),
+            b"mary@example.com".to_vec(),
+            b" ".repeat(30),
+            b"\n".to_vec(),
+        ]
+        .concat();
+        File::create(&path)?.write_all(&event)?;
+
+        let checkpoint_file = dir.path().join("checkpoints");
+        let buf = Arc::new(Mutex::new(vec![]));
+        let mut agent = new_agent(
+            dir.path().join("none.log").to_str().unwrap(),
+            checkpoint_file.to_str().unwrap(),
+            buf.clone(),
+        )?;
+        let mut config = agent.config.clone();
+        config.files = vec![FileConfig::new(path.to_str().unwrap())];
+        config.buffer_size = 64;
+        config.redactions = vec![RedactionRule::builtin("email")];
+        agent.reload(config)?;
+
+        for _ in 0..10 {
+            agent.work().await?;
+        }
+        let expected = [
+            b" ".repeat(56),
+            b"[REDACTED:email]".to_vec(),
+            b" ".repeat(30),
+            b"\n".to_vec(),
+        ]
+        .concat();
+        assert_eq!(expected, *buf.lock().unwrap());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn filter_events_per_file() -> Result<()> {
+        init();
+
+        let dir = tempdir()?;
+        File::create(dir.path().join("app.log"))?
+            .write_all(b"[INFO] GET /health\n[DEBUG] Connected\n[INFO] GET /orders\n")?;
+        File::create(dir.path().join("other.log"))?.write_all(b"[INFO] GET /health\n")?;
+
+        // Start without files, to tail them with a buffer large enough for whole events.
+        let checkpoint_file = dir.path().join("checkpoints");
+        let buf = Arc::new(Mutex::new(vec![]));
+        let mut agent = new_agent(
+            dir.path().join("none.log").to_str().unwrap(),
+            checkpoint_file.to_str().unwrap(),
+            buf.clone(),
+        )?;
+        let mut config = agent.config.clone();
+        let app_path = dir.path().join("app.log").to_str().unwrap().to_string();
+        config.files = vec![
+            FileConfig {
+                exclude: vec![
+                    FilterRule::pattern("GET /health"),
+                    FilterRule::levels(&["DEBUG"]),
+                ],
+                schema_regex: r"\[(?P<level>\w+)\] (?P<content>.*)".to_string(),
+                ..FileConfig::new(&app_path)
+            },
+            FileConfig::new(dir.path().join("*.log").to_str().unwrap()),
+        ];
+        config.buffer_size = 64;
+        agent.reload(config)?;
+
+        for _ in 0..10 {
+            agent.work().await?;
+        }
+        let mut lines: Vec<String> = String::from_utf8(buf.lock().unwrap().clone())
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        lines.sort();
+        assert_eq!(vec!["[INFO] GET /health", "[INFO] GET /orders"], lines);
+        assert_eq!(Some(&2), agent.filter_metrics().get(&app_path));
+        assert_eq!(1, agent.filter_metrics().len());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn attach_source_of_segments() -> Result<()> {
+        init();
+
+        let dir = tempdir()?;
+        let path = dir.path().join("app.log");
+        let mut file = File::create(&path)?;
+        file.write_all(b"[INFO] GET /health\n[INFO] GET /orders\n")?;
+
+        let checkpoint_file = dir.path().join("checkpoints");
+        let buf = Arc::new(Mutex::new(vec![]));
+        let mut agent = new_agent(
+            dir.path().join("none.log").to_str().unwrap(),
+            checkpoint_file.to_str().unwrap(),
+            buf.clone(),
+        )?;
+        let mut config = agent.config.clone();
+        let path = path.to_str().unwrap().to_string();
+        config.files = vec![FileConfig {
+            schema_id: "app".to_string(),
+            exclude: vec![FilterRule::pattern("GET /health")],
+            ..FileConfig::new(&path)
+        }];
+        config.buffer_size = 64;
+        config.attach_metadata = true;
+        agent.reload(config)?;
+        for _ in 0..5 {
+            agent.work().await?;
+        }
+        file.write_all(b"[INFO] GET /items\n")?;
+        for _ in 0..5 {
+            agent.work().await?;
+        }
+
+        let buf = buf.lock().unwrap().clone();
+        let upload = envelope::decode(&buf)?;
+        let ranges: Vec<_> = upload
+            .segments
+            .iter()
+            .map(|(segment, payload)| {
+                (
+                    segment.path.as_str(),
+                    segment.start_offset,
+                    segment.end_offset,
+                    *payload,
+                )
+            })
+            .collect();
+        // The range covers the events filtered out too.
+        assert_eq!(
+            vec![
+                (
+                    path.as_str(),
+                    Some(0),
+                    Some(38),
+                    &b"[INFO] GET /orders\n"[..]
+                ),
+                (
+                    path.as_str(),
+                    Some(38),
+                    Some(56),
+                    &b"[INFO] GET /items\n"[..]
+                ),
+            ],
+            ranges
+        );
+        assert!(upload
+            .segments
+            .iter()
+            .all(|(segment, _)| segment.schema_id == "app"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resume_journal_after_cursor() -> Result<()> {
+        init();
+
+        let dir = tempdir()?;
+        let journal = dir.path().join("journal.export");
+        let mut file = File::create(&journal)?;
+        file.write_all(
+            b"__CURSOR=s=a;i=1\nMESSAGE=Mary had a little lamb\n\n\
+            __CURSOR=s=a;i=2\nMESSAGE=Its fleece was white as snow\n\n",
+        )?;
+
+        let checkpoint_file = dir.path().join("checkpoints");
+        let buf = Arc::new(Mutex::new(vec![]));
+        let agent = new_agent(
+            dir.path().join("none.log").to_str().unwrap(),
+            checkpoint_file.to_str().unwrap(),
+            buf.clone(),
+        )?;
+        let mut config = agent.config.clone();
+        config.sources = vec![SourceConfig::Journal {
+            path: journal.to_str().unwrap().to_string(),
+        }];
+        config.buffer_size = 1024;
+        let read_to_end = |config: AgentConfig, buf: Arc<Mutex<Vec<u8>>>| async move {
+            let mut agent = Agent::new(config, Box::new(BufferCollector { buffer: buf }))?;
+            for _ in 0..100 {
+                if agent.sources.is_empty() {
+                    return Ok(());
+                }
+                agent.work().await?;
+                sleep(Duration::from_millis(10)).await;
+            }
+            Err(woodpecker_error("Journal is not closed"))
+        };
+        read_to_end(config.clone(), buf.clone()).await?;
+        assert_eq!(
+            "{\"__CURSOR\":\"s=a;i=1\",\"MESSAGE\":\"Mary had a little lamb\"}\n\
+            {\"__CURSOR\":\"s=a;i=2\",\"MESSAGE\":\"Its fleece was white as snow\"}\n",
+            String::from_utf8(buf.lock().unwrap().clone()).unwrap()
+        );
+
+        // Upon restart, entries up to the cursor are skipped, e.g. as journalctl starts over.
+        buf.lock().unwrap().clear();
+        file.write_all(b"__CURSOR=s=a;i=3\nMESSAGE=And everywhere that Mary went\n\n")?;
+        read_to_end(config, buf.clone()).await?;
+        assert_eq!(
+            "{\"__CURSOR\":\"s=a;i=3\",\"MESSAGE\":\"And everywhere that Mary went\"}\n",
+            String::from_utf8(buf.lock().unwrap().clone()).unwrap()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn close_sources_ending_with_partial_event() -> Result<()> {
+        init();
+
+        let dir = tempdir()?;
+        let journal = dir.path().join("journal.export");
+        let mut file = File::create(&journal)?;
+        file.write_all(b"MESSAGE=a\n\nMESSAGE=partial\n")?;
+
+        let checkpoint_file = dir.path().join("checkpoints");
+        let buf = Arc::new(Mutex::new(vec![]));
+        let mut agent = new_agent(
+            dir.path().join("none.log").to_str().unwrap(),
+            checkpoint_file.to_str().unwrap(),
+            buf.clone(),
+        )?;
+        agent.sources.push(Box::new(StreamSource::new(
+            "stream",
+            || Ok(Cursor::new(b"b\nc".to_vec())),
+            EventSplitter::default(),
+            1024,
+        )));
+        agent.sources.push(
+            SourceConfig::Journal {
+                path: journal.to_str().unwrap().to_string(),
+            }
+            .open(EventSplitter::default(), 1024, None)?,
+        );
+        for _ in 0..100 {
+            if agent.sources.is_empty() {
+                break;
+            }
+            agent.work().await?;
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert!(agent.sources.is_empty());
+
+        // The last event of the stream ends with a new line, the partial entry is dropped.
+        let mut lines: Vec<String> = String::from_utf8(buf.lock().unwrap().clone())
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        lines.sort();
+        assert_eq!(vec!["b", "c", "{\"MESSAGE\":\"a\"}"], lines);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_schema_of_syslog_without_metadata() -> Result<()> {
+        init();
+
+        let dir = tempdir()?;
+        let checkpoint_file = dir.path().join("checkpoints");
+        let buf = Arc::new(Mutex::new(vec![]));
+        let agent = new_agent(
+            dir.path().join("none.log").to_str().unwrap(),
+            checkpoint_file.to_str().unwrap(),
+            buf.clone(),
+        )?;
+        let address = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let mut config = agent.config.clone();
+        config.sources = vec![SourceConfig::Syslog {
+            protocol: Protocol::Tcp,
+            address: address.to_string(),
+        }];
+        let mut agent = Agent::new(
+            config,
+            Box::new(BufferCollector {
+                buffer: buf.clone(),
+            }),
+        )?;
+        let mut stream = TcpStream::connect(address)?;
+        stream.write_all(b"<13>Oct 11 22:14:15 host app: up\n")?;
+        for _ in 0..100 {
+            if !buf.lock().unwrap().is_empty() {
+                break;
+            }
+            agent.work().await?;
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        let buf = buf.lock().unwrap().clone();
+        let upload = envelope::decode(&buf)?;
+        assert_eq!(1, upload.segments.len());
+        let (segment, payload) = &upload.segments[0];
+        assert_eq!(SYSLOG_SCHEMA_ID, segment.schema_id);
+        assert_eq!(None, segment.end_offset);
+        assert_eq!(&b"<13>Oct 11 22:14:15 host app: up\n"[..], *payload);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn spool_during_outage() -> Result<()> {
+        init();
+
+        let dir = tempdir()?;
+        let spool = Spool::try_new(dir.path().to_str().unwrap(), 1024)?;
+        let spool = Arc::new(Mutex::new(spool));
+        let buf = Arc::new(Mutex::new(vec![]));
+        let down = Arc::new(AtomicBool::new(true));
+        let new_handler = || FlakyHandler {
+            buffer: buf.clone(),
+            down: down.clone(),
+        };
+        let mut handler = SpoolingHandler {
+            handler: new_handler(),
+            spool: spool.clone(),
+        };
+
+        handler.consume(b"Mary had ").await?;
+        handler.consume(b"a little ").await?;
+        // Spool even if the backend is back, until the spool is drained.
+        down.store(false, Ordering::SeqCst);
+        handler.consume(b"lamb\n").await?;
+        assert!(buf.lock().unwrap().is_empty());
+        assert_eq!(3, spool.lock().unwrap().metrics().buffers);
+
+        drain(&spool, &mut new_handler()).await?;
+        assert_eq!(b"Mary had a little lamb\n", buf.lock().unwrap().as_slice());
+        assert!(spool.lock().unwrap().is_empty());
+
+        handler.consume(b"Little lamb\n").await?;
+        assert_eq!(0, spool.lock().unwrap().metrics().buffers);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn hold_back_while_handler_refuses() -> Result<()> {
+        init();
+
+        let content = b"Mary had a little lamb\nLittle lamb, little lamb\n";
+        let mut temp_file = NamedTempFile::new()?;
+        temp_file.write_all(content)?;
+        let path_str = temp_file.path().to_str().unwrap();
+        let checkpoint_dir = tempdir()?;
+        let checkpoint_file = checkpoint_dir.path().join("checkpoints");
+        let checkpoint_str = checkpoint_file.to_str().unwrap();
+
+        let buf = Arc::new(Mutex::new(vec![]));
+        let down = Arc::new(AtomicBool::new(true));
+        let mut agent = new_agent(path_str, checkpoint_str, Arc::new(Mutex::new(vec![])))?;
+        agent.handler = Box::new(FlakyHandler {
+            buffer: buf.clone(),
+            down: down.clone(),
+        });
+        for _ in 0..10 {
+            agent.work().await?;
+        }
+        // Nothing is read past the refused buffer, nor checkpointed.
+        assert!(agent.refused.is_some());
+        assert!(CheckpointStore::try_new(checkpoint_str)?
+            .get(path_str)
+            .is_none());
+
+        down.store(false, Ordering::SeqCst);
+        for _ in 0..20 {
+            agent.work().await?;
+        }
+        assert_eq!(content, buf.lock().unwrap().as_slice());
+        Ok(())
+    }
+
+    /// Keep every buffer consumed apart, to count uploads.
+    struct UploadCollector {
+        uploads: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    #[async_trait]
+    impl BufferHandler for UploadCollector {
+        async fn consume(&mut self, buffer: &[u8]) -> Result<()> {
+            self.uploads.lock().unwrap().push(buffer.to_vec());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn batch_by_size_and_time() -> Result<()> {
+        init();
+
+        let uploads = Arc::new(Mutex::new(vec![]));
+        let collector = UploadCollector {
+            uploads: uploads.clone(),
+        };
+        let mut handler = BatchingHandler::new(collector, 10, Duration::from_secs(60));
+        handler.consume(b"Mary\n").await?;
+        assert!(uploads.lock().unwrap().is_empty());
+        handler.consume(b"had a\n").await?;
+        assert_eq!(vec![b"Mary\nhad a\n".to_vec()], *uploads.lock().unwrap());
+
+        handler.consume(b"a\n").await?;
+        handler.consume(b"lamb\n").await?;
+        handler.poll().await?;
+        assert!(handler.has_pending());
+        assert_eq!(1, uploads.lock().unwrap().len());
+
+        handler.flush_interval = Duration::from_secs(0);
+        handler.poll().await?;
+        assert!(!handler.has_pending());
+        assert_eq!(b"a\nlamb\n", uploads.lock().unwrap()[1].as_slice());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn batch_by_key() -> Result<()> {
+        init();
+
+        let uploads = Arc::new(Mutex::new(vec![]));
+        let collector = UploadCollector {
+            uploads: uploads.clone(),
+        };
+        let mut handler = BatchingHandler::new(collector, 10, Duration::from_secs(60));
+        handler.consume_keyed("app", b"Mary\n").await?;
+        handler.consume_keyed("syslog", b"had a\n").await?;
+        assert!(uploads.lock().unwrap().is_empty());
+        handler.consume_keyed("app", b"little\n").await?;
+        assert_eq!(vec![b"Mary\nlittle\n".to_vec()], *uploads.lock().unwrap());
+
+        handler.flush().await?;
+        assert!(!handler.has_pending());
+        assert_eq!(b"had a\n", uploads.lock().unwrap()[1].as_slice());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refuse_once_batch_is_full() -> Result<()> {
+        init();
+
+        let buf = Arc::new(Mutex::new(vec![]));
+        let down = Arc::new(AtomicBool::new(true));
+        let flaky = FlakyHandler {
+            buffer: buf.clone(),
+            down: down.clone(),
+        };
+        let mut handler = BatchingHandler::new(flaky, 10, Duration::from_secs(60));
+        // The flush fails, but the buffer is accepted.
+        handler.consume(b"Mary had a\n").await?;
+        assert!(handler.consume(b"little\n").await.is_err());
+        // Other keys have room of their own.
+        handler.consume_keyed("app", b"lamb\n").await?;
+
+        down.store(false, Ordering::SeqCst);
+        handler.consume(b"little\n").await?;
+        handler.flush().await?;
+        assert_eq!(b"Mary had a\nlittle\nlamb\n".to_vec(), *buf.lock().unwrap());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn checkpoint_after_batch_is_flushed() -> Result<()> {
+        init();
+
+        let content = b"Mary had a little lamb\nLittle lamb, little lamb\n";
+        let mut temp_file = NamedTempFile::new()?;
+        temp_file.write_all(content)?;
+
+        let path_str = temp_file.path().to_str().unwrap();
+        let checkpoint_dir = tempdir()?;
+        let checkpoint_file = checkpoint_dir.path().join("checkpoints");
+        let checkpoint_str = checkpoint_file.to_str().unwrap();
+        let uploads = Arc::new(Mutex::new(vec![]));
+        let mut agent = new_agent(path_str, checkpoint_str, Arc::new(Mutex::new(vec![])))?;
+        agent.handler = Box::new(BatchingHandler::new(
+            UploadCollector {
+                uploads: uploads.clone(),
+            },
+            1024,
+            Duration::from_secs(60),
+        ));
+        for _ in 0..10 {
+            agent.work().await?;
+        }
+        assert!(uploads.lock().unwrap().is_empty());
+        drop(agent);
+
+        // Nothing was uploaded, so a restarted agent reads everything again.
+        let buf = Arc::new(Mutex::new(vec![]));
+        let mut agent = new_agent(path_str, checkpoint_str, buf.clone())?;
+        for _ in 0..10 {
+            agent.work().await?;
+        }
+        assert_eq!(content, buf.lock().unwrap().as_slice());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scan_for_new_files_once_per_interval() -> Result<()> {
+        init();
+
+        let dir = tempdir()?;
+        let pattern = dir.path().join("*.log");
+        let checkpoint_file = dir.path().join("checkpoints");
+        let mut agent = new_agent(
+            pattern.to_str().unwrap(),
+            checkpoint_file.to_str().unwrap(),
+            Arc::new(Mutex::new(vec![])),
+        )?;
+        assert!(!agent.should_scan());
+
+        // Files not matching any pattern are ignored.
+        agent
+            .woken
+            .push_back(dir.path().join("a.txt").to_str().unwrap().to_string());
+        assert_eq!(None, agent.next_woken());
+        assert!(!agent.should_scan());
+
+        agent
+            .woken
+            .push_back(dir.path().join("a.log").to_str().unwrap().to_string());
+        assert_eq!(None, agent.next_woken());
+        assert!(agent.should_scan());
+        agent.scan()?;
+
+        // Left to the periodic scan within the rescan interval.
+        agent
+            .woken
+            .push_back(dir.path().join("b.log").to_str().unwrap().to_string());
+        assert_eq!(None, agent.next_woken());
+        assert!(!agent.should_scan());
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn wake_upon_change() -> Result<()> {
+        init();
+
+        let mut temp_file = NamedTempFile::new()?;
+        let path_str = temp_file.path().to_str().unwrap().to_string();
+        let checkpoint_dir = tempdir()?;
+        let checkpoint_file = checkpoint_dir.path().join("checkpoints");
+        let buf = Arc::new(Mutex::new(vec![]));
+        let mut agent = new_agent(&path_str, checkpoint_file.to_str().unwrap(), buf.clone())?;
+        assert!(agent.watcher.is_some());
+
+        // Write once polling would have backed off to over a second,
+        // and shut down before the next poll.
+        let shutdown = async move {
+            sleep(Duration::from_secs(1)).await;
+            temp_file.write_all(b"Mary had\n").unwrap();
+            sleep(Duration::from_millis(200)).await;
+        };
+        agent.run(shutdown).await?;
+        assert_eq!(b"Mary had\n".to_vec(), *buf.lock().unwrap());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn run_until_shutdown() -> Result<()> {
+        init();
+
+        let content = b"Mary had a little lamb\nLittle lamb, little lamb\n";
+        let mut temp_file = NamedTempFile::new()?;
+        temp_file.write_all(content)?;
+
+        let path_str = temp_file.path().to_str().unwrap();
+        let checkpoint_dir = tempdir()?;
+        let checkpoint_file = checkpoint_dir.path().join("checkpoints");
+        let checkpoint_str = checkpoint_file.to_str().unwrap();
+        let uploads = Arc::new(Mutex::new(vec![]));
+        let mut agent = new_agent(path_str, checkpoint_str, Arc::new(Mutex::new(vec![])))?;
+        agent.handler = Box::new(BatchingHandler::new(
+            UploadCollector {
+                uploads: uploads.clone(),
+            },
+            1024,
+            Duration::from_secs(60),
+        ));
+        agent.run(sleep(Duration::from_millis(200))).await?;
+        // The batch is flushed upon shutdown.
+        assert_eq!(vec![content.to_vec()], *uploads.lock().unwrap());
+        drop(agent);
+
+        // And checkpointed, so a restarted agent has nothing to read.
+        let buf = Arc::new(Mutex::new(vec![]));
+        let mut agent = new_agent(path_str, checkpoint_str, buf.clone())?;
+        for _ in 0..10 {
+            agent.work().await?;
+        }
+        assert!(buf.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stop_upon_repeated_local_failures() -> Result<()> {
+        init();
+
+        let mut temp_file = NamedTempFile::new()?;
+        temp_file.write_all(b"Mary had a little lamb\n")?;
+        let path_str = temp_file.path().to_str().unwrap();
+        let checkpoint_dir = tempdir()?;
+        // Checkpoints cannot be saved into a missing directory.
+        let checkpoint_file = checkpoint_dir.path().join("missing").join("checkpoints");
+        let checkpoint_str = checkpoint_file.to_str().unwrap();
+        let mut agent = new_agent(path_str, checkpoint_str, Arc::new(Mutex::new(vec![])))?;
+        agent.watcher = None;
+        let result = agent.run(futures::future::pending()).await;
+        assert!(matches!(result, Err(WoodpeckerError::IoError(_))));
         Ok(())
     }
 }
@@ -0,0 +1,152 @@
+use crate::error::Result;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// A checkpoint records how far a file has been consumed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Path of the tailed file.
+    pub path: String,
+    /// Device and inode identify the file behind the path at the time of checkpoint.
+    pub device: u64,
+    pub inode: u64,
+    /// Number of bytes consumed from the start of the file.
+    pub offset: u64,
+    /// Cursor of the last consumed entry, for sources without a file to resume from,
+    /// e.g. the journal.
+    #[serde(default)]
+    pub cursor: Option<String>,
+}
+
+/// Persist checkpoints to a local file, keyed by the path of the tailed file.
+// Writes go to a temporary file first, then rename over the old one,
+// so a crash never leaves a half-written checkpoint behind.
+pub struct CheckpointStore {
+    path: PathBuf,
+    checkpoints: HashMap<String, Checkpoint>,
+}
+
+impl CheckpointStore {
+    /// Load checkpoints from path, or start empty if the file does not exist.
+    pub fn try_new(path: &str) -> Result<CheckpointStore> {
+        let path = PathBuf::from(path);
+        let checkpoints = if path.exists() {
+            let content = fs::read(&path)?;
+            serde_json::from_slice(&content)?
+        } else {
+            HashMap::new()
+        };
+        debug!("Loaded checkpoints from {:?}: {:?}", path, checkpoints);
+        Ok(CheckpointStore { path, checkpoints })
+    }
+
+    pub fn get(&self, path: &str) -> Option<&Checkpoint> {
+        self.checkpoints.get(path)
+    }
+
+    pub fn save(&mut self, checkpoint: Checkpoint) -> Result<()> {
+        debug!("Saving checkpoint: {:?}", checkpoint);
+        self.checkpoints.insert(checkpoint.path.clone(), checkpoint);
+        self.write()
+    }
+
+    /// Forget checkpoints for which keep returns false, e.g. of deleted files.
+    pub fn retain<F: FnMut(&Checkpoint) -> bool>(&mut self, mut keep: F) -> Result<()> {
+        let count = self.checkpoints.len();
+        self.checkpoints.retain(|_, checkpoint| keep(checkpoint));
+        if self.checkpoints.len() == count {
+            return Ok(());
+        }
+        debug!("Pruned {} checkpoints", count - self.checkpoints.len());
+        self.write()
+    }
+
+    fn write(&self) -> Result<()> {
+        let content = serde_json::to_vec(&self.checkpoints)?;
+        write_atomically(&self.path, &content)
+    }
+}
+
+/// Write content to a temporary file, then rename it over path, so a crash never leaves
+/// a half-written file behind. Sync the temporary file before the rename, and the
+/// directory after it, so the content survives a power loss.
+pub(crate) fn write_atomically(path: &Path, content: &[u8]) -> Result<()> {
+    let temp = temp_path(path);
+    let mut file = File::create(&temp)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    fs::rename(&temp, path)?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn roundtrip() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("checkpoints");
+        let path_str = path.to_str().unwrap();
+
+        let mut store = CheckpointStore::try_new(path_str)?;
+        assert_eq!(None, store.get("/var/log/app.log"));
+
+        let checkpoint = Checkpoint {
+            path: "/var/log/app.log".to_string(),
+            device: 1,
+            inode: 2,
+            offset: 3,
+            cursor: None,
+        };
+        store.save(checkpoint.clone())?;
+        assert_eq!(Some(&checkpoint), store.get("/var/log/app.log"));
+
+        // A new store picks up what the previous one saved.
+        let store = CheckpointStore::try_new(path_str)?;
+        assert_eq!(Some(&checkpoint), store.get("/var/log/app.log"));
+        Ok(())
+    }
+
+    #[test]
+    fn retain() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("checkpoints");
+        let path_str = path.to_str().unwrap();
+
+        let mut store = CheckpointStore::try_new(path_str)?;
+        for name in &["a.log", "b.log"] {
+            store.save(Checkpoint {
+                path: name.to_string(),
+                device: 1,
+                inode: 2,
+                offset: 3,
+                cursor: None,
+            })?;
+        }
+        store.retain(|checkpoint| checkpoint.path == "a.log")?;
+        assert!(store.get("a.log").is_some());
+        assert_eq!(None, store.get("b.log"));
+
+        let store = CheckpointStore::try_new(path_str)?;
+        assert!(store.get("a.log").is_some());
+        assert_eq!(None, store.get("b.log"));
+        Ok(())
+    }
+}
@@ -0,0 +1,446 @@
+use crate::agent::client::filter::FilterRule;
+use crate::agent::client::rate_limit::OverLimit;
+use crate::agent::client::redact::RedactionRule;
+use crate::agent::client::source::SourceConfig;
+use crate::agent::client::uploader::RetryPolicy;
+use crate::agent::protobuf;
+use crate::codec::Codec;
+use crate::config::{from_millis, ServiceConfig, AGENT_SERVICE_URL_VAR};
+use crate::error::{woodpecker_error, Result};
+use crate::event::EventBoundary;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::time::Duration;
+
+pub const USAGE: &str = "Usage: agent [--help] [--config <file>] [--file <pattern>]... \
+[--checkpoint-file <file>] [--enrollment-token <token>] [--label <key>=<value>]... \
+[--agent-service-url <url>]";
+/// Environment variable of the redaction key, to keep it out of the config file.
+pub const REDACTION_KEY_VAR: &str = "WOODPECKER_REDACTION_KEY";
+
+/// Configure the behaviour of the agent.
+/// In a config file, durations are in milliseconds, e.g. rescan_interval_ms.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AgentConfig {
+    /// Where to reach the agent service
+    pub agent_service_url: String,
+    /// Which log files to tail
+    pub files: Vec<FileConfig>,
+    /// How much buffer per file
+    pub buffer_size: usize,
+    /// Where to persist the offsets of tailed files across restarts
+    pub checkpoint_file: String,
+    /// How often to look for new files matching the patterns
+    #[serde(rename = "rescan_interval_ms", deserialize_with = "from_millis")]
+    pub rescan_interval: Duration,
+    /// How long a rotated file must go without new data before switching to the new file
+    #[serde(rename = "rotation_quiet_period_ms", deserialize_with = "from_millis")]
+    pub rotation_quiet_period: Duration,
+    /// Wake tailers upon changes to their files with inotify, instead of polling.
+    /// Fall back to polling where inotify is unavailable, e.g. outside Linux.
+    pub watch_files: bool,
+    /// How lines in the files are grouped into events
+    pub event_boundary: EventBoundary,
+    /// How long to wait for more lines of the last event in a file before uploading it,
+    /// as a multi-line event is only known to be complete once the next one starts
+    #[serde(rename = "event_timeout_ms", deserialize_with = "from_millis")]
+    pub event_timeout: Duration,
+    /// How often to send a heartbeat and poll configuration changes from the agent service
+    #[serde(rename = "check_in_interval_ms", deserialize_with = "from_millis")]
+    pub check_in_interval: Duration,
+    /// Where to persist the agent id across restarts
+    pub identity_file: String,
+    /// Attributes to register the agent with, e.g. environment or service
+    pub labels: HashMap<String, String>,
+    /// Secret to register with, by which the agent service knows the account of the agent
+    pub enrollment_token: String,
+    /// Where to keep buffers that fail to upload until the backend is reachable again
+    pub spool_dir: String,
+    /// How many bytes the spool may hold
+    pub spool_max_bytes: u64,
+    /// How to compress buffers before upload
+    pub compression: Codec,
+    /// How many bytes of buffers to batch into an upload
+    pub batch_size: usize,
+    /// Upload a batch at least this often, even if it is small
+    #[serde(rename = "flush_interval_ms", deserialize_with = "from_millis")]
+    pub flush_interval: Duration,
+    /// How to retry uploads that fail transiently, before spooling them
+    pub upload_retry: RetryPolicy,
+    /// Bytes per second to read across all files. Zero for unlimited.
+    pub rate_limit: u64,
+    /// What to do with files over their rate limit, or the global one
+    pub over_limit: OverLimit,
+    /// Rules to redact secrets from events before upload, applied in order
+    pub redactions: Vec<RedactionRule>,
+    /// Secret key of redactions in hash mode. Same key, same hashes across agents.
+    pub redaction_key: String,
+    /// Attach the source of events to uploads: hostname, agent id, file path, offset range
+    /// and upload time. Ingress adds them to the parsed events as system columns.
+    /// Off by default, as an ingress of an older version would parse them as events.
+    /// Events of a schema other than the default carry their schema either way.
+    pub attach_metadata: bool,
+    /// Where to read events from besides the files, e.g. stdin or the journal
+    pub sources: Vec<SourceConfig>,
+}
+
+/// Configure a group of log files.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FileConfig {
+    /// Glob pattern of the files, e.g. /var/log/app/*.log
+    pub pattern: String,
+    /// Schema to parse the files with. Empty for the default schema.
+    #[serde(default)]
+    pub schema_id: String,
+    /// Bytes per second to read from each file matching the pattern. Zero for unlimited.
+    #[serde(default)]
+    pub rate_limit: u64,
+    /// Keep only events matching any of these rules, if any
+    #[serde(default)]
+    pub include: Vec<FilterRule>,
+    /// Drop events matching any of these rules, e.g. health checks
+    #[serde(default)]
+    pub exclude: Vec<FilterRule>,
+    /// Regex of the schema, for rules by level. Set by the agent service from schema_id,
+    /// as built-in schemas are the only ones the agent knows of.
+    #[serde(skip)]
+    pub schema_regex: String,
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        AgentConfig {
+            agent_service_url: ServiceConfig::default().agent_service_url,
+            files: vec![],
+            buffer_size: 64 * 1024,
+            checkpoint_file: "checkpoints".to_string(),
+            rescan_interval: Duration::from_secs(10),
+            rotation_quiet_period: Duration::from_secs(1),
+            watch_files: true,
+            event_boundary: EventBoundary::NewLine,
+            event_timeout: Duration::from_secs(5),
+            check_in_interval: Duration::from_secs(30),
+            identity_file: "agent_id".to_string(),
+            labels: HashMap::new(),
+            enrollment_token: String::new(),
+            spool_dir: "spool".to_string(),
+            spool_max_bytes: 256 * 1024 * 1024,
+            compression: Codec::None,
+            batch_size: 4 * 1024 * 1024,
+            flush_interval: Duration::from_secs(10),
+            upload_retry: RetryPolicy::default(),
+            rate_limit: 0,
+            over_limit: OverLimit::Backpressure,
+            redactions: vec![],
+            redaction_key: String::new(),
+            attach_metadata: false,
+            sources: vec![],
+        }
+    }
+}
+
+impl FileConfig {
+    pub fn new(pattern: &str) -> FileConfig {
+        FileConfig {
+            pattern: pattern.to_string(),
+            schema_id: String::new(),
+            rate_limit: 0,
+            include: vec![],
+            exclude: vec![],
+            schema_regex: String::new(),
+        }
+    }
+}
+
+impl From<protobuf::FileConfig> for FileConfig {
+    fn from(file: protobuf::FileConfig) -> Self {
+        FileConfig {
+            pattern: file.pattern,
+            schema_id: file.schema_id,
+            rate_limit: file.rate_limit.map_or(0, |limit| limit.bytes_per_second),
+            include: file.include.into_iter().map(FilterRule::from).collect(),
+            exclude: file.exclude.into_iter().map(FilterRule::from).collect(),
+            schema_regex: file.schema_regex,
+        }
+    }
+}
+
+impl From<protobuf::FilterRule> for FilterRule {
+    fn from(rule: protobuf::FilterRule) -> Self {
+        FilterRule {
+            pattern: rule.pattern,
+            levels: rule.levels,
+        }
+    }
+}
+
+impl AgentConfig {
+    /// Load a config file in json. Missing fields take the default.
+    pub fn from_file(path: &str) -> Result<AgentConfig> {
+        let content = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    /// Load the config file given by --config, if any, then apply environment variables
+    /// and the other flags on top.
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<AgentConfig> {
+        let mut flags = vec![];
+        let mut args = args.into_iter();
+        while let Some(flag) = args.next() {
+            let value = args.next().ok_or_else(|| {
+                woodpecker_error(format!("Missing value of {}\n{}", flag, USAGE).as_str())
+            })?;
+            flags.push((flag, value));
+        }
+
+        let mut config = match flags.iter().find(|(flag, _)| flag == "--config") {
+            Some((_, path)) => AgentConfig::from_file(path)?,
+            None => AgentConfig::default(),
+        };
+        if let Ok(url) = env::var(AGENT_SERVICE_URL_VAR) {
+            config.agent_service_url = url;
+        }
+        if let Ok(key) = env::var(REDACTION_KEY_VAR) {
+            config.redaction_key = key;
+        }
+        let mut files = vec![];
+        for (flag, value) in flags {
+            match flag.as_str() {
+                "--config" => {}
+                "--file" => files.push(FileConfig::new(&value)),
+                "--checkpoint-file" => config.checkpoint_file = value,
+                "--enrollment-token" => config.enrollment_token = value,
+                "--agent-service-url" => config.agent_service_url = value,
+                "--label" => {
+                    let (key, value) = value.split_once('=').ok_or_else(|| {
+                        woodpecker_error(format!("Invalid label {}\n{}", value, USAGE).as_str())
+                    })?;
+                    config.labels.insert(key.to_string(), value.to_string());
+                }
+                _ => {
+                    return Err(woodpecker_error(
+                        format!("Unknown flag {}\n{}", flag, USAGE).as_str(),
+                    ))
+                }
+            }
+        }
+        if !files.is_empty() {
+            config.files = files;
+        }
+        Ok(config)
+    }
+
+    /// Apply configuration from the agent service on top of the local one.
+    pub fn merge(&self, remote: protobuf::AgentConfig) -> AgentConfig {
+        let mut config = self.clone();
+        config.files = remote.files.into_iter().map(FileConfig::from).collect();
+        if remote.buffer_size > 0 {
+            config.buffer_size = remote.buffer_size as usize;
+        }
+        if remote.flush_interval_ms > 0 {
+            config.flush_interval = Duration::from_millis(remote.flush_interval_ms);
+        }
+        match protobuf::Compression::from_i32(remote.compression) {
+            Some(protobuf::Compression::Unspecified) | None => {}
+            Some(compression) => config.compression = Codec::from(compression),
+        }
+        if let Some(limit) = remote.rate_limit {
+            config.rate_limit = limit.bytes_per_second;
+        }
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::client::redact::RedactMode;
+    use crate::agent::client::syslog::Protocol;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn merge() {
+        let local = AgentConfig {
+            agent_service_url: "http://[::1]:50051".to_string(),
+            files: vec![FileConfig::new("/var/log/*.log")],
+            buffer_size: 1024,
+            checkpoint_file: "checkpoints".to_string(),
+            rescan_interval: Duration::from_secs(10),
+            rotation_quiet_period: Duration::from_secs(1),
+            watch_files: true,
+            event_boundary: EventBoundary::NewLine,
+            event_timeout: Duration::from_secs(60),
+            check_in_interval: Duration::from_secs(60),
+            identity_file: "agent_id".to_string(),
+            labels: HashMap::new(),
+            enrollment_token: "token".to_string(),
+            spool_dir: "spool".to_string(),
+            spool_max_bytes: 1024,
+            compression: Codec::None,
+            batch_size: 1024 * 1024,
+            flush_interval: Duration::from_secs(0),
+            upload_retry: RetryPolicy::default(),
+            rate_limit: 0,
+            over_limit: OverLimit::Backpressure,
+            redactions: vec![],
+            redaction_key: String::new(),
+            attach_metadata: true,
+            sources: vec![],
+        };
+
+        let remote = protobuf::AgentConfig {
+            files: vec![protobuf::FileConfig {
+                pattern: "/var/log/app/*.log".to_string(),
+                schema_id: "app".to_string(),
+                rate_limit: Some(protobuf::RateLimit {
+                    bytes_per_second: 1024,
+                }),
+                include: vec![protobuf::FilterRule {
+                    pattern: "GET /orders".to_string(),
+                    ..Default::default()
+                }],
+                exclude: vec![protobuf::FilterRule {
+                    levels: vec!["DEBUG".to_string()],
+                    ..Default::default()
+                }],
+                schema_regex: r"(?P<level>\w+) (?P<content>.*)".to_string(),
+            }],
+            flush_interval_ms: 500,
+            compression: protobuf::Compression::Zstd as i32,
+            ..Default::default()
+        };
+        let merged = local.merge(remote);
+        assert_eq!(
+            vec![FileConfig {
+                pattern: "/var/log/app/*.log".to_string(),
+                schema_id: "app".to_string(),
+                rate_limit: 1024,
+                include: vec![FilterRule::pattern("GET /orders")],
+                exclude: vec![FilterRule::levels(&["DEBUG"])],
+                schema_regex: r"(?P<level>\w+) (?P<content>.*)".to_string(),
+            }],
+            merged.files
+        );
+        // Unset fields keep the local setting.
+        assert_eq!(1024, merged.buffer_size);
+        assert_eq!("checkpoints", merged.checkpoint_file);
+        assert_eq!(0, merged.rate_limit);
+        assert_eq!(Duration::from_millis(500), merged.flush_interval);
+        assert_eq!(Codec::Zstd, merged.compression);
+
+        // COMPRESSION_UNSPECIFIED keeps the local codec, COMPRESSION_NONE turns it off.
+        let local = AgentConfig {
+            compression: Codec::Gzip,
+            ..local
+        };
+        assert_eq!(
+            Codec::Gzip,
+            local.merge(protobuf::AgentConfig::default()).compression
+        );
+        let remote = protobuf::AgentConfig {
+            compression: protobuf::Compression::None as i32,
+            ..Default::default()
+        };
+        assert_eq!(Codec::None, local.merge(remote).compression);
+    }
+
+    #[test]
+    fn from_file() -> Result<()> {
+        let mut file = NamedTempFile::new()?;
+        file.write_all(
+            br#"{
+                "files": [{"pattern": "/var/log/*.log"}],
+                "rescan_interval_ms": 500,
+                "event_boundary": {"StartsWith": "\\["},
+                "compression": "gzip",
+                "over_limit": "drop",
+                "upload_retry": {"max_attempts": 2, "budget_ms": 1000},
+                "redactions": [{"name": "email"}, {"name": "id", "pattern": "id=\\d+", "mode": "hash"}],
+                "redaction_key": "secret",
+                "sources": [
+                    {"type": "stdin"},
+                    {"type": "journal", "path": "/run/journal.export"},
+                    {"type": "syslog", "protocol": "udp", "address": "0.0.0.0:514"}
+                ]
+            }"#,
+        )?;
+        let config = AgentConfig::from_file(file.path().to_str().unwrap())?;
+        assert_eq!(vec![FileConfig::new("/var/log/*.log")], config.files);
+        assert_eq!(Duration::from_millis(500), config.rescan_interval);
+        assert_eq!(
+            EventBoundary::StartsWith("\\[".to_string()),
+            config.event_boundary
+        );
+        assert_eq!(Codec::Gzip, config.compression);
+        assert_eq!(OverLimit::Drop, config.over_limit);
+        assert_eq!(2, config.upload_retry.max_attempts);
+        assert_eq!(Duration::from_secs(1), config.upload_retry.budget);
+        assert_eq!(
+            RetryPolicy::default().initial_backoff,
+            config.upload_retry.initial_backoff
+        );
+        assert_eq!(
+            vec![
+                RedactionRule::builtin("email"),
+                RedactionRule {
+                    name: "id".to_string(),
+                    pattern: "id=\\d+".to_string(),
+                    mode: RedactMode::Hash,
+                }
+            ],
+            config.redactions
+        );
+        assert_eq!("secret", config.redaction_key);
+        assert_eq!(
+            vec![
+                SourceConfig::Stdin,
+                SourceConfig::Journal {
+                    path: "/run/journal.export".to_string()
+                },
+                SourceConfig::Syslog {
+                    protocol: Protocol::Udp,
+                    address: "0.0.0.0:514".to_string()
+                }
+            ],
+            config.sources
+        );
+        // Missing fields take the default.
+        assert_eq!(AgentConfig::default().buffer_size, config.buffer_size);
+
+        let mut file = NamedTempFile::new()?;
+        file.write_all(br#"{"unknown": 1}"#)?;
+        assert!(AgentConfig::from_file(file.path().to_str().unwrap()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn from_args() -> Result<()> {
+        let mut file = NamedTempFile::new()?;
+        file.write_all(
+            br#"{"files": [{"pattern": "/var/log/*.log"}], "enrollment_token": "file"}"#,
+        )?;
+        let args = vec![
+            "--file",
+            "/var/log/app/*.log",
+            "--config",
+            file.path().to_str().unwrap(),
+            "--enrollment-token",
+            "flag",
+            "--label",
+            "env=test",
+        ];
+        let config = AgentConfig::from_args(args.into_iter().map(String::from))?;
+        // Flags win over the config file, regardless of order.
+        assert_eq!(vec![FileConfig::new("/var/log/app/*.log")], config.files);
+        assert_eq!("flag", config.enrollment_token);
+        assert_eq!(Some(&"test".to_string()), config.labels.get("env"));
+
+        assert!(AgentConfig::from_args(vec!["--file".to_string()]).is_err());
+        assert!(AgentConfig::from_args(vec!["--unknown".to_string(), "".to_string()]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,194 @@
+use crate::error::{woodpecker_error, Result};
+use crate::event::EventSplitter;
+use regex::bytes::Regex as BytesRegex;
+use serde::Deserialize;
+use std::borrow::Cow;
+
+/// Name of the group in a schema regex that captures the level of an event.
+const LEVEL_GROUP: &str = "level";
+
+/// Match events by a regex, or by their level. Set one of pattern and levels.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilterRule {
+    /// Regex found anywhere in an event, e.g. GET /health
+    pub pattern: String,
+    /// Levels, e.g. DEBUG, as captured by the group named level of the file's schema regex.
+    /// Compared regardless of case.
+    pub levels: Vec<String>,
+}
+
+impl FilterRule {
+    pub fn pattern(pattern: &str) -> FilterRule {
+        FilterRule {
+            pattern: pattern.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn levels(levels: &[&str]) -> FilterRule {
+        FilterRule {
+            levels: levels.iter().map(|level| level.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+}
+
+enum Matcher {
+    Pattern(BytesRegex),
+    Levels {
+        schema: BytesRegex,
+        levels: Vec<String>,
+    },
+}
+
+impl Matcher {
+    fn try_new(rule: &FilterRule, schema_regex: &str) -> Result<Matcher> {
+        match (rule.pattern.is_empty(), rule.levels.is_empty()) {
+            (false, true) => Ok(Matcher::Pattern(compile(&rule.pattern)?)),
+            (true, false) => {
+                let schema = compile(schema_regex)?;
+                if !schema.capture_names().any(|name| name == Some(LEVEL_GROUP)) {
+                    return Err(woodpecker_error(
+                        format!("No group named level in schema regex {}", schema_regex).as_str(),
+                    ));
+                }
+                Ok(Matcher::Levels {
+                    schema,
+                    levels: rule.levels.clone(),
+                })
+            }
+            _ => Err(woodpecker_error(
+                format!("Set one of pattern and levels in filter {:?}", rule).as_str(),
+            )),
+        }
+    }
+
+    fn matches(&self, event: &[u8]) -> bool {
+        match self {
+            Matcher::Pattern(regex) => regex.is_match(event),
+            Matcher::Levels { schema, levels } => schema
+                .captures(event)
+                .and_then(|caps| caps.name(LEVEL_GROUP))
+                .and_then(|level| std::str::from_utf8(level.as_bytes()).ok())
+                .is_some_and(|level| levels.iter().any(|l| l.eq_ignore_ascii_case(level))),
+        }
+    }
+}
+
+fn compile(pattern: &str) -> Result<BytesRegex> {
+    BytesRegex::new(pattern)
+        .map_err(|e| woodpecker_error(format!("Invalid pattern {}: {}", pattern, e).as_str()))
+}
+
+/// Keep the events of a file that match any include rule, if there are include rules,
+/// and no exclude rule.
+/// Parts of an event split for exceeding the buffer size are filtered on their own.
+pub struct EventFilter {
+    include: Vec<Matcher>,
+    exclude: Vec<Matcher>,
+}
+
+impl EventFilter {
+    pub fn try_new(
+        include: &[FilterRule],
+        exclude: &[FilterRule],
+        schema_regex: &str,
+    ) -> Result<EventFilter> {
+        let compile_all = |rules: &[FilterRule]| -> Result<Vec<Matcher>> {
+            rules
+                .iter()
+                .map(|rule| Matcher::try_new(rule, schema_regex))
+                .collect()
+        };
+        Ok(EventFilter {
+            include: compile_all(include)?,
+            exclude: compile_all(exclude)?,
+        })
+    }
+
+    fn keeps(&self, event: &[u8]) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|m| m.matches(event)))
+            && !self.exclude.iter().any(|m| m.matches(event))
+    }
+
+    /// Return the events to keep, and how many are dropped. Borrow the buffer if all are kept.
+    pub fn apply<'a>(&self, splitter: &EventSplitter, buffer: &'a [u8]) -> (Cow<'a, [u8]>, u64) {
+        let events = splitter.split_inclusive(buffer);
+        let kept: Vec<&[u8]> = events
+            .iter()
+            .copied()
+            .filter(|event| self.keeps(event))
+            .collect();
+        let dropped = (events.len() - kept.len()) as u64;
+        if dropped == 0 {
+            (Cow::Borrowed(buffer), 0)
+        } else {
+            (Cow::Owned(kept.concat()), dropped)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::EventBoundary;
+
+    const SCHEMA_REGEX: &str = r"\[(?P<timestamp>\S+) (?P<level>\w+)\] (?P<content>.*)";
+
+    #[test]
+    fn exclude_by_pattern_and_level() -> Result<()> {
+        let exclude = vec![
+            FilterRule::pattern("GET /health"),
+            FilterRule::levels(&["debug"]),
+        ];
+        let filter = EventFilter::try_new(&[], &exclude, SCHEMA_REGEX)?;
+        let (kept, dropped) = filter.apply(
+            &EventSplitter::default(),
+            b"[2021-05-01T00:00:00Z INFO] GET /health\n\
+            [2021-05-01T00:00:01Z DEBUG] Connected\n\
+            [2021-05-01T00:00:02Z INFO] GET /orders\n",
+        );
+        assert_eq!(
+            &b"[2021-05-01T00:00:02Z INFO] GET /orders\n"[..],
+            kept.as_ref()
+        );
+        assert_eq!(2, dropped);
+        Ok(())
+    }
+
+    #[test]
+    fn include_multi_line_events() -> Result<()> {
+        let include = vec![FilterRule::levels(&["ERROR", "WARN"])];
+        let filter = EventFilter::try_new(&include, &[], SCHEMA_REGEX)?;
+        let splitter = EventSplitter::try_new(&EventBoundary::Continuation)?;
+        let (kept, dropped) = filter.apply(
+            &splitter,
+            b"[2021-05-01T00:00:00Z INFO] Starting\n\
+            [2021-05-01T00:00:01Z ERROR] Oops\n  at main\n",
+        );
+        assert_eq!(
+            &b"[2021-05-01T00:00:01Z ERROR] Oops\n  at main\n"[..],
+            kept.as_ref()
+        );
+        assert_eq!(1, dropped);
+
+        let (kept, dropped) = filter.apply(&splitter, b"[2021-05-01T00:00:02Z WARN] Slow\n");
+        assert!(matches!(kept, Cow::Borrowed(_)));
+        assert_eq!(0, dropped);
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_rules() {
+        let rule = FilterRule {
+            pattern: "GET".to_string(),
+            levels: vec!["DEBUG".to_string()],
+        };
+        assert!(EventFilter::try_new(&[rule], &[], SCHEMA_REGEX).is_err());
+        // Levels need a schema regex with a level group.
+        let rule = FilterRule::levels(&["DEBUG"]);
+        assert!(EventFilter::try_new(std::slice::from_ref(&rule), &[], "").is_err());
+        assert!(EventFilter::try_new(&[], &[rule], r"(?P<content>.*)").is_err());
+    }
+}
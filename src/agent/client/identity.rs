@@ -0,0 +1,83 @@
+use crate::agent::client::checkpoint::write_atomically;
+use crate::error::Result;
+use log::info;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use uuid::Uuid;
+
+/// Who the agent is to the agent service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgentIdentity {
+    /// Generated once and kept across restarts.
+    pub agent_id: String,
+    pub hostname: String,
+    /// Free-form attributes for operators, e.g. environment or service.
+    pub labels: HashMap<String, String>,
+    /// Secret the agent service knows the account of the agent by.
+    pub enrollment_token: String,
+}
+
+impl AgentIdentity {
+    /// Load the agent id persisted at path, or generate and persist a new one.
+    /// An empty file counts as missing, e.g. after a crash while writing it.
+    pub fn load_or_create(
+        path: &str,
+        labels: HashMap<String, String>,
+        enrollment_token: &str,
+    ) -> Result<AgentIdentity> {
+        let persisted = if Path::new(path).exists() {
+            fs::read_to_string(path)?.trim().to_string()
+        } else {
+            String::new()
+        };
+        let agent_id = if !persisted.is_empty() {
+            persisted
+        } else {
+            let agent_id = Uuid::new_v4().to_string();
+            info!("Generated agent id {} at {}", agent_id, path);
+            write_atomically(Path::new(path), agent_id.as_bytes())?;
+            agent_id
+        };
+        let hostname = hostname::get()?.to_string_lossy().to_string();
+        Ok(AgentIdentity {
+            agent_id,
+            hostname,
+            labels,
+            enrollment_token: enrollment_token.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn load_or_create() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("agent_id");
+        let path_str = path.to_str().unwrap();
+
+        let mut labels = HashMap::new();
+        labels.insert("env".to_string(), "test".to_string());
+        let identity = AgentIdentity::load_or_create(path_str, labels.clone(), "token")?;
+        assert!(!identity.agent_id.is_empty());
+        assert!(!identity.hostname.is_empty());
+        assert_eq!(labels, identity.labels);
+
+        // The same id is used after restart.
+        let reloaded = AgentIdentity::load_or_create(path_str, HashMap::new(), "token")?;
+        assert_eq!(identity.agent_id, reloaded.agent_id);
+
+        // An empty file gets a new id.
+        fs::write(&path, "\n")?;
+        let regenerated = AgentIdentity::load_or_create(path_str, HashMap::new(), "token")?;
+        assert!(!regenerated.agent_id.is_empty());
+        assert_eq!(regenerated.agent_id, fs::read_to_string(&path)?);
+        // Written through a temporary file, which is renamed away.
+        assert_eq!(1, fs::read_dir(dir.path())?.count());
+        Ok(())
+    }
+}
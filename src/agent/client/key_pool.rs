@@ -0,0 +1,286 @@
+use crate::agent::protobuf::{
+    agent_service_client::AgentServiceClient, Compression, CreateKeysRequest, Key,
+};
+use crate::codec::Codec;
+use crate::error::{woodpecker_error, Result};
+use async_trait::async_trait;
+use log::{debug, warn};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tonic::transport::Channel;
+
+/// Where a KeyPool gets presigned urls from.
+#[async_trait]
+pub trait KeySource: Clone + Send + Sync + 'static {
+    async fn create_keys(&mut self, count: u32) -> Result<Vec<Key>>;
+}
+
+/// Create keys with the agent service, for uploads compressed with codec.
+#[derive(Clone)]
+pub struct ServiceKeySource {
+    client: AgentServiceClient<Channel>,
+    agent_id: String,
+    codec: Codec,
+}
+
+impl ServiceKeySource {
+    pub fn new(
+        client: AgentServiceClient<Channel>,
+        agent_id: String,
+        codec: Codec,
+    ) -> ServiceKeySource {
+        ServiceKeySource {
+            client,
+            agent_id,
+            codec,
+        }
+    }
+}
+
+#[async_trait]
+impl KeySource for ServiceKeySource {
+    async fn create_keys(&mut self, count: u32) -> Result<Vec<Key>> {
+        let request = CreateKeysRequest {
+            agent_id: self.agent_id.clone(),
+            count,
+            compression: Compression::from(self.codec) as i32,
+        };
+        Ok(self.client.create_keys(request).await?.into_inner().keys)
+    }
+}
+
+struct PooledKey {
+    url: String,
+    expires_at: SystemTime,
+    /// When the time partition of the key ends, if the service says so.
+    use_before: Option<SystemTime>,
+}
+
+impl From<Key> for PooledKey {
+    fn from(key: Key) -> Self {
+        let use_before = if key.use_before_ms > 0 {
+            Some(UNIX_EPOCH + Duration::from_millis(key.use_before_ms as u64))
+        } else {
+            None
+        };
+        PooledKey {
+            url: key.url,
+            expires_at: UNIX_EPOCH + Duration::from_millis(key.expires_at_ms.max(0) as u64),
+            use_before,
+        }
+    }
+}
+
+/// A KeyPool keeps presigned urls ahead of uploads, so that an upload rarely waits
+/// for the agent service and no url is wasted.
+/// The pool refills in the background once it runs low. Clones share the same keys.
+#[derive(Clone)]
+pub struct KeyPool<S: KeySource> {
+    source: S,
+    keys: Arc<Mutex<VecDeque<PooledKey>>>,
+    refilling: Arc<AtomicBool>,
+    batch_size: u32,
+    low_watermark: usize,
+    /// Discard urls expiring within this time, which leaves time to upload and
+    /// tolerates clock skew with the agent service.
+    margin: Duration,
+}
+
+impl<S: KeySource> KeyPool<S> {
+    pub fn new(source: S, batch_size: u32, margin: Duration) -> KeyPool<S> {
+        KeyPool {
+            source,
+            keys: Arc::new(Mutex::new(VecDeque::new())),
+            refilling: Arc::new(AtomicBool::new(false)),
+            batch_size,
+            low_watermark: (batch_size as usize / 4).max(1),
+            margin,
+        }
+    }
+
+    /// Take an unexpired key, waiting for the agent service only if the pool is empty.
+    pub async fn take(&mut self) -> Result<String> {
+        let key = match self.pop_unexpired() {
+            Some(key) => key,
+            None => {
+                debug!("Key pool is empty, creating keys");
+                let mut keys = self.source.create_keys(self.batch_size).await?;
+                if keys.is_empty() {
+                    return Err(woodpecker_error("Agent service created no keys"));
+                }
+                // Fresh from the service, use it even if it expires soon.
+                let key = keys.remove(0).url;
+                push(&self.keys, keys);
+                key
+            }
+        };
+        if self.len() < self.low_watermark {
+            self.refill_in_background();
+        }
+        Ok(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drop all keys, e.g. once the service rejects one of them.
+    pub fn clear(&self) {
+        self.keys.lock().unwrap().clear();
+    }
+
+    /// Pop a key that neither expires within the margin nor is past its partition.
+    fn pop_unexpired(&self) -> Option<String> {
+        let now = SystemTime::now();
+        let deadline = now + self.margin;
+        let mut keys = self.keys.lock().unwrap();
+        while let Some(key) = keys.pop_front() {
+            let past_partition = matches!(key.use_before, Some(use_before) if use_before <= now);
+            if key.expires_at > deadline && !past_partition {
+                return Some(key.url);
+            }
+            debug!("Discard expired key {}", key.url);
+        }
+        None
+    }
+
+    fn refill_in_background(&self) {
+        if self.refilling.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let mut source = self.source.clone();
+        let keys = self.keys.clone();
+        let refilling = self.refilling.clone();
+        let batch_size = self.batch_size;
+        tokio::spawn(async move {
+            match source.create_keys(batch_size).await {
+                Ok(new_keys) => push(&keys, new_keys),
+                Err(e) => warn!("Failed to refill keys: {}", e),
+            }
+            refilling.store(false, Ordering::SeqCst);
+        });
+    }
+}
+
+fn push(keys: &Mutex<VecDeque<PooledKey>>, new_keys: Vec<Key>) {
+    keys.lock()
+        .unwrap()
+        .extend(new_keys.into_iter().map(PooledKey::from));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    fn init() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[derive(Clone)]
+    struct CountingKeySource {
+        requests: Arc<AtomicUsize>,
+        expires_in: Duration,
+        /// How long until the partition of the keys ends, if ever.
+        partition_ends_in: Option<Duration>,
+    }
+
+    impl CountingKeySource {
+        fn new(expires_in: Duration) -> CountingKeySource {
+            CountingKeySource {
+                requests: Arc::new(AtomicUsize::new(0)),
+                expires_in,
+                partition_ends_in: None,
+            }
+        }
+    }
+
+    fn millis_since_epoch(time: SystemTime) -> i64 {
+        time.duration_since(UNIX_EPOCH).unwrap().as_millis() as i64
+    }
+
+    #[async_trait]
+    impl KeySource for CountingKeySource {
+        async fn create_keys(&mut self, count: u32) -> Result<Vec<Key>> {
+            let request = self.requests.fetch_add(1, Ordering::SeqCst);
+            let expires_at_ms = millis_since_epoch(SystemTime::now() + self.expires_in);
+            let use_before_ms = self
+                .partition_ends_in
+                .map_or(0, |ends_in| millis_since_epoch(SystemTime::now() + ends_in));
+            Ok((0..count)
+                .map(|i| Key {
+                    url: format!("{}-{}", request, i),
+                    expires_at_ms,
+                    use_before_ms,
+                })
+                .collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn take_from_pool() -> Result<()> {
+        init();
+        let source = CountingKeySource::new(Duration::from_secs(3600));
+        let mut pool = KeyPool::new(source.clone(), 4, Duration::from_secs(60));
+
+        assert_eq!("0-0", pool.take().await?);
+        assert_eq!(3, pool.len());
+        assert_eq!("0-1", pool.take().await?);
+        assert_eq!("0-2", pool.take().await?);
+        assert_eq!(1, source.requests.load(Ordering::SeqCst));
+
+        // Running low, refill in the background.
+        assert_eq!("0-3", pool.take().await?);
+        tokio::task::yield_now().await;
+        assert_eq!(2, source.requests.load(Ordering::SeqCst));
+        assert_eq!(4, pool.len());
+        assert_eq!("1-0", pool.take().await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn discard_expired_keys() -> Result<()> {
+        init();
+        let source = CountingKeySource::new(Duration::from_secs(30));
+        let mut pool = KeyPool::new(source.clone(), 4, Duration::from_secs(60));
+
+        assert_eq!("0-0", pool.take().await?);
+        // The rest of the keys expire within the margin.
+        assert_eq!("1-0", pool.take().await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn discard_keys_past_partition() -> Result<()> {
+        init();
+        let mut source = CountingKeySource::new(Duration::from_secs(3600));
+        source.partition_ends_in = Some(Duration::from_millis(50));
+        let mut pool = KeyPool::new(source.clone(), 4, Duration::from_secs(60));
+
+        assert_eq!("0-0", pool.take().await?);
+        assert_eq!("0-1", pool.take().await?);
+        // The rest of the keys would upload into a past partition.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!("1-0", pool.take().await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn clear() -> Result<()> {
+        init();
+        let source = CountingKeySource::new(Duration::from_secs(3600));
+        let mut pool = KeyPool::new(source.clone(), 4, Duration::from_secs(60));
+
+        assert_eq!("0-0", pool.take().await?);
+        pool.clear();
+        assert!(pool.is_empty());
+        assert_eq!("1-0", pool.take().await?);
+        Ok(())
+    }
+}
@@ -1,3 +1,14 @@
 pub mod agent;
+pub mod checkpoint;
+pub mod config;
+pub mod filter;
+pub mod identity;
+pub mod key_pool;
+pub mod rate_limit;
+pub mod redact;
+pub mod source;
+pub mod spool;
+pub mod syslog;
 pub mod tailer;
 pub mod uploader;
+pub mod watcher;
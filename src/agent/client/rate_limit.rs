@@ -0,0 +1,155 @@
+use serde::Deserialize;
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// What the agent does with a file that exceeds its rate limit, or the global one.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OverLimit {
+    /// Stop reading the file until the limit allows, and catch up later.
+    #[default]
+    Backpressure,
+    /// Keep reading, but drop what exceeds the limit. What is kept is a sample of the file.
+    Drop,
+}
+
+/// Counters of rate limiting since the agent started.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RateLimitMetrics {
+    /// Turns a file was skipped to wait for the limit.
+    pub throttled: u64,
+    pub dropped_buffers: u64,
+    pub dropped_bytes: u64,
+}
+
+/// Where rate limiters get the time from, which tests control.
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+#[derive(Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when advanced.
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub struct ManualClock(Arc<std::sync::Mutex<Instant>>);
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        ManualClock(Arc::new(std::sync::Mutex::new(Instant::now())))
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.0.lock().unwrap() += duration;
+    }
+}
+
+#[cfg(test)]
+impl Default for ManualClock {
+    fn default() -> ManualClock {
+        ManualClock::new()
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// A token bucket of bytes, which allows a burst of one second worth of bytes.
+/// Consuming may overdraw the bucket, e.g. with a buffer larger than the rate,
+/// then the limiter is not ready again until the debt is paid back.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_second: u64,
+    tokens: f64,
+    last_refill: Instant,
+    clock: Arc<dyn Clock>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: u64) -> RateLimiter {
+        RateLimiter {
+            bytes_per_second,
+            tokens: bytes_per_second as f64,
+            last_refill: Instant::now(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.last_refill = clock.now();
+        self.clock = clock;
+        self
+    }
+
+    pub fn bytes_per_second(&self) -> u64 {
+        self.bytes_per_second
+    }
+
+    /// How long until bytes may be consumed again. Zero if right away.
+    pub fn wait_time(&mut self) -> Duration {
+        self.refill();
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.bytes_per_second as f64)
+        }
+    }
+
+    pub fn consume(&mut self, bytes: usize) {
+        self.refill();
+        self.tokens -= bytes as f64;
+    }
+
+    fn refill(&mut self) {
+        let now = self.clock.now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        let capacity = self.bytes_per_second as f64;
+        self.tokens = (self.tokens + elapsed * capacity).min(capacity);
+        self.last_refill = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_then_wait() {
+        let clock = ManualClock::new();
+        let mut limiter = RateLimiter::new(1000).with_clock(Arc::new(clock.clone()));
+        assert_eq!(Duration::from_secs(0), limiter.wait_time());
+        limiter.consume(600);
+        assert_eq!(Duration::from_secs(0), limiter.wait_time());
+        // Overdraw by 200 bytes, which takes 200ms to pay back.
+        limiter.consume(600);
+        assert_eq!(Duration::from_millis(200), limiter.wait_time());
+
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(Duration::from_millis(100), limiter.wait_time());
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(Duration::from_secs(0), limiter.wait_time());
+    }
+
+    #[test]
+    fn burst_is_capped() {
+        let clock = ManualClock::new();
+        let mut limiter = RateLimiter::new(1000).with_clock(Arc::new(clock.clone()));
+        clock.advance(Duration::from_secs(10));
+        // Idle time does not accumulate beyond one second worth of bytes.
+        limiter.consume(1100);
+        assert_eq!(Duration::from_millis(100), limiter.wait_time());
+    }
+}
@@ -0,0 +1,619 @@
+use crate::agent::client::checkpoint::Checkpoint;
+use crate::agent::client::syslog::{self, Protocol, SyslogSource};
+use crate::error::{woodpecker_error, Result};
+use crate::event::EventSplitter;
+use log::{debug, info, warn};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, Read};
+use std::sync::mpsc::{sync_channel, Receiver, TryRecvError};
+use std::thread;
+
+/// Bytes to read from a stream at a time.
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Chunks read ahead of the agent, before the reader blocks.
+const CHUNKS_AHEAD: usize = 16;
+/// Field of a journal entry with its cursor.
+const CURSOR_FIELD: &str = "__CURSOR";
+
+/// Names and values of the fields of a journal entry, in order.
+type Fields = Vec<(String, Vec<u8>)>;
+
+/// Where to read events from, besides the files.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum SourceConfig {
+    /// Events piped into the agent, e.g. in a container. Stdin cannot be resumed,
+    /// so whatever is not uploaded before a restart is lost.
+    Stdin,
+    /// Entries in journal export format from a file or a named pipe,
+    /// e.g. written by journalctl -o export -f. Each entry becomes an event of one line
+    /// with its fields in json. Resume after the cursor of the last uploaded entry.
+    Journal { path: String },
+    /// Syslog messages received on a port, e.g. from network appliances.
+    /// Each message becomes an event of one line, which ingress parses with the built-in
+    /// syslog schema.
+    Syslog { protocol: Protocol, address: String },
+}
+
+impl SourceConfig {
+    /// Name to checkpoint the source by, and to attach as the path of its events.
+    pub fn name(&self) -> String {
+        match self {
+            SourceConfig::Stdin => "stdin".to_string(),
+            SourceConfig::Journal { path } => format!("journal:{}", path),
+            SourceConfig::Syslog { protocol, address } => syslog::source_name(*protocol, address),
+        }
+    }
+
+    /// Start reading the source in the background. Fail if a port cannot be listened on.
+    pub fn open(
+        &self,
+        splitter: EventSplitter,
+        buffer_size: usize,
+        checkpoint: Option<&Checkpoint>,
+    ) -> Result<Box<dyn Source>> {
+        Ok(match self {
+            SourceConfig::Stdin => Box::new(StreamSource::new(
+                &self.name(),
+                || Ok(io::stdin()),
+                splitter,
+                buffer_size,
+            )),
+            SourceConfig::Journal { path } => {
+                let path = path.clone();
+                let cursor = checkpoint.and_then(|checkpoint| checkpoint.cursor.clone());
+                Box::new(JournalSource::new(
+                    &self.name(),
+                    move || File::open(path),
+                    buffer_size,
+                    cursor,
+                ))
+            }
+            SourceConfig::Syslog { protocol, address } => {
+                Box::new(SyslogSource::bind(*protocol, address, buffer_size)?)
+            }
+        })
+    }
+}
+
+/// A stream of events other than a tailed file.
+/// A source reads in the background and never blocks the agent.
+pub trait Source: Send {
+    fn name(&self) -> &str;
+    /// Return complete events, or None if none is available for now.
+    /// An event larger than the buffer is split at buffer size.
+    fn read(&mut self) -> Result<Option<&[u8]>>;
+    /// Like read, but split an event larger than the buffer at the point of the full buffer
+    /// given by split_point. Sources that do not split events just read.
+    fn read_split_by(&mut self, _split_point: &dyn Fn(&[u8]) -> usize) -> Result<Option<&[u8]>> {
+        self.read()
+    }
+    /// Return the partial event carried over, if any. Use it once the source is closed.
+    fn flush(&mut self) -> Option<&[u8]>;
+    /// Schema to parse the events with. Empty for the default schema.
+    fn schema_id(&self) -> &str {
+        ""
+    }
+    /// Where to resume after a restart, None if the source cannot resume.
+    fn checkpoint(&self) -> Option<Checkpoint>;
+    /// Whether the stream ended, or failed. Read until it returns None, then flush the rest.
+    fn is_closed(&self) -> bool;
+}
+
+/// Chunks of a stream read by a background thread, which blocks while the agent is behind.
+struct Chunks {
+    name: String,
+    receiver: Receiver<io::Result<Vec<u8>>>,
+    closed: bool,
+}
+
+impl Chunks {
+    fn spawn<R, F>(name: &str, open: F) -> Chunks
+    where
+        R: Read,
+        F: FnOnce() -> io::Result<R> + Send + 'static,
+    {
+        let (sender, receiver) = sync_channel(CHUNKS_AHEAD);
+        // Open in the background too, since opening a named pipe blocks until a writer opens it.
+        thread::spawn(move || {
+            let mut reader = match open() {
+                Ok(reader) => reader,
+                Err(e) => {
+                    let _ = sender.send(Err(e));
+                    return;
+                }
+            };
+            loop {
+                let mut chunk = vec![0; CHUNK_SIZE];
+                match reader.read(&mut chunk) {
+                    Ok(0) => return,
+                    Ok(bytes) => {
+                        chunk.truncate(bytes);
+                        if sender.send(Ok(chunk)).is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        let _ = sender.send(Err(e));
+                        return;
+                    }
+                }
+            }
+        });
+        Chunks {
+            name: name.to_string(),
+            receiver,
+            closed: false,
+        }
+    }
+
+    /// Append the chunks available, until buffer holds max bytes or more.
+    /// A failure closes the stream, as there is no way to read it again.
+    fn fill(&mut self, buffer: &mut Vec<u8>, max: usize) {
+        while !self.closed && buffer.len() < max {
+            match self.receiver.try_recv() {
+                Ok(Ok(chunk)) => buffer.extend(chunk),
+                Ok(Err(e)) => {
+                    warn!("Failed to read {}, close it: {}", self.name, e);
+                    self.closed = true;
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    info!("Reached end of {}", self.name);
+                    self.closed = true;
+                }
+            }
+        }
+    }
+}
+
+/// Events split by the event boundary from a stream, e.g. stdin.
+pub struct StreamSource {
+    chunks: Chunks,
+    buffer: Vec<u8>,
+    buffer_size: usize,
+    splitter: EventSplitter,
+    /// Bytes at the front of buffer returned by the previous read.
+    returned: usize,
+}
+
+impl StreamSource {
+    pub fn new<R, F>(
+        name: &str,
+        open: F,
+        splitter: EventSplitter,
+        buffer_size: usize,
+    ) -> StreamSource
+    where
+        R: Read,
+        F: FnOnce() -> io::Result<R> + Send + 'static,
+    {
+        StreamSource {
+            chunks: Chunks::spawn(name, open),
+            buffer: Vec::with_capacity(buffer_size),
+            buffer_size,
+            splitter,
+            returned: 0,
+        }
+    }
+
+    fn take(&mut self, end: usize) -> &[u8] {
+        self.returned = end;
+        &self.buffer[..end]
+    }
+}
+
+impl Source for StreamSource {
+    fn name(&self) -> &str {
+        &self.chunks.name
+    }
+
+    fn read(&mut self) -> Result<Option<&[u8]>> {
+        self.read_split_by(&|buffer| buffer.len())
+    }
+
+    fn read_split_by(&mut self, split_point: &dyn Fn(&[u8]) -> usize) -> Result<Option<&[u8]>> {
+        self.buffer.drain(..self.returned);
+        self.returned = 0;
+        self.chunks.fill(&mut self.buffer, self.buffer_size);
+        let filled = self.buffer.len().min(self.buffer_size);
+        if let Some(end) = self.splitter.end_of_last_event(&self.buffer[..filled]) {
+            return Ok(Some(self.take(end)));
+        }
+        if filled == self.buffer_size {
+            warn!(
+                "Event in {} exceeds buffer size of {} bytes, split it",
+                self.chunks.name, self.buffer_size
+            );
+            let end = split_point(&self.buffer[..filled]).clamp(1, filled);
+            return Ok(Some(self.take(end)));
+        }
+        Ok(None)
+    }
+
+    fn flush(&mut self) -> Option<&[u8]> {
+        self.buffer.drain(..self.returned);
+        self.returned = 0;
+        if self.buffer.is_empty() {
+            None
+        } else {
+            Some(self.take(self.buffer.len()))
+        }
+    }
+
+    fn checkpoint(&self) -> Option<Checkpoint> {
+        None
+    }
+
+    fn is_closed(&self) -> bool {
+        self.chunks.closed
+    }
+}
+
+/// Entries of the journal in export format, as events of one line of json.
+/// See https://systemd.io/JOURNAL_EXPORT_FORMATS/
+pub struct JournalSource {
+    chunks: Chunks,
+    /// Export bytes not parsed into entries yet, i.e. a partial entry.
+    input: Vec<u8>,
+    events: Vec<u8>,
+    buffer_size: usize,
+    /// Cursor of the last returned entry.
+    cursor: Option<String>,
+    /// Cursor of the last entry uploaded before a restart, to skip entries up to it.
+    resume_after: Option<String>,
+}
+
+impl JournalSource {
+    pub fn new<R, F>(
+        name: &str,
+        open: F,
+        buffer_size: usize,
+        resume_after: Option<String>,
+    ) -> JournalSource
+    where
+        R: Read,
+        F: FnOnce() -> io::Result<R> + Send + 'static,
+    {
+        JournalSource {
+            chunks: Chunks::spawn(name, open),
+            input: vec![],
+            events: vec![],
+            buffer_size,
+            cursor: None,
+            resume_after,
+        }
+    }
+
+    fn append(&mut self, fields: Fields) -> Result<()> {
+        let mut entry = Map::new();
+        for (name, value) in fields {
+            let value = String::from_utf8_lossy(&value).into_owned();
+            entry.insert(name, Value::String(value));
+        }
+        let cursor = entry
+            .get(CURSOR_FIELD)
+            .and_then(|cursor| cursor.as_str())
+            .map(String::from);
+        // Keep skipping until an entry is known to come after, not upon one without cursor.
+        if let (Some(resume_after), Some(cursor)) = (&self.resume_after, &cursor) {
+            if !is_after(cursor, resume_after) {
+                debug!("Skip entry {} uploaded before", cursor);
+                return Ok(());
+            }
+            self.resume_after = None;
+        }
+        serde_json::to_writer(&mut self.events, &entry)?;
+        self.events.push(b'\n');
+        if cursor.is_some() {
+            self.cursor = cursor;
+        }
+        Ok(())
+    }
+}
+
+impl Source for JournalSource {
+    fn name(&self) -> &str {
+        &self.chunks.name
+    }
+
+    fn read(&mut self) -> Result<Option<&[u8]>> {
+        self.events.clear();
+        // What is left is a partial entry. Read on if it is larger than the buffer.
+        let max = if self.input.len() >= self.buffer_size {
+            self.input.len() + 1
+        } else {
+            self.buffer_size
+        };
+        self.chunks.fill(&mut self.input, max);
+        let mut parsed = 0;
+        loop {
+            // The stream cannot be parsed past a corrupt entry, so stop reading it.
+            let (fields, len) = match parse_entry(&self.input[parsed..], self.buffer_size) {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break,
+                Err(e) => {
+                    self.chunks.closed = true;
+                    self.input.clear();
+                    return Err(woodpecker_error(
+                        format!("Failed to parse {}: {}", self.chunks.name, e).as_str(),
+                    ));
+                }
+            };
+            parsed += len;
+            if !fields.is_empty() {
+                self.append(fields)?;
+            }
+        }
+        self.input.drain(..parsed);
+        if self.events.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(&self.events))
+    }
+
+    /// A partial entry is incomplete export data, which is dropped.
+    fn flush(&mut self) -> Option<&[u8]> {
+        if !self.input.is_empty() {
+            warn!(
+                "Drop {} bytes of a partial entry at the end of {}",
+                self.input.len(),
+                self.chunks.name
+            );
+            self.input.clear();
+        }
+        None
+    }
+
+    /// Resume by cursor, as the journal has no offsets.
+    fn checkpoint(&self) -> Option<Checkpoint> {
+        let cursor = self.cursor.clone()?;
+        Some(Checkpoint {
+            path: self.chunks.name.clone(),
+            device: 0,
+            inode: 0,
+            offset: 0,
+            cursor: Some(cursor),
+        })
+    }
+
+    fn is_closed(&self) -> bool {
+        self.chunks.closed
+    }
+}
+
+/// Parse the fields of the first entry in bytes, and how many bytes it takes.
+/// None if the entry is incomplete. Fail on a binary field larger than max_field_size.
+/// A text field is NAME=value and a new line. A binary field is NAME and a new line,
+/// then its size in 64-bit little endian, the value, and a new line.
+/// An empty line ends the entry.
+fn parse_entry(bytes: &[u8], max_field_size: usize) -> Result<Option<(Fields, usize)>> {
+    let mut fields = vec![];
+    let mut pos = 0;
+    loop {
+        let end_of_line = match bytes[pos..].iter().position(|&b| b == b'\n') {
+            Some(len) => pos + len,
+            None => return Ok(None),
+        };
+        let line = &bytes[pos..end_of_line];
+        if line.is_empty() {
+            return Ok(Some((fields, end_of_line + 1)));
+        }
+        match line.iter().position(|&b| b == b'=') {
+            Some(eq) => {
+                let name = String::from_utf8_lossy(&line[..eq]).into_owned();
+                fields.push((name, line[eq + 1..].to_vec()));
+                pos = end_of_line + 1;
+            }
+            None => {
+                let name = String::from_utf8_lossy(line).into_owned();
+                let start = end_of_line + 1 + 8;
+                let mut size = [0; 8];
+                match bytes.get(end_of_line + 1..start) {
+                    Some(bytes) => size.copy_from_slice(bytes),
+                    None => return Ok(None),
+                }
+                let size = u64::from_le_bytes(size);
+                let end = usize::try_from(size)
+                    .ok()
+                    .filter(|&size| size <= max_field_size)
+                    .and_then(|size| start.checked_add(size))
+                    .ok_or_else(|| {
+                        woodpecker_error(
+                            format!(
+                                "Binary field {} of {} bytes exceeds {} bytes",
+                                name, size, max_field_size
+                            )
+                            .as_str(),
+                        )
+                    })?;
+                // The value is followed by a new line.
+                match bytes.get(start..end) {
+                    Some(value) if end < bytes.len() => fields.push((name, value.to_vec())),
+                    _ => return Ok(None),
+                }
+                pos = end + 1;
+            }
+        }
+    }
+}
+
+/// Whether the entry at cursor comes after the one at other. A cursor is a list of
+/// key=value, e.g. s=<seqnum id>;i=<seqnum>;b=<boot id>;m=<monotonic>;t=<realtime>;x=<hash>.
+/// Compare sequence numbers of the same journal file, otherwise the realtime.
+/// Assume after if the cursors tell nothing, not to lose entries.
+fn is_after(cursor: &str, other: &str) -> bool {
+    let parse = |cursor: &str| -> HashMap<String, String> {
+        cursor
+            .split(';')
+            .filter_map(|part| part.split_once('='))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    };
+    let hex = |fields: &HashMap<String, String>, key: &str| {
+        fields
+            .get(key)
+            .and_then(|value| u64::from_str_radix(value, 16).ok())
+    };
+    let (a, b) = (parse(cursor), parse(other));
+    if a.contains_key("s") && a.get("s") == b.get("s") {
+        if let (Some(a), Some(b)) = (hex(&a, "i"), hex(&b, "i")) {
+            return a > b;
+        }
+    }
+    match (hex(&a, "t"), hex(&b, "t")) {
+        (Some(a), Some(b)) => a > b,
+        _ => cursor != other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::EventBoundary;
+    use std::io::Cursor;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    fn init() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    /// Read until the source is closed, then flush it, as the agent does.
+    fn read_all(source: &mut dyn Source) -> Result<Vec<Vec<u8>>> {
+        let mut buffers = vec![];
+        for _ in 0..100 {
+            if let Some(buffer) = source.read()? {
+                buffers.push(buffer.to_vec());
+            } else if source.is_closed() {
+                if let Some(buffer) = source.flush() {
+                    buffers.push(buffer.to_vec());
+                }
+                return Ok(buffers);
+            } else {
+                sleep(Duration::from_millis(10));
+            }
+        }
+        Err(woodpecker_error("Source is not closed"))
+    }
+
+    fn entry(cursor: &str, message: &str) -> Vec<u8> {
+        format!(
+            "__CURSOR={}\n__REALTIME_TIMESTAMP=1620000000000000\nMESSAGE={}\n\n",
+            cursor, message
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn read_stream() -> Result<()> {
+        init();
+        let splitter = EventSplitter::try_new(&EventBoundary::Continuation)?;
+        let input = b"Mary had\n  a little lamb\nIts fleece was white as snow\n";
+        let mut source = StreamSource::new("stdin", move || Ok(Cursor::new(input)), splitter, 64);
+        // The last event is only known to be complete once the stream is closed.
+        assert_eq!(
+            vec![
+                b"Mary had\n  a little lamb\n".to_vec(),
+                b"Its fleece was white as snow\n".to_vec()
+            ],
+            read_all(&mut source)?
+        );
+        assert_eq!(None, source.checkpoint());
+        Ok(())
+    }
+
+    #[test]
+    fn read_journal_export() -> Result<()> {
+        init();
+        let mut input = entry("s=a;i=1", "Mary had a little lamb");
+        // A binary field, with a new line in the value.
+        input.extend(b"__CURSOR=s=a;i=2\nMESSAGE\n");
+        input.extend(&12u64.to_le_bytes());
+        input.extend(b"Its fleece\nw\n\n");
+        let mut source = JournalSource::new("journal", move || Ok(Cursor::new(input)), 1024, None);
+        let events = read_all(&mut source)?.concat();
+        let events: Vec<Value> = events
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(serde_json::from_slice)
+            .collect::<std::result::Result<_, _>>()?;
+        assert_eq!(2, events.len());
+        assert_eq!("Mary had a little lamb", events[0]["MESSAGE"]);
+        assert_eq!("1620000000000000", events[0]["__REALTIME_TIMESTAMP"]);
+        assert_eq!("Its fleece\nw", events[1]["MESSAGE"]);
+        assert_eq!(
+            Some("s=a;i=2".to_string()),
+            source.checkpoint().and_then(|checkpoint| checkpoint.cursor)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn resume_after_cursor() -> Result<()> {
+        init();
+        let input = [
+            b"MESSAGE=Without cursor\n\n".to_vec(),
+            entry("s=a;i=9;t=5", "Mary had a little lamb"),
+            entry("s=a;i=a;t=6", "Its fleece was white as snow"),
+            entry("s=b;i=1;t=7", "And everywhere that Mary went"),
+        ]
+        .concat();
+        let resume_after = Some("s=a;i=9;t=5".to_string());
+        let mut source = JournalSource::new(
+            "journal",
+            move || Ok(Cursor::new(input)),
+            1024,
+            resume_after,
+        );
+        let events = String::from_utf8(read_all(&mut source)?.concat()).unwrap();
+        assert!(events.contains("Without cursor"));
+        assert!(!events.contains("Mary had a little lamb"));
+        assert!(events.contains("Its fleece was white as snow"));
+        assert!(events.contains("And everywhere that Mary went"));
+
+        assert!(is_after("s=a;i=a", "s=a;i=9"));
+        assert!(!is_after("s=b;i=1;t=5", "s=a;i=9;t=6"));
+        assert!(is_after("unknown", "s=a;i=9"));
+        Ok(())
+    }
+
+    #[test]
+    fn refuse_oversized_binary_field() -> Result<()> {
+        init();
+        for size in &[1025, u64::MAX] {
+            let mut input = b"MESSAGE\n".to_vec();
+            input.extend(&size.to_le_bytes());
+            input.extend(b"Mary had a little lamb\n\n");
+            assert!(parse_entry(&input, 1024).is_err());
+
+            let mut source =
+                JournalSource::new("journal", move || Ok(Cursor::new(input)), 1024, None);
+            let mut result = Ok(None);
+            for _ in 0..100 {
+                result = source.read().map(|read| read.map(|events| events.to_vec()));
+                if result.is_err() {
+                    break;
+                }
+                sleep(Duration::from_millis(10));
+            }
+            assert!(result.is_err());
+            assert!(source.is_closed());
+            assert_eq!(None, source.read()?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn close_upon_failure() {
+        init();
+        let mut source =
+            JournalSource::new("journal", || File::open("/does/not/exist"), 1024, None);
+        assert_eq!(0, read_all(&mut source).unwrap().len());
+    }
+}
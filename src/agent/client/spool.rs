@@ -0,0 +1,254 @@
+use crate::error::{woodpecker_error, Result};
+use log::{debug, warn};
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const EXTENSION: &str = "spool";
+
+/// Warn once the spool is this full.
+const WARN_RATIO: f64 = 0.8;
+
+/// Counters of a Spool since the agent started.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SpoolMetrics {
+    /// Buffers in the spool now.
+    pub buffers: usize,
+    /// Bytes in the spool now.
+    pub bytes: u64,
+    pub spooled: u64,
+    pub drained: u64,
+    /// Buffers not spooled because the spool is full.
+    pub rejected: u64,
+}
+
+/// A Spool keeps buffers that failed to upload in a directory, one file per buffer,
+/// so that they survive restarts and outlive the rotation of their source files.
+/// Buffers come out in the order they went in.
+pub struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    /// Sequence numbers and sizes of spooled buffers, oldest first.
+    entries: VecDeque<(u64, u64)>,
+    next_seq: u64,
+    metrics: SpoolMetrics,
+    /// Whether the spool warned of being nearly full, since it last was not.
+    warned: bool,
+}
+
+impl Spool {
+    pub fn try_new(dir: &str, max_bytes: u64) -> Result<Spool> {
+        fs::create_dir_all(dir)?;
+        let mut entries = vec![];
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            match parse_seq(&path) {
+                Some(seq) => entries.push((seq, fs::metadata(&path)?.len())),
+                // Leftover of an interrupted write.
+                None if path.extension().is_some_and(|ext| ext == "tmp") => fs::remove_file(&path)?,
+                None => warn!("Ignore unknown file in spool: {:?}", path),
+            }
+        }
+        entries.sort_unstable();
+        let next_seq = entries.last().map_or(0, |(seq, _)| seq + 1);
+        let metrics = SpoolMetrics {
+            buffers: entries.len(),
+            bytes: entries.iter().map(|(_, size)| size).sum(),
+            ..Default::default()
+        };
+        if !entries.is_empty() {
+            warn!("Found {} spooled buffers in {}", entries.len(), dir);
+        }
+        Ok(Spool {
+            dir: PathBuf::from(dir),
+            max_bytes,
+            entries: entries.into(),
+            next_seq,
+            metrics,
+            warned: false,
+        })
+    }
+
+    /// Add a buffer, or fail if the spool would exceed its size cap.
+    pub fn push(&mut self, buffer: &[u8]) -> Result<()> {
+        let size = buffer.len() as u64;
+        if self.metrics.bytes + size > self.max_bytes {
+            self.metrics.rejected += 1;
+            return Err(woodpecker_error(
+                format!(
+                    "Spool {:?} is full with {} bytes",
+                    self.dir, self.metrics.bytes
+                )
+                .as_str(),
+            ));
+        }
+        let seq = self.next_seq;
+        let path = self.path(seq);
+        // Sync the buffer before the rename, and the directory after it,
+        // so a spooled buffer survives a power loss.
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(buffer)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        File::open(&self.dir)?.sync_all()?;
+        debug!("Spooled {} bytes to {:?}", size, path);
+
+        self.next_seq += 1;
+        self.entries.push_back((seq, size));
+        self.metrics.buffers += 1;
+        self.metrics.bytes += size;
+        self.metrics.spooled += 1;
+        if self.is_nearly_full() && !self.warned {
+            warn!(
+                "Spool {:?} is at {} of {} bytes",
+                self.dir, self.metrics.bytes, self.max_bytes
+            );
+            self.warned = true;
+        }
+        Ok(())
+    }
+
+    /// Return the oldest buffer with its sequence number, without removing it.
+    pub fn peek(&self) -> Result<Option<(u64, Vec<u8>)>> {
+        match self.entries.front() {
+            Some(&(seq, _)) => Ok(Some((seq, fs::read(self.path(seq))?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Remove the oldest buffer once it is uploaded.
+    pub fn pop(&mut self, seq: u64) -> Result<()> {
+        match self.entries.front() {
+            Some(&(front, size)) if front == seq => {
+                fs::remove_file(self.path(seq))?;
+                self.entries.pop_front();
+                self.metrics.buffers -= 1;
+                self.metrics.bytes -= size;
+                self.metrics.drained += 1;
+                if !self.is_nearly_full() {
+                    self.warned = false;
+                }
+                Ok(())
+            }
+            _ => Err(woodpecker_error(
+                format!("Buffer {} is not the oldest in spool", seq).as_str(),
+            )),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn metrics(&self) -> SpoolMetrics {
+        self.metrics.clone()
+    }
+
+    fn is_nearly_full(&self) -> bool {
+        self.metrics.bytes as f64 >= self.max_bytes as f64 * WARN_RATIO
+    }
+
+    fn path(&self, seq: u64) -> PathBuf {
+        self.dir.join(format!("{:020}.{}", seq, EXTENSION))
+    }
+}
+
+fn parse_seq(path: &Path) -> Option<u64> {
+    if path.extension()? != EXTENSION {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn init() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[test]
+    fn push_and_pop_in_order() -> Result<()> {
+        init();
+        let dir = tempdir()?;
+        let mut spool = Spool::try_new(dir.path().to_str().unwrap(), 100)?;
+        assert_eq!(None, spool.peek()?);
+
+        spool.push(b"first")?;
+        spool.push(b"second")?;
+        let (seq, buffer) = spool.peek()?.unwrap();
+        assert_eq!(b"first", buffer.as_slice());
+        assert!(spool.pop(seq + 1).is_err());
+        spool.pop(seq)?;
+        let (seq, buffer) = spool.peek()?.unwrap();
+        assert_eq!(b"second", buffer.as_slice());
+        spool.pop(seq)?;
+        assert!(spool.is_empty());
+
+        let metrics = spool.metrics();
+        assert_eq!(2, metrics.spooled);
+        assert_eq!(2, metrics.drained);
+        assert_eq!(0, metrics.bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn reload_after_restart() -> Result<()> {
+        init();
+        let dir = tempdir()?;
+        let dir_str = dir.path().to_str().unwrap();
+        let mut spool = Spool::try_new(dir_str, 100)?;
+        spool.push(b"first")?;
+        spool.push(b"second")?;
+        drop(spool);
+
+        let mut spool = Spool::try_new(dir_str, 100)?;
+        assert_eq!(2, spool.metrics().buffers);
+        assert_eq!(11, spool.metrics().bytes);
+        spool.push(b"third")?;
+        let mut buffers = vec![];
+        while let Some((seq, buffer)) = spool.peek()? {
+            buffers.push(buffer);
+            spool.pop(seq)?;
+        }
+        assert_eq!(vec![&b"first"[..], b"second", b"third"], buffers);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_when_full() -> Result<()> {
+        init();
+        let dir = tempdir()?;
+        let mut spool = Spool::try_new(dir.path().to_str().unwrap(), 10)?;
+        spool.push(b"12345678")?;
+        assert!(spool.push(b"123").is_err());
+        assert_eq!(1, spool.metrics().rejected);
+        assert_eq!(1, spool.metrics().buffers);
+        Ok(())
+    }
+
+    #[test]
+    fn warn_once_when_nearly_full() -> Result<()> {
+        init();
+        let dir = tempdir()?;
+        let mut spool = Spool::try_new(dir.path().to_str().unwrap(), 10)?;
+        spool.push(b"1234567")?;
+        assert!(!spool.warned);
+        spool.push(b"8")?;
+        assert!(spool.warned);
+        spool.push(b"9")?;
+        assert!(spool.warned);
+
+        // Warn again once it fills up after draining below the threshold.
+        let (seq, _) = spool.peek()?.unwrap();
+        spool.pop(seq)?;
+        assert!(!spool.warned);
+        spool.push(b"1234567")?;
+        assert!(spool.warned);
+        Ok(())
+    }
+}
@@ -0,0 +1,503 @@
+use crate::agent::client::checkpoint::Checkpoint;
+use crate::agent::client::source::Source;
+use crate::error::{woodpecker_error, Result};
+use crate::ingress::schema::SYSLOG_SCHEMA_ID;
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use std::io::{self, Read};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Largest message to accept, as most senders truncate at 8 or 64 KiB.
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+/// Bytes to read from a connection at a time.
+const READ_SIZE: usize = 64 * 1024;
+/// Messages received ahead of the agent, before the receiver blocks.
+/// A blocked TCP connection pushes back on its sender, while UDP datagrams are dropped.
+const MESSAGES_AHEAD: usize = 1024;
+/// Most TCP connections served at once, each by a thread. More are closed upon accept.
+const MAX_CONNECTIONS: usize = 256;
+/// How long a TCP connection may go without data before it is closed, to free its thread.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+/// Start of the name of syslog sources, followed by the protocol and address.
+const NAME_PREFIX: &str = "syslog:";
+
+/// Transport to receive syslog messages on.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    /// A message per datagram, as in RFC 5426.
+    Udp,
+    /// Messages framed by octet counting or new lines, as in RFC 6587.
+    Tcp,
+}
+
+impl Protocol {
+    fn name(&self) -> &str {
+        match self {
+            Protocol::Udp => "udp",
+            Protocol::Tcp => "tcp",
+        }
+    }
+}
+
+/// Name of the source listening on address, e.g. syslog:udp:0.0.0.0:514.
+pub fn source_name(protocol: Protocol, address: &str) -> String {
+    format!("{}{}:{}", NAME_PREFIX, protocol.name(), address)
+}
+
+/// Syslog messages, in RFC 5424 or 3164 format, received from the network.
+/// Each message becomes an event of one line. New lines within a message are escaped as #012
+/// and carriage returns as #015, as rsyslog does.
+/// Messages cannot be received again, so whatever is not uploaded before a restart is lost.
+pub struct SyslogSource {
+    name: String,
+    local_addr: SocketAddr,
+    /// Messages, or the error that stopped receiving.
+    receiver: Receiver<io::Result<Vec<u8>>>,
+    events: Vec<u8>,
+    buffer_size: usize,
+    /// Why receiving stopped, to fail the next read once the messages before are returned.
+    failure: Option<io::Error>,
+    closed: bool,
+}
+
+impl SyslogSource {
+    /// Listen on address, e.g. 0.0.0.0:514, and receive in the background.
+    pub fn bind(protocol: Protocol, address: &str, buffer_size: usize) -> Result<SyslogSource> {
+        let name = source_name(protocol, address);
+        let (sender, receiver) = sync_channel(MESSAGES_AHEAD);
+        let local_addr = match protocol {
+            Protocol::Udp => {
+                let socket = UdpSocket::bind(address)?;
+                let local_addr = socket.local_addr()?;
+                let name = name.clone();
+                thread::spawn(move || receive_datagrams(&name, socket, sender));
+                local_addr
+            }
+            Protocol::Tcp => {
+                let listener = TcpListener::bind(address)?;
+                let local_addr = listener.local_addr()?;
+                let name = name.clone();
+                thread::spawn(move || accept_connections(&name, listener, sender, MAX_CONNECTIONS));
+                local_addr
+            }
+        };
+        info!("Listen for syslog on {} {}", protocol.name(), local_addr);
+        Ok(SyslogSource {
+            name,
+            local_addr,
+            receiver,
+            events: vec![],
+            buffer_size,
+            failure: None,
+            closed: false,
+        })
+    }
+
+    /// Address listened on, e.g. to find the port bound for port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Source for SyslogSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Return the messages received, up to about the buffer size.
+    fn read(&mut self) -> Result<Option<&[u8]>> {
+        self.events.clear();
+        while self.failure.is_none() && self.events.len() < self.buffer_size {
+            match self.receiver.try_recv() {
+                Ok(Err(e)) => self.failure = Some(e),
+                Ok(Ok(message)) => {
+                    let event = escape(&message);
+                    if !event.is_empty() {
+                        self.events.extend(event);
+                        self.events.push(b'\n');
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    warn!("Stopped receiving {}", self.name);
+                    self.closed = true;
+                    break;
+                }
+            }
+        }
+        if self.events.is_empty() {
+            if let Some(e) = self.failure.take() {
+                self.closed = true;
+                return Err(woodpecker_error(
+                    format!("Failed to receive {}: {}", self.name, e).as_str(),
+                ));
+            }
+            return Ok(None);
+        }
+        Ok(Some(&self.events))
+    }
+
+    fn schema_id(&self) -> &str {
+        SYSLOG_SCHEMA_ID
+    }
+
+    fn flush(&mut self) -> Option<&[u8]> {
+        None
+    }
+
+    fn checkpoint(&self) -> Option<Checkpoint> {
+        None
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed
+    }
+}
+
+/// Trim the trailer some senders add, and escape what would break the message into lines.
+fn escape(message: &[u8]) -> Vec<u8> {
+    let end = message
+        .iter()
+        .rposition(|&b| !matches!(b, b'\n' | b'\r' | b'\0'))
+        .map_or(0, |i| i + 1);
+    let mut escaped = Vec::with_capacity(end);
+    for &b in &message[..end] {
+        match b {
+            b'\n' => escaped.extend(b"#012"),
+            b'\r' => escaped.extend(b"#015"),
+            _ => escaped.push(b),
+        }
+    }
+    escaped
+}
+
+/// Receive datagrams until the agent drops the source. Pass on the error that stops it,
+/// as the source would go silent otherwise.
+fn receive_datagrams(name: &str, socket: UdpSocket, sender: SyncSender<io::Result<Vec<u8>>>) {
+    let mut datagram = vec![0; MAX_MESSAGE_SIZE];
+    loop {
+        match socket.recv_from(&mut datagram) {
+            Ok((bytes, peer)) => {
+                debug!("Received {} bytes from {}", bytes, peer);
+                if bytes > 0 && sender.send(Ok(datagram[..bytes].to_vec())).is_err() {
+                    return;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => {
+                error!("Stop receiving {}: {}", name, e);
+                let _ = sender.send(Err(e));
+                return;
+            }
+        }
+    }
+}
+
+/// Serve each connection on a thread of its own, up to max_connections at once.
+fn accept_connections(
+    name: &str,
+    listener: TcpListener,
+    sender: SyncSender<io::Result<Vec<u8>>>,
+    max_connections: usize,
+) {
+    let connections = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        match stream {
+            Ok(_) if connections.load(Ordering::SeqCst) >= max_connections => {
+                warn!(
+                    "Close a connection to {}, as {} are open already",
+                    name, max_connections
+                );
+            }
+            Ok(stream) => {
+                connections.fetch_add(1, Ordering::SeqCst);
+                let sender = sender.clone();
+                let connections = connections.clone();
+                thread::spawn(move || {
+                    serve_connection(stream, sender);
+                    connections.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+            Err(e) => warn!("Failed to accept a connection to {}: {}", name, e),
+        }
+    }
+}
+
+/// Receive the messages of a connection until it closes, goes idle, or sends an invalid frame.
+fn serve_connection(mut stream: TcpStream, sender: SyncSender<io::Result<Vec<u8>>>) {
+    let peer = stream
+        .peer_addr()
+        .map_or("unknown peer".to_string(), |peer| peer.to_string());
+    debug!("Accepted connection from {}", peer);
+    if let Err(e) = stream.set_read_timeout(Some(IDLE_TIMEOUT)) {
+        warn!("Close connection from {}: {}", peer, e);
+        return;
+    }
+    let mut framer = Framer::default();
+    let mut chunk = vec![0; READ_SIZE];
+    loop {
+        let bytes = match stream.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                debug!("Close idle connection from {}", peer);
+                break;
+            }
+            Err(e) => {
+                warn!("Failed to read from {}: {}", peer, e);
+                return;
+            }
+        };
+        framer.push(&chunk[..bytes]);
+        loop {
+            match framer.next() {
+                Ok(Some(message)) => {
+                    if !message.is_empty() && sender.send(Ok(message)).is_err() {
+                        return;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Close connection from {}: {}", peer, e);
+                    return;
+                }
+            }
+        }
+    }
+    if let Some(message) = framer.finish() {
+        let _ = sender.send(Ok(message));
+    }
+    debug!("Closed connection from {}", peer);
+}
+
+/// Split a stream into messages, as in RFC 6587. A frame that starts with a digit is
+/// octet counted, e.g. "11 <34>1 - - -", since a message starts with <.
+/// Other frames end with a new line.
+#[derive(Default)]
+struct Framer {
+    buffer: Vec<u8>,
+}
+
+impl Framer {
+    fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Return the next complete message, if any. Fail on a frame that is invalid or too large.
+    fn next(&mut self) -> Result<Option<Vec<u8>>> {
+        if self.buffer.first().is_some_and(|b| b.is_ascii_digit()) {
+            let digits = match self.buffer.iter().position(|b| !b.is_ascii_digit()) {
+                Some(digits) => digits,
+                None if self.buffer.len() > 5 => {
+                    return Err(woodpecker_error("Octet count is too large"))
+                }
+                None => return Ok(None),
+            };
+            if self.buffer[digits] != b' ' {
+                return Err(woodpecker_error("Octet count is not followed by a space"));
+            }
+            let len: usize = std::str::from_utf8(&self.buffer[..digits])
+                .ok()
+                .and_then(|len| len.parse().ok())
+                .ok_or_else(|| woodpecker_error("Invalid octet count"))?;
+            if len > MAX_MESSAGE_SIZE {
+                return Err(woodpecker_error(
+                    format!("Message of {} bytes is too large", len).as_str(),
+                ));
+            }
+            let end = digits + 1 + len;
+            if self.buffer.len() < end {
+                return Ok(None);
+            }
+            let message = self.buffer[digits + 1..end].to_vec();
+            self.buffer.drain(..end);
+            return Ok(Some(message));
+        }
+        match self.buffer.iter().position(|&b| b == b'\n') {
+            Some(end) => {
+                let message = self.buffer[..end].to_vec();
+                self.buffer.drain(..=end);
+                Ok(Some(message))
+            }
+            None if self.buffer.len() > MAX_MESSAGE_SIZE => Err(woodpecker_error(
+                "Message exceeds the max size without a new line",
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// Return the last message of a closed stream, which may lack the new line.
+    /// An incomplete octet counted frame is dropped.
+    fn finish(&mut self) -> Option<Vec<u8>> {
+        let rest = std::mem::take(&mut self.buffer);
+        if rest.is_empty() || rest[0].is_ascii_digit() {
+            None
+        } else {
+            Some(rest)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::thread::sleep;
+
+    fn init() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    /// Read until count events are received.
+    fn read_events(source: &mut SyslogSource, count: usize) -> Result<String> {
+        let mut events = vec![];
+        for _ in 0..100 {
+            if let Some(buffer) = source.read()? {
+                events.extend_from_slice(buffer);
+            }
+            if events.iter().filter(|&&b| b == b'\n').count() >= count {
+                break;
+            }
+            sleep(Duration::from_millis(10));
+        }
+        Ok(String::from_utf8(events).unwrap())
+    }
+
+    #[test]
+    fn frame_by_octet_count_and_new_line() -> Result<()> {
+        let mut framer = Framer::default();
+        framer.push(b"26 <34>1 - host app - - - a\nb<13>Oct 11 22:14:15 host app: c\n\n");
+        framer.push(b"16 <34>1 - ho");
+        assert_eq!(
+            Some(b"<34>1 - host app - - - a\nb".to_vec()),
+            framer.next()?
+        );
+        assert_eq!(
+            Some(b"<13>Oct 11 22:14:15 host app: c".to_vec()),
+            framer.next()?
+        );
+        assert_eq!(Some(vec![]), framer.next()?);
+        // Wait for the rest of the frame.
+        assert_eq!(None, framer.next()?);
+        framer.push(b"st app");
+        assert_eq!(Some(b"<34>1 - host app".to_vec()), framer.next()?);
+        assert_eq!(None, framer.next()?);
+
+        framer.push(b"<13>Oct 11 22:14:15 host app: last");
+        assert_eq!(None, framer.next()?);
+        assert_eq!(
+            Some(b"<13>Oct 11 22:14:15 host app: last".to_vec()),
+            framer.finish()
+        );
+
+        let mut framer = Framer::default();
+        framer.push(b"99999999 <34>1");
+        assert!(framer.next().is_err());
+        let mut framer = Framer::default();
+        framer.push(b"12<34>1");
+        assert!(framer.next().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn receive_udp() -> Result<()> {
+        init();
+        let mut source = SyslogSource::bind(Protocol::Udp, "127.0.0.1:0", 1024)?;
+        assert!(source.name().starts_with("syslog:udp:"));
+        let socket = UdpSocket::bind("127.0.0.1:0")?;
+        socket.send_to(b"<34>1 - host app - - - a\nb\n", source.local_addr())?;
+        socket.send_to(b"<13>Oct 11 22:14:15 host app: c", source.local_addr())?;
+
+        assert_eq!(
+            "<34>1 - host app - - - a#012b\n<13>Oct 11 22:14:15 host app: c\n",
+            read_events(&mut source, 2)?
+        );
+        assert!(!source.is_closed());
+        Ok(())
+    }
+
+    #[test]
+    fn receive_tcp() -> Result<()> {
+        init();
+        let mut source = SyslogSource::bind(Protocol::Tcp, "127.0.0.1:0", 1024)?;
+        let mut stream = TcpStream::connect(source.local_addr())?;
+        stream.write_all(b"27 <34>1 - host app - - - a\r\nb<13>Oct 11 22:14:15 host app: c\n")?;
+        let mut other = TcpStream::connect(source.local_addr())?;
+        other.write_all(b"<13>Oct 11 22:14:16 other app: d")?;
+        drop(other);
+
+        let events = read_events(&mut source, 3)?;
+        let mut events: Vec<&str> = events.lines().collect();
+        events.sort();
+        assert_eq!(
+            vec![
+                "<13>Oct 11 22:14:15 host app: c",
+                "<13>Oct 11 22:14:16 other app: d",
+                "<34>1 - host app - - - a#015#012b",
+            ],
+            events
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn fail_after_messages_received_before_error() -> Result<()> {
+        init();
+        let (sender, receiver) = sync_channel(MESSAGES_AHEAD);
+        let mut source = SyslogSource {
+            name: "syslog:udp:127.0.0.1:514".to_string(),
+            local_addr: "127.0.0.1:514".parse().unwrap(),
+            receiver,
+            events: vec![],
+            buffer_size: 1024,
+            failure: None,
+            closed: false,
+        };
+        sender
+            .send(Ok(b"<13>Oct 11 22:14:15 host app: a".to_vec()))
+            .unwrap();
+        sender.send(Err(io::Error::other("socket closed"))).unwrap();
+        drop(sender);
+
+        assert_eq!(
+            Some(&b"<13>Oct 11 22:14:15 host app: a\n"[..]),
+            source.read()?
+        );
+        assert!(!source.is_closed());
+        assert!(source.read().is_err());
+        assert!(source.is_closed());
+        Ok(())
+    }
+
+    #[test]
+    fn close_connections_over_limit() -> Result<()> {
+        init();
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let (sender, receiver) = sync_channel(MESSAGES_AHEAD);
+        thread::spawn(move || accept_connections("syslog", listener, sender, 1));
+
+        let mut first = TcpStream::connect(addr)?;
+        first.write_all(b"<13>Oct 11 22:14:15 host app: a\n")?;
+        let message = receiver.recv_timeout(Duration::from_secs(5)).unwrap()?;
+        assert_eq!(b"<13>Oct 11 22:14:15 host app: a".to_vec(), message);
+
+        // The first connection is still open, so the second is closed.
+        let mut second = TcpStream::connect(addr)?;
+        second.set_read_timeout(Some(Duration::from_secs(5)))?;
+        assert_eq!(0, second.read(&mut [0; 1])?);
+        Ok(())
+    }
+}
@@ -1,42 +1,249 @@
+use crate::agent::client::checkpoint::Checkpoint;
 use crate::error::Result;
+use crate::event::EventSplitter;
+use log::{debug, warn};
 use same_file::Handle;
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Seek, SeekFrom};
 use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// How long the old file must go without new data before switching to the new one.
+const DEFAULT_QUIET_PERIOD: Duration = Duration::from_secs(1);
+/// How long the file must go without new data before returning the carried over event.
+const DEFAULT_EVENT_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// Continuously tails a log file from previous offset.
 /// Return a buffer of log events aligned by regex.
-/// Detect and chase to new file upon end-of-file.
+/// Detect and chase to new file upon end-of-file, once the old file is drained.
+/// Start over when the file is truncated, e.g. by logrotate's copytruncate.
 // See the kinesis agent for checkpoints etc. https://github.com/awslabs/amazon-kinesis-agent
 pub struct Tailer {
     path: String,
     file: File,
     buffer: Vec<u8>,
+    splitter: EventSplitter,
+    /// Bytes read from file into the front of buffer.
+    filled: usize,
+    /// Bytes at the front of buffer returned by the previous read.
+    /// The rest of the filled bytes is a partial event carried over to the next read.
+    returned: usize,
+    /// Offset of the end of the last returned event.
+    offset: u64,
+    /// When the file last had new data.
+    last_data: Instant,
+    quiet_period: Duration,
+    event_timeout: Duration,
 }
 
 impl Tailer {
-    pub fn try_new(path: &str, buffer_size: usize) -> Result<Tailer> {
-        let file = File::open(Path::new(path))?;
+    /// Open the file at path. Resume from the checkpoint if it still refers to the same file,
+    /// otherwise start from the beginning.
+    pub fn try_new(
+        path: &str,
+        buffer_size: usize,
+        splitter: EventSplitter,
+        checkpoint: Option<&Checkpoint>,
+    ) -> Result<Tailer> {
+        let mut file = File::open(Path::new(path))?;
+        let mut offset = 0;
+        if let Some(checkpoint) = checkpoint {
+            let (device, inode) = identity(&file)?;
+            let len = file.metadata()?.len();
+            if checkpoint.device != device || checkpoint.inode != inode {
+                warn!(
+                    "Checkpoint of {} refers to another file, start from beginning",
+                    path
+                );
+            } else if checkpoint.offset > len {
+                warn!(
+                    "Checkpoint of {} is beyond end of file, start from beginning",
+                    path
+                );
+            } else {
+                debug!("Resume {} from offset {}", path, checkpoint.offset);
+                file.seek(SeekFrom::Start(checkpoint.offset))?;
+                offset = checkpoint.offset;
+            }
+        }
         Ok(Tailer {
             path: path.to_string(),
             file,
             buffer: vec![0; buffer_size],
+            splitter,
+            filled: 0,
+            returned: 0,
+            offset,
+            last_data: Instant::now(),
+            quiet_period: DEFAULT_QUIET_PERIOD,
+            event_timeout: DEFAULT_EVENT_TIMEOUT,
         })
     }
 
+    /// Wait this long without new data before leaving a rotated or deleted file.
+    pub fn with_quiet_period(mut self, quiet_period: Duration) -> Self {
+        self.quiet_period = quiet_period;
+        self
+    }
+
+    /// Return the complete lines of the event carried over once the file goes this long
+    /// without new data, as the next event may never start to complete it.
+    pub fn with_event_timeout(mut self, event_timeout: Duration) -> Self {
+        self.event_timeout = event_timeout;
+        self
+    }
+
+    /// Split events by another boundary from now on, e.g. upon reload of the config.
+    pub fn set_splitter(&mut self, splitter: EventSplitter) {
+        self.splitter = splitter;
+    }
+
+    /// Return complete events only, or None upon end-of-file.
+    /// An event larger than the buffer is split at buffer size.
+    /// The last event is complete once the next one starts, or after the event timeout.
     pub fn read(&mut self) -> Result<Option<&[u8]>> {
-        let bytes = self.file.read(&mut *self.buffer)?;
-        if bytes == 0 {
-            Ok(None)
+        self.read_split_by(&|buffer| buffer.len())
+    }
+
+    /// Like read, but split an event larger than the buffer at the point of the full buffer
+    /// given by split_point, e.g. where no secret spans the parts.
+    /// The bytes after it are returned with the next part.
+    pub fn read_split_by(&mut self, split_point: &dyn Fn(&[u8]) -> usize) -> Result<Option<&[u8]>> {
+        self.discard_returned();
+        loop {
+            let bytes = self.file.read(&mut self.buffer[self.filled..])?;
+            if bytes > 0 {
+                self.last_data = Instant::now();
+            }
+            self.filled += bytes;
+            if let Some(end) = self.splitter.end_of_last_event(&self.buffer[..self.filled]) {
+                return Ok(Some(self.take(end)));
+            }
+            if self.filled == self.buffer.len() {
+                warn!(
+                    "Event in {} exceeds buffer size of {} bytes, split it",
+                    self.path,
+                    self.buffer.len()
+                );
+                let end = split_point(&self.buffer[..self.filled]).clamp(1, self.filled);
+                return Ok(Some(self.take(end)));
+            }
+            if bytes == 0 {
+                if self.is_truncated()? {
+                    self.restart()?;
+                    continue;
+                }
+                if self.last_data.elapsed() >= self.event_timeout {
+                    if let Some(end) = self.buffer[..self.filled].iter().rposition(|&b| b == b'\n')
+                    {
+                        debug!("Return the last event of {} after timeout", self.path);
+                        return Ok(Some(self.take(end + 1)));
+                    }
+                }
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Whether the file shrank below what was read, e.g. by copytruncate.
+    /// A file that grows past the read position again before the check goes unnoticed.
+    fn is_truncated(&mut self) -> Result<bool> {
+        let position = self.file.stream_position()?;
+        Ok(self.file.metadata()?.len() < position)
+    }
+
+    /// Read the truncated file from the beginning.
+    /// The partial event carried over went with the rest of the content before truncation.
+    fn restart(&mut self) -> Result<()> {
+        warn!(
+            "{} is truncated, start from beginning and drop {} bytes of a partial event",
+            self.path, self.filled
+        );
+        self.file.seek(SeekFrom::Start(0))?;
+        self.filled = 0;
+        self.returned = 0;
+        self.offset = 0;
+        Ok(())
+    }
+
+    /// Return the partial event carried over, if any.
+    /// Use it when no more data is expected, e.g. before rotation.
+    pub fn flush(&mut self) -> Option<&[u8]> {
+        self.discard_returned();
+        if self.filled == 0 {
+            None
         } else {
-            Ok(Some(&self.buffer[0..bytes]))
+            Some(self.take(self.filled))
+        }
+    }
+
+    /// Whether the open file is quiescent: nothing left to read and no new data for the
+    /// quiet period. A writer may append to a rotated file until it reopens the path,
+    /// so only leave the old file once it is drained.
+    pub fn is_drained(&mut self) -> Result<bool> {
+        if self.last_data.elapsed() < self.quiet_period {
+            return Ok(false);
         }
+        let position = self.file.stream_position()?;
+        Ok(self.file.metadata()?.len() <= position)
     }
 
-    pub fn rotate(&mut self) -> Result<()> {
-        // TODO check is_rotated
+    /// Switch to the new file behind path. Any partial event not flushed is dropped.
+    /// Return false and keep the open file if the path still refers to it.
+    pub fn rotate(&mut self) -> Result<bool> {
+        if !self.is_rotated()? {
+            debug!("{} is not rotated, keep reading it", self.path);
+            return Ok(false);
+        }
         self.file = File::open(Path::new(self.path.as_str()))?;
-        Ok(())
+        self.filled = 0;
+        self.returned = 0;
+        self.offset = 0;
+        self.last_data = Instant::now();
+        Ok(true)
+    }
+
+    fn take(&mut self, end: usize) -> &[u8] {
+        self.returned = end;
+        self.offset += end as u64;
+        &self.buffer[..end]
+    }
+
+    fn discard_returned(&mut self) {
+        self.buffer.copy_within(self.returned..self.filled, 0);
+        self.filled -= self.returned;
+        self.returned = 0;
+    }
+
+    /// Position of everything returned by read so far.
+    pub fn checkpoint(&self) -> Result<Checkpoint> {
+        let (device, inode) = identity(&self.file)?;
+        Ok(Checkpoint {
+            path: self.path.clone(),
+            device,
+            inode,
+            offset: self.offset,
+            cursor: None,
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Offset of the end of everything returned by read so far.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// The file is no longer reachable through its path.
+    pub fn is_deleted(&self) -> bool {
+        !Path::new(self.path.as_str()).exists()
+    }
+
+    /// Device and inode of the open file, which may no longer be the one behind path.
+    pub fn identity(&self) -> Result<(u64, u64)> {
+        identity(&self.file)
     }
 
     pub fn is_rotated(&self) -> Result<bool> {
@@ -46,13 +253,28 @@ impl Tailer {
     }
 }
 
+fn identity(file: &File) -> Result<(u64, u64)> {
+    let handle = Handle::from_file(file.try_clone()?)?;
+    Ok((handle.dev(), handle.ino()))
+}
+
+/// Device and inode of the file behind path.
+pub fn path_identity(path: &str) -> Result<(u64, u64)> {
+    let handle = Handle::from_path(Path::new(path))?;
+    Ok((handle.dev(), handle.ino()))
+}
+
 #[cfg(test)]
 mod tests {
     use crate::agent::client::tailer::Tailer;
+    use crate::error::Result;
+    use crate::event::{EventBoundary, EventSplitter};
     use log::debug;
-    use std::fs::{rename, File};
+    use std::fs::{rename, File, OpenOptions};
     use std::io::Write;
     use std::str::from_utf8;
+    use std::thread::sleep;
+    use std::time::Duration;
     use tempfile::NamedTempFile;
 
     fn init() {
@@ -62,13 +284,13 @@ mod tests {
     #[test]
     fn read_file() {
         init();
-        let content = b"Mary had a little lamb\nLittle lamb, little lamb";
+        let content = b"Mary had a little lamb\nLittle lamb, little lamb\n";
         let mut temp_file = NamedTempFile::new().unwrap();
         temp_file.write(content).unwrap();
 
         let path_str = temp_file.path().to_str().unwrap();
         debug!("File created at {}", path_str);
-        let mut tailer = Tailer::try_new(path_str, 10).unwrap();
+        let mut tailer = Tailer::try_new(path_str, 10, EventSplitter::default(), None).unwrap();
         let mut bytes = 0;
         while let Some(v) = tailer.read().unwrap() {
             debug!("length: {} content: {}", v.len(), from_utf8(v).unwrap());
@@ -84,7 +306,7 @@ mod tests {
         let path_str = file1.path().to_str().unwrap();
         debug!("File created at {}", path_str);
 
-        let tailer = Tailer::try_new(path_str, 10).unwrap();
+        let tailer = Tailer::try_new(path_str, 10, EventSplitter::default(), None).unwrap();
         assert!(!tailer.is_rotated().unwrap());
 
         // Simulate a rotation with rename and create.
@@ -96,7 +318,7 @@ mod tests {
     #[test]
     fn rotate() {
         init();
-        let content = b"Mary had a little lamb\nLittle lamb, little lamb";
+        let content = b"Mary had a little lamb\nLittle lamb, little lamb\n";
         let mut file = NamedTempFile::new().unwrap();
         file.write(content).unwrap();
 
@@ -104,7 +326,7 @@ mod tests {
         debug!("File created at {}", path_str);
 
         let mut bytes = 0;
-        let mut tailer = Tailer::try_new(path_str, 10).unwrap();
+        let mut tailer = Tailer::try_new(path_str, 10, EventSplitter::default(), None).unwrap();
         while let Some(v) = tailer.read().unwrap() {
             debug!("length: {} content: {}", v.len(), from_utf8(v).unwrap());
             bytes += v.len();
@@ -119,7 +341,7 @@ mod tests {
         let mut file = File::create(path_str).unwrap();
         debug!("File created at {}", path_str);
 
-        let content2 = b"Mary had a little lamb\nIt's fleece was white as snow";
+        let content2 = b"Mary had a little lamb\nIt's fleece was white as snow\n";
         file.write(content2).unwrap();
         assert!(tailer.is_rotated().unwrap());
 
@@ -130,4 +352,225 @@ mod tests {
         }
         assert_eq!(bytes, content.len() + content2.len());
     }
+
+    #[test]
+    fn rotate_only_when_rotated() -> Result<()> {
+        init();
+        let mut file = NamedTempFile::new()?;
+        file.write_all(b"Mary had a little lamb\n")?;
+        let path_str = file.path().to_str().unwrap();
+
+        let mut tailer = Tailer::try_new(path_str, 64, EventSplitter::default(), None)?;
+        assert!(!tailer.rotate()?);
+        assert_eq!(b"Mary had a little lamb\n", tailer.read()?.unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn drain_before_rotate() -> Result<()> {
+        init();
+        let mut file = NamedTempFile::new()?;
+        file.write_all(b"Mary had a little lamb\n")?;
+        let path_str = file.path().to_str().unwrap();
+
+        let quiet_period = Duration::from_millis(50);
+        let mut tailer = Tailer::try_new(path_str, 64, EventSplitter::default(), None)?
+            .with_quiet_period(quiet_period);
+        assert_eq!(b"Mary had a little lamb\n", tailer.read()?.unwrap());
+        assert_eq!(None, tailer.read()?);
+
+        // Rotate by rename and create, then the writer appends to the old file.
+        let renamed = NamedTempFile::new()?;
+        rename(file.path(), renamed.path())?;
+        File::create(path_str)?.write_all(b"Its fleece was white as snow\n")?;
+        assert!(tailer.is_rotated()?);
+        // Just read data.
+        assert!(!tailer.is_drained()?);
+        sleep(quiet_period);
+        file.write_all(b"Little lamb, little lamb\n")?;
+        // Not read yet.
+        assert!(!tailer.is_drained()?);
+        assert_eq!(b"Little lamb, little lamb\n", tailer.read()?.unwrap());
+        assert_eq!(None, tailer.read()?);
+        assert!(!tailer.is_drained()?);
+
+        sleep(quiet_period);
+        assert!(tailer.is_drained()?);
+        assert!(tailer.rotate()?);
+        assert_eq!(b"Its fleece was white as snow\n", tailer.read()?.unwrap());
+        assert_eq!(29, tailer.checkpoint()?.offset);
+        Ok(())
+    }
+
+    #[test]
+    fn resume_from_checkpoint() -> Result<()> {
+        init();
+        let content = b"Mary had a little lamb\nLittle lamb, little lamb\n";
+        let mut file = NamedTempFile::new()?;
+        file.write_all(content)?;
+        let path_str = file.path().to_str().unwrap();
+
+        let mut tailer = Tailer::try_new(path_str, 10, EventSplitter::default(), None)?;
+        let first = tailer.read()?.unwrap().to_vec();
+        let checkpoint = tailer.checkpoint()?;
+        assert_eq!(first.len() as u64, checkpoint.offset);
+
+        let mut resumed =
+            Tailer::try_new(path_str, 100, EventSplitter::default(), Some(&checkpoint))?;
+        let rest = resumed.read()?.unwrap().to_vec();
+        assert_eq!(content.to_vec(), [first, rest].concat());
+        Ok(())
+    }
+
+    #[test]
+    fn ignore_checkpoint_of_another_file() -> Result<()> {
+        init();
+        let content = b"Mary had a little lamb\nLittle lamb, little lamb\n";
+        let mut file = NamedTempFile::new()?;
+        file.write_all(content)?;
+        let path_str = file.path().to_str().unwrap();
+
+        let mut tailer = Tailer::try_new(path_str, 10, EventSplitter::default(), None)?;
+        tailer.read()?;
+        let checkpoint = tailer.checkpoint()?;
+
+        // Simulate a rotation while the agent is down.
+        rename(file.path(), NamedTempFile::new()?.path())?;
+        let mut rotated = File::create(path_str)?;
+        rotated.write_all(content)?;
+
+        let mut resumed =
+            Tailer::try_new(path_str, 100, EventSplitter::default(), Some(&checkpoint))?;
+        assert_eq!(content.len(), resumed.read()?.unwrap().len());
+        Ok(())
+    }
+
+    #[test]
+    fn read_aligned_to_lines() -> Result<()> {
+        init();
+        let content = b"Mary had a little lamb\nLittle lamb, little lamb\n";
+        let mut file = NamedTempFile::new()?;
+        file.write_all(content)?;
+        let path_str = file.path().to_str().unwrap();
+
+        let mut tailer = Tailer::try_new(path_str, 32, EventSplitter::default(), None)?;
+        assert_eq!(b"Mary had a little lamb\n", tailer.read()?.unwrap());
+        assert_eq!(b"Little lamb, little lamb\n", tailer.read()?.unwrap());
+        assert_eq!(None, tailer.read()?);
+        Ok(())
+    }
+
+    #[test]
+    fn carry_over_partial_line() -> Result<()> {
+        init();
+        let mut file = NamedTempFile::new()?;
+        file.write_all(b"Mary had a little lamb\nLittle lamb,")?;
+        let path_str = file.path().to_str().unwrap();
+
+        let mut tailer = Tailer::try_new(path_str, 64, EventSplitter::default(), None)?;
+        assert_eq!(b"Mary had a little lamb\n", tailer.read()?.unwrap());
+        assert_eq!(None, tailer.read()?);
+        assert_eq!(23, tailer.checkpoint()?.offset);
+
+        file.write_all(b" little lamb\n")?;
+        assert_eq!(b"Little lamb, little lamb\n", tailer.read()?.unwrap());
+        assert_eq!(None, tailer.read()?);
+        assert_eq!(None, tailer.flush());
+        Ok(())
+    }
+
+    #[test]
+    fn split_event_larger_than_buffer() -> Result<()> {
+        init();
+        let mut file = NamedTempFile::new()?;
+        file.write_all(b"Mary had a little lamb\n")?;
+        let path_str = file.path().to_str().unwrap();
+
+        let mut tailer = Tailer::try_new(path_str, 10, EventSplitter::default(), None)?;
+        assert_eq!(b"Mary had a", tailer.read()?.unwrap());
+        assert_eq!(b" little la", tailer.read()?.unwrap());
+        assert_eq!(b"mb\n", tailer.read()?.unwrap());
+        assert_eq!(None, tailer.read()?);
+        Ok(())
+    }
+
+    #[test]
+    fn flush_partial_line() -> Result<()> {
+        init();
+        let mut file = NamedTempFile::new()?;
+        file.write_all(b"Mary had a little lamb\nLittle lamb")?;
+        let path_str = file.path().to_str().unwrap();
+
+        let mut tailer = Tailer::try_new(path_str, 64, EventSplitter::default(), None)?;
+        assert_eq!(b"Mary had a little lamb\n", tailer.read()?.unwrap());
+        assert_eq!(None, tailer.read()?);
+        assert_eq!(Some(&b"Little lamb"[..]), tailer.flush());
+        assert_eq!(34, tailer.checkpoint()?.offset);
+        Ok(())
+    }
+
+    #[test]
+    fn read_multi_line_events() -> Result<()> {
+        init();
+        let mut file = NamedTempFile::new()?;
+        file.write_all(b"panicked at 'oops'\n  0: main\n  1: start\n")?;
+        let path_str = file.path().to_str().unwrap();
+
+        let splitter = EventSplitter::try_new(&EventBoundary::Continuation)?;
+        let mut tailer = Tailer::try_new(path_str, 64, splitter, None)?;
+        // The stack trace may continue.
+        assert_eq!(None, tailer.read()?);
+
+        file.write_all(b"panicked at 'again'\n")?;
+        assert_eq!(
+            b"panicked at 'oops'\n  0: main\n  1: start\n",
+            tailer.read()?.unwrap()
+        );
+        assert_eq!(None, tailer.read()?);
+        assert_eq!(Some(&b"panicked at 'again'\n"[..]), tailer.flush());
+        Ok(())
+    }
+
+    #[test]
+    fn return_last_event_after_timeout() -> Result<()> {
+        init();
+        let mut file = NamedTempFile::new()?;
+        file.write_all(b"panicked at 'oops'\n  0: main\n  1: st")?;
+        let path_str = file.path().to_str().unwrap();
+
+        let splitter = EventSplitter::try_new(&EventBoundary::Continuation)?;
+        let mut tailer = Tailer::try_new(path_str, 64, splitter, None)?
+            .with_event_timeout(Duration::from_millis(50));
+        assert_eq!(None, tailer.read()?);
+
+        // Only complete lines are returned, the partial one may still grow.
+        sleep(Duration::from_millis(100));
+        assert_eq!(b"panicked at 'oops'\n  0: main\n", tailer.read()?.unwrap());
+        assert_eq!(None, tailer.read()?);
+        assert_eq!(29, tailer.checkpoint()?.offset);
+        Ok(())
+    }
+
+    #[test]
+    fn copy_truncate() -> Result<()> {
+        init();
+        let content = b"Mary had a little lamb\nLittle lamb, little lamb\n";
+        let mut file = NamedTempFile::new()?;
+        file.write_all(content)?;
+        let path_str = file.path().to_str().unwrap();
+
+        let mut tailer = Tailer::try_new(path_str, 100, EventSplitter::default(), None)?;
+        assert_eq!(content, tailer.read()?.unwrap());
+        assert_eq!(None, tailer.read()?);
+
+        // Simulate copytruncate, then write less than before.
+        let mut truncated = OpenOptions::new().write(true).open(path_str)?;
+        truncated.set_len(0)?;
+        truncated.write_all(b"Its fleece was white\n")?;
+        assert!(!tailer.is_rotated()?);
+        assert_eq!(b"Its fleece was white\n", tailer.read()?.unwrap());
+        assert_eq!(21, tailer.checkpoint()?.offset);
+        assert_eq!(None, tailer.read()?);
+        Ok(())
+    }
 }
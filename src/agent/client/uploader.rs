@@ -1,32 +1,118 @@
-use crate::error::Result;
-use log::debug;
+use crate::config::from_millis;
+use crate::error::{Result, WoodpeckerError};
+use log::{debug, warn};
+use rand::Rng;
 use reqwest::Client;
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+/// How an Uploader retries transient failures, e.g. 5xx, timeouts and connection resets.
+/// In a config file, durations are in milliseconds, e.g. budget_ms.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// Attempts including the first one.
+    pub max_attempts: u32,
+    #[serde(rename = "initial_backoff_ms", deserialize_with = "from_millis")]
+    pub initial_backoff: Duration,
+    #[serde(rename = "max_backoff_ms", deserialize_with = "from_millis")]
+    pub max_backoff: Duration,
+    /// Give up once retrying would take longer than this in total.
+    #[serde(rename = "budget_ms", deserialize_with = "from_millis")]
+    pub budget: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            budget: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff with full jitter before the next attempt.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt - 1).unwrap_or(u32::MAX);
+        let cap = self
+            .initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff);
+        let millis = rand::thread_rng().gen_range(0..=cap.as_millis() as u64);
+        Duration::from_millis(millis)
+    }
+}
 
 /// Uploader for presigned url
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Clone)]
 pub struct Uploader {
     client: Client,
+    retry: RetryPolicy,
 }
 
 impl Uploader {
+    pub fn try_new(retry: RetryPolicy) -> Result<Uploader> {
+        let client = Client::builder().timeout(Duration::from_secs(60)).build()?;
+        Ok(Uploader { client, retry })
+    }
+
     pub async fn upload(&self, presigned_url: &str, bytes: &[u8]) -> Result<()> {
-        let client = reqwest::Client::new();
-        // TODO: retry strategy
-        let _res = client
+        let started = Instant::now();
+        let mut attempt = 1;
+        loop {
+            match self.put(presigned_url, bytes).await {
+                Err(e) if is_transient(&e) && attempt < self.retry.max_attempts => {
+                    let backoff = self.retry.backoff(attempt);
+                    if started.elapsed() + backoff > self.retry.budget {
+                        return Err(e);
+                    }
+                    warn!(
+                        "Upload to {} failed on attempt {}, retry in {:?}: {}",
+                        presigned_url, attempt, backoff, e
+                    );
+                    sleep(backoff).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn put(&self, presigned_url: &str, bytes: &[u8]) -> Result<()> {
+        let res = self
+            .client
             .put(presigned_url)
             .body(bytes.to_vec())
             .send()
-            .await
-            .expect("Put object with presigned url failed");
+            .await?;
+        if !res.status().is_success() {
+            return Err(WoodpeckerError::UploadError(res.status()));
+        }
         debug!("Object uploaded to {}", presigned_url);
         Ok(())
     }
 }
 
+fn is_transient(e: &WoodpeckerError) -> bool {
+    match e {
+        WoodpeckerError::UploadError(status) => status.is_server_error(),
+        WoodpeckerError::ReqwestError(e) => e.is_timeout() || e.is_connect() || e.is_request(),
+        _ => false,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use warp::Filter;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use warp::http::StatusCode;
+    use warp::{Filter, Reply};
 
     fn init() {
         let _ = env_logger::builder().is_test(true).try_init();
@@ -36,18 +122,116 @@ mod tests {
     async fn roundtrip() -> Result<()> {
         init();
 
-        // Set up a server at localhost:50051.
-        tokio::spawn(async move {
-            let routes = warp::any().map(|| warp::reply());
-            warp::serve(routes).run(([127, 0, 0, 1], 50051)).await;
-        });
+        let addr = serve(warp::any().map(warp::reply));
 
         // Instead of `assert!` in server, rely on absent of upload failure.
-        let uploader = Uploader::default();
+        let uploader = Uploader::try_new(RetryPolicy::default())?;
         uploader
-            .upload("http://127.0.0.1:50051/", b"content")
+            .upload(&format!("http://{}/", addr), b"content")
             .await?;
 
         Ok(())
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn forbidden() -> Result<()> {
+        init();
+
+        let addr = serve(
+            warp::any().map(|| warp::reply::with_status(warp::reply(), StatusCode::FORBIDDEN)),
+        );
+
+        let uploader = Uploader::try_new(RetryPolicy::default())?;
+        let result = uploader
+            .upload(&format!("http://{}/", addr), b"content")
+            .await;
+        assert!(matches!(
+            result,
+            Err(WoodpeckerError::UploadError(reqwest::StatusCode::FORBIDDEN))
+        ));
+        Ok(())
+    }
+
+    fn fast_retry(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(10),
+            budget: Duration::from_secs(10),
+        }
+    }
+
+    /// Serve routes on a free port of localhost, and return its address.
+    fn serve<F>(routes: F) -> SocketAddr
+    where
+        F: Filter + Clone + Send + Sync + 'static,
+        F::Extract: Reply,
+    {
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        addr
+    }
+
+    /// Serve statuses in order, then 200. Return the address and the count of requests.
+    fn serve_statuses(statuses: Vec<StatusCode>) -> (SocketAddr, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let addr = serve(warp::any().map(move || {
+            let i = counter.fetch_add(1, Ordering::SeqCst);
+            let status = statuses.get(i).cloned().unwrap_or(StatusCode::OK);
+            warp::reply::with_status(warp::reply(), status)
+        }));
+        (addr, requests)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn retry_server_error() -> Result<()> {
+        init();
+        let statuses = vec![StatusCode::INTERNAL_SERVER_ERROR, StatusCode::BAD_GATEWAY];
+        let (addr, requests) = serve_statuses(statuses);
+
+        let uploader = Uploader::try_new(fast_retry(5))?;
+        uploader
+            .upload(&format!("http://{}/", addr), b"content")
+            .await?;
+        assert_eq!(3, requests.load(Ordering::SeqCst));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn give_up_after_max_attempts() -> Result<()> {
+        init();
+        let statuses = vec![StatusCode::SERVICE_UNAVAILABLE; 5];
+        let (addr, requests) = serve_statuses(statuses);
+
+        let uploader = Uploader::try_new(fast_retry(3))?;
+        let result = uploader
+            .upload(&format!("http://{}/", addr), b"content")
+            .await;
+        assert!(matches!(
+            result,
+            Err(WoodpeckerError::UploadError(
+                StatusCode::SERVICE_UNAVAILABLE
+            ))
+        ));
+        assert_eq!(3, requests.load(Ordering::SeqCst));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connection_refused() -> Result<()> {
+        init();
+        let uploader = Uploader::try_new(fast_retry(2))?;
+        let result = uploader.upload("http://127.0.0.1:1/", b"content").await;
+        assert!(matches!(result, Err(WoodpeckerError::ReqwestError(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let retry = RetryPolicy::default();
+        for attempt in 1..40 {
+            assert!(retry.backoff(attempt) <= retry.max_backoff);
+        }
+    }
 }
@@ -0,0 +1,251 @@
+pub use imp::Watcher;
+
+/// What changed since the last wait.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Changes {
+    /// Paths of files changed or created.
+    Paths(Vec<String>),
+    /// Events were lost, so any file may have changed.
+    Overflow,
+}
+
+/// Wake tailers when their files change, instead of polling them.
+/// Watch every file for writes, renames and deletes, and its directory for files created,
+/// e.g. the new file after a rotation.
+#[cfg(target_os = "linux")]
+mod imp {
+    use super::Changes;
+    use crate::error::{woodpecker_error, Result};
+    use futures::{FutureExt, StreamExt};
+    use inotify::{EventMask, EventStream, Inotify, WatchDescriptor, WatchMask};
+    use log::debug;
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    const EVENT_BUFFER_SIZE: usize = 4096;
+
+    pub struct Watcher {
+        inotify: Inotify,
+        events: EventStream<Vec<u8>>,
+        /// Path of every watched file.
+        files: HashMap<WatchDescriptor, String>,
+        /// Path of every watched directory.
+        dirs: HashMap<WatchDescriptor, String>,
+    }
+
+    impl Watcher {
+        /// Fail if inotify is unavailable, e.g. out of instances, or outside a tokio runtime.
+        pub fn try_new() -> Result<Watcher> {
+            let mut inotify = Inotify::init()?;
+            let events = inotify.event_stream(vec![0; EVENT_BUFFER_SIZE])?;
+            Ok(Watcher {
+                inotify,
+                events,
+                files: HashMap::new(),
+                dirs: HashMap::new(),
+            })
+        }
+
+        /// Watch the file behind path, and its directory. Watch again after a rotation,
+        /// since a watch follows the file rather than the path.
+        pub fn watch(&mut self, path: &str) -> Result<()> {
+            let wd = self.inotify.add_watch(
+                path,
+                WatchMask::MODIFY | WatchMask::MOVE_SELF | WatchMask::DELETE_SELF,
+            )?;
+            self.files.insert(wd, path.to_string());
+
+            let dir = dir_of(path)?;
+            let wd = self
+                .inotify
+                .add_watch(dir, WatchMask::CREATE | WatchMask::MOVED_TO)?;
+            self.dirs.insert(wd, dir.to_string());
+            debug!("Watch {}", path);
+            Ok(())
+        }
+
+        /// Stop watching the files behind path, e.g. once its tailer is dropped or before
+        /// watching the new file after a rotation. Stop watching the directory too once
+        /// no other watched file is in it.
+        pub fn unwatch(&mut self, path: &str) -> Result<()> {
+            let wds: Vec<WatchDescriptor> = self
+                .files
+                .iter()
+                .filter(|(_, file)| file.as_str() == path)
+                .map(|(wd, _)| wd.clone())
+                .collect();
+            for wd in wds {
+                self.files.remove(&wd);
+                // The watch is already gone if the file was deleted.
+                if let Err(e) = self.inotify.rm_watch(wd) {
+                    debug!("Failed to unwatch {}: {}", path, e);
+                }
+            }
+
+            let dir = dir_of(path)?;
+            if self
+                .files
+                .values()
+                .any(|file| dir_of(file).ok() == Some(dir))
+            {
+                return Ok(());
+            }
+            let wds: Vec<WatchDescriptor> = self
+                .dirs
+                .iter()
+                .filter(|(_, watched)| watched.as_str() == dir)
+                .map(|(wd, _)| wd.clone())
+                .collect();
+            for wd in wds {
+                self.dirs.remove(&wd);
+                if let Err(e) = self.inotify.rm_watch(wd) {
+                    debug!("Failed to unwatch {}: {}", dir, e);
+                }
+            }
+            debug!("Unwatch {}", path);
+            Ok(())
+        }
+
+        /// Wait for changes. Return the paths of files changed or created,
+        /// or Overflow if the kernel dropped events.
+        pub async fn changed(&mut self) -> Result<Changes> {
+            let mut paths = vec![];
+            let mut next = self.events.next().await;
+            loop {
+                let event = match next {
+                    Some(event) => event?,
+                    None => return Err(woodpecker_error("Inotify closed")),
+                };
+                if event.mask.contains(EventMask::Q_OVERFLOW) {
+                    return Ok(Changes::Overflow);
+                }
+                if event.mask.contains(EventMask::IGNORED) {
+                    // The file is gone, or watched again under a new descriptor.
+                    self.files.remove(&event.wd);
+                    self.dirs.remove(&event.wd);
+                } else if let Some(path) = self.files.get(&event.wd) {
+                    paths.push(path.clone());
+                } else if let (Some(dir), Some(name)) = (self.dirs.get(&event.wd), event.name) {
+                    if let Some(path) = Path::new(dir).join(name).to_str() {
+                        paths.push(path.to_string());
+                    }
+                }
+                // Collect whatever else is ready without waiting,
+                // unless nothing watched changed yet, e.g. after unwatching.
+                next = match self.events.next().now_or_never() {
+                    Some(next) => next,
+                    None if paths.is_empty() => self.events.next().await,
+                    None => break,
+                };
+            }
+            paths.sort();
+            paths.dedup();
+            Ok(Changes::Paths(paths))
+        }
+    }
+
+    fn dir_of(path: &str) -> Result<&str> {
+        match Path::new(path).parent().and_then(|dir| dir.to_str()) {
+            Some("") => Ok("."),
+            Some(dir) => Ok(dir),
+            None => Err(woodpecker_error(
+                format!("No directory of {}", path).as_str(),
+            )),
+        }
+    }
+}
+
+/// Inotify is Linux only. Elsewhere, the agent polls.
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use super::Changes;
+    use crate::error::{woodpecker_error, Result};
+
+    pub struct Watcher;
+
+    impl Watcher {
+        pub fn try_new() -> Result<Watcher> {
+            Err(woodpecker_error(
+                "Watching files is only supported on Linux",
+            ))
+        }
+
+        pub fn watch(&mut self, _path: &str) -> Result<()> {
+            Ok(())
+        }
+
+        pub fn unwatch(&mut self, _path: &str) -> Result<()> {
+            Ok(())
+        }
+
+        pub async fn changed(&mut self) -> Result<Changes> {
+            futures::future::pending().await
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::error::Result;
+    use std::fs::{rename, File};
+    use std::io::Write;
+    use std::time::Duration;
+    use tempfile::tempdir;
+    use tokio::time::timeout;
+
+    fn init() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[tokio::test]
+    async fn wake_on_write_and_rotate() -> Result<()> {
+        init();
+        let dir = tempdir()?;
+        let path = dir.path().join("app.log");
+        let path_str = path.to_str().unwrap();
+        let mut file = File::create(&path)?;
+        File::create(dir.path().join("other.log"))?;
+
+        let mut watcher = Watcher::try_new()?;
+        watcher.watch(path_str)?;
+        file.write_all(b"Mary had a little lamb\n")?;
+        let changed = timeout(Duration::from_secs(1), watcher.changed()).await;
+        assert_eq!(
+            Changes::Paths(vec![path_str.to_string()]),
+            changed.unwrap()?
+        );
+
+        // Rotate by rename and create. Both wake the tailer of path.
+        rename(&path, dir.path().join("app.log.1"))?;
+        let changed = timeout(Duration::from_secs(1), watcher.changed()).await;
+        assert!(
+            matches!(changed.unwrap()?, Changes::Paths(paths) if paths.contains(&path_str.to_string()))
+        );
+        File::create(&path)?;
+        let changed = timeout(Duration::from_secs(1), watcher.changed()).await;
+        assert_eq!(
+            Changes::Paths(vec![path_str.to_string()]),
+            changed.unwrap()?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unwatch() -> Result<()> {
+        init();
+        let dir = tempdir()?;
+        let path = dir.path().join("app.log");
+        let path_str = path.to_str().unwrap();
+        let mut file = File::create(&path)?;
+
+        let mut watcher = Watcher::try_new()?;
+        watcher.watch(path_str)?;
+        watcher.unwatch(path_str)?;
+        file.write_all(b"Mary had a little lamb\n")?;
+        File::create(dir.path().join("other.log"))?;
+        let changed = timeout(Duration::from_millis(200), watcher.changed()).await;
+        assert!(changed.is_err());
+        Ok(())
+    }
+}
@@ -1,16 +1,23 @@
 #[cfg(test)]
 mod tests {
-    use crate::agent::client::agent::{Agent, AgentConfig};
+    use crate::agent::client::agent::Agent;
+    use crate::agent::client::config::{AgentConfig, FileConfig};
+    use crate::agent::client::rate_limit::OverLimit;
+    use crate::agent::client::uploader::RetryPolicy;
     use crate::agent::server::server;
+    use crate::codec::Codec;
+    use crate::config::ServiceConfig;
     use crate::error::Result;
+    use crate::event::EventBoundary;
     use crate::resource_util::tests::{
         create_default_bucket, create_default_queue, delete_default_bucket, delete_default_queue,
         list_default_bucket,
     };
     use log::debug;
     use serial_test::serial;
+    use std::collections::HashMap;
     use std::io::Write;
-    use tempfile::NamedTempFile;
+    use tempfile::{tempdir, NamedTempFile};
     use tokio::task;
     use tokio::time::{sleep, Duration};
 
@@ -24,19 +31,59 @@ mod tests {
         init();
         create_default_bucket().await;
         create_default_queue().await;
-        let task = task::spawn_blocking(|| async { server::run_server().await }).await?;
+        let mut config = ServiceConfig::default();
+        config
+            .enrollment_tokens
+            .insert("token".to_string(), "account".to_string());
+        let task =
+            task::spawn_blocking(move || async move { server::run_server(config).await }).await?;
         let _server = task::spawn(task);
         // Wait for server to start
         sleep(Duration::from_millis(1000)).await;
 
-        let content = b"Mary had a little lamb\nLittle lamb, little lamb";
+        let content = b"Mary had a little lamb\nLittle lamb, little lamb\n";
         let mut temp_file = NamedTempFile::new()?;
         temp_file.write(content)?;
 
         let path_str = temp_file.path().to_str().unwrap();
+        let checkpoint_dir = tempdir()?;
+        let checkpoint_file = checkpoint_dir.path().join("checkpoints");
         let config = AgentConfig {
-            file: path_str.to_string(),
+            agent_service_url: "http://[::1]:50051".to_string(),
+            files: vec![FileConfig::new(path_str)],
             buffer_size: 1024,
+            checkpoint_file: checkpoint_file.to_str().unwrap().to_string(),
+            rescan_interval: Duration::from_secs(60),
+            rotation_quiet_period: Duration::from_secs(0),
+            watch_files: true,
+            event_boundary: EventBoundary::NewLine,
+            event_timeout: Duration::from_secs(60),
+            check_in_interval: Duration::from_secs(60),
+            identity_file: checkpoint_dir
+                .path()
+                .join("agent_id")
+                .to_str()
+                .unwrap()
+                .to_string(),
+            labels: HashMap::new(),
+            enrollment_token: "token".to_string(),
+            spool_dir: checkpoint_dir
+                .path()
+                .join("spool")
+                .to_str()
+                .unwrap()
+                .to_string(),
+            spool_max_bytes: 1024 * 1024,
+            compression: Codec::None,
+            batch_size: 1024 * 1024,
+            flush_interval: Duration::from_secs(0),
+            upload_retry: RetryPolicy::default(),
+            rate_limit: 0,
+            over_limit: OverLimit::Backpressure,
+            redactions: vec![],
+            redaction_key: String::new(),
+            attach_metadata: true,
+            sources: vec![],
         };
         let mut agent = Agent::try_new(config).await?;
         agent.work().await?;
@@ -0,0 +1,92 @@
+use crate::agent::protobuf::AgentConfig;
+use crate::error::Result;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+/// An AgentConfigStore keeps the configuration of each agent, keyed by agent id.
+#[async_trait]
+pub trait AgentConfigStore: Send + Sync {
+    /// Get the configuration of an agent, if any.
+    async fn get_config(&self, agent_id: &str) -> Result<Option<AgentConfig>>;
+    /// Set the configuration of an agent, replacing the previous one.
+    async fn put_config(&self, agent_id: &str, config: AgentConfig) -> Result<()>;
+}
+
+/// An AgentConfigStore in memory.
+/// Agents without their own configuration get the default one, if any.
+#[derive(Default)]
+pub struct InMemoryAgentConfigStore {
+    configs: RwLock<HashMap<String, AgentConfig>>,
+    default: Option<AgentConfig>,
+}
+
+impl InMemoryAgentConfigStore {
+    pub fn new(default: Option<AgentConfig>) -> InMemoryAgentConfigStore {
+        InMemoryAgentConfigStore {
+            configs: RwLock::new(HashMap::new()),
+            default,
+        }
+    }
+}
+
+#[async_trait]
+impl AgentConfigStore for InMemoryAgentConfigStore {
+    async fn get_config(&self, agent_id: &str) -> Result<Option<AgentConfig>> {
+        let configs = self.configs.read().unwrap();
+        Ok(configs.get(agent_id).or(self.default.as_ref()).cloned())
+    }
+
+    async fn put_config(&self, agent_id: &str, config: AgentConfig) -> Result<()> {
+        let mut configs = self.configs.write().unwrap();
+        configs.insert(agent_id.to_string(), config);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::protobuf::FileConfig;
+
+    fn config(pattern: &str) -> AgentConfig {
+        AgentConfig {
+            files: vec![FileConfig {
+                pattern: pattern.to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn roundtrip() -> Result<()> {
+        let store = InMemoryAgentConfigStore::default();
+        assert_eq!(None, store.get_config("agent").await?);
+
+        store.put_config("agent", config("/var/log/*.log")).await?;
+        assert_eq!(
+            Some(config("/var/log/*.log")),
+            store.get_config("agent").await?
+        );
+        assert_eq!(None, store.get_config("another agent").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fall_back_to_default() -> Result<()> {
+        let store = InMemoryAgentConfigStore::new(Some(config("/var/log/*.log")));
+        store
+            .put_config("agent", config("/var/log/app/*.log"))
+            .await?;
+        assert_eq!(
+            Some(config("/var/log/app/*.log")),
+            store.get_config("agent").await?
+        );
+        assert_eq!(
+            Some(config("/var/log/*.log")),
+            store.get_config("another agent").await?
+        );
+        Ok(())
+    }
+}
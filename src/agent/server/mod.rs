@@ -1,3 +1,5 @@
+pub mod config_store;
 pub mod presigned_url;
+pub mod registry;
 #[allow(clippy::module_inception)]
 pub mod server;
@@ -1,11 +1,16 @@
+use crate::codec::Codec;
+use crate::config::ServiceConfig;
 use crate::data::pub_sub::{PubSub, SqsPubSub};
 use crate::error::Result;
 use crate::serde::ingress_task::IngressTask;
+use chrono::{DateTime, DurationRound, Utc};
 use rusoto_core::Region;
 use rusoto_credential::AwsCredentials;
 use rusoto_s3::util::{PreSignedRequest, PreSignedRequestOption};
 use rusoto_s3::PutObjectRequest;
+use std::convert::TryFrom;
 use std::fmt;
+use std::time::Duration;
 use uuid::Uuid;
 
 /// A PresignedUrl is a place holder for uploads.
@@ -35,31 +40,81 @@ pub struct PresignedUrlRepository {
     region: Region,
     credentials: AwsCredentials,
     pub_sub: SqsPubSub,
+    expires_in: Duration,
 }
 
-// TODO: create partitions by agent id, account id, etc.
-// Example: /bucket/account_id/agent_id/uuid
-fn new_key() -> String {
-    Uuid::new_v4().to_string()
+/// Who asks for the keys and when, to partition the keys by.
+#[derive(Clone, Debug)]
+pub struct KeyContext {
+    pub account_id: String,
+    pub agent_id: String,
+    pub time: DateTime<Utc>,
+    /// How the uploads are compressed, marked by the extension of the keys.
+    pub codec: Codec,
 }
 
-/// Default to use localstack at port 4566.
-impl Default for PresignedUrlRepository {
-    fn default() -> Self {
-        let region = Region::Custom {
-            name: "local".to_string(),
-            endpoint: "http://localhost:4566".to_string(),
-        };
-        PresignedUrlRepository::new(
-            "default-bucket".to_string(),
-            "http://localhost:4566/000000000000/default_queue_name".to_string(),
-            region,
-            AwsCredentials::default(),
-        )
+impl KeyContext {
+    pub fn new(account_id: &str, agent_id: &str) -> KeyContext {
+        KeyContext {
+            account_id: account_id.to_string(),
+            agent_id: agent_id.to_string(),
+            time: Utc::now(),
+            codec: Codec::None,
+        }
+    }
+
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
     }
+
+    /// When the hourly partition of the keys ends.
+    pub fn partition_end(&self) -> DateTime<Utc> {
+        let start = self
+            .time
+            .duration_trunc(chrono::Duration::hours(1))
+            .unwrap_or(self.time);
+        start + chrono::Duration::hours(1)
+    }
+}
+
+// Example: raw/account_id/agent_id/yyyy/mm/dd/hh/uuid.gz
+fn new_key(context: &KeyContext) -> String {
+    format!(
+        "{}{}/{}{}",
+        key_prefix(&context.account_id, &context.agent_id),
+        context.time.format("%Y/%m/%d/%H"),
+        Uuid::new_v4(),
+        context.codec.extension()
+    )
+}
+
+/// Start of the keys of an agent, e.g. raw/account_id/agent_id/
+pub fn key_prefix(account_id: &str, agent_id: &str) -> String {
+    format!("raw/{}/{}/", segment(account_id), segment(agent_id))
+}
+
+/// Keep a value within one segment of the key, replacing characters other than
+/// letters, digits, dots, dashes and underscores.
+fn segment(value: &str) -> String {
+    if value.is_empty() {
+        return "unknown".to_string();
+    }
+    value
+        .chars()
+        .map(|c| match c {
+            'A'..='Z' | 'a'..='z' | '0'..='9' | '.' | '_' | '-' => c,
+            _ => '_',
+        })
+        .collect()
 }
 
 impl PresignedUrlRepository {
+    /// Use localstack at port 4566.
+    pub fn localstack() -> Result<PresignedUrlRepository> {
+        PresignedUrlRepository::from_config(&ServiceConfig::default())
+    }
+
     fn new(
         bucket: String,
         queue_url: String,
@@ -72,23 +127,48 @@ impl PresignedUrlRepository {
             region: region.clone(),
             credentials,
             pub_sub: SqsPubSub::new(region),
+            expires_in: ServiceConfig::default().presigned_url_expiry,
         }
     }
 
+    pub fn from_config(config: &ServiceConfig) -> Result<PresignedUrlRepository> {
+        Ok(PresignedUrlRepository::new(
+            config.bucket.clone(),
+            config.queue_url.clone(),
+            config.region()?,
+            AwsCredentials::default(),
+        )
+        .with_expires_in(config.presigned_url_expiry))
+    }
+
+    /// Set how long PresignedUrls work after they are produced.
+    pub fn with_expires_in(mut self, expires_in: Duration) -> Self {
+        self.expires_in = expires_in;
+        self
+    }
+
+    pub fn expires_in(&self) -> Duration {
+        self.expires_in
+    }
+
+    /// Bucket the PresignedUrls upload to.
+    pub fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
     /// Produce PresignedUrls for client use.
-    pub async fn produce(&self, n: usize) -> Vec<PresignedUrl> {
+    pub async fn produce(&self, n: usize, context: &KeyContext) -> Vec<PresignedUrl> {
+        let option = PreSignedRequestOption {
+            expires_in: self.expires_in,
+        };
         let mut urls = Vec::with_capacity(n);
         for _ in 0..n {
             let req = PutObjectRequest {
                 bucket: self.bucket.clone(),
-                key: new_key(),
+                key: new_key(context),
                 ..Default::default()
             };
-            let url = req.get_presigned_url(
-                &self.region,
-                &self.credentials,
-                &PreSignedRequestOption::default(),
-            );
+            let url = req.get_presigned_url(&self.region, &self.credentials, &option);
             urls.push(PresignedUrl::new(&url));
         }
         urls
@@ -98,7 +178,7 @@ impl PresignedUrlRepository {
     pub async fn consume(&self, urls: Vec<PresignedUrl>) -> Result<()> {
         let mut messages = Vec::with_capacity(urls.len());
         for url in urls {
-            let task: IngressTask = url.into();
+            let task = IngressTask::try_from(url)?;
             let json = serde_json::to_string(&task).expect("serialize to json string: ");
             messages.push(json);
         }
@@ -114,6 +194,7 @@ mod tests {
     use super::*;
     use crate::data::pub_sub::PubSub;
     use crate::error::Result;
+    use chrono::TimeZone;
     use log::debug;
     use serial_test::serial;
 
@@ -122,35 +203,93 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_zero() {
-        let repository = PresignedUrlRepository::default();
-        let urls = repository.produce(0).await;
+    async fn test_zero() -> Result<()> {
+        let repository = PresignedUrlRepository::localstack()?;
+        let urls = repository
+            .produce(0, &KeyContext::new("account", "agent"))
+            .await;
         assert!(urls.is_empty());
+        Ok(())
     }
 
     #[tokio::test]
-    async fn test_one() {
+    async fn test_one() -> Result<()> {
         init();
-        let repository = PresignedUrlRepository::default();
-        let urls = repository.produce(1).await;
+        let repository = PresignedUrlRepository::localstack()?;
+        let urls = repository
+            .produce(1, &KeyContext::new("account", "agent"))
+            .await;
         assert_eq!(1, urls.len());
         debug!("{}", urls[0]);
         assert!(urls[0]
             .to_string()
-            .starts_with("http://localhost:4566/default-bucket/"));
+            .starts_with("http://localhost:4566/default-bucket/raw/account/agent/"));
+        Ok(())
+    }
+
+    #[test]
+    fn expiry_from_config() -> Result<()> {
+        let config = ServiceConfig {
+            presigned_url_expiry: Duration::from_secs(600),
+            ..Default::default()
+        };
+        let repository = PresignedUrlRepository::from_config(&config)?;
+        assert_eq!(Duration::from_secs(600), repository.expires_in());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_expires_in() -> Result<()> {
+        init();
+        let repository =
+            PresignedUrlRepository::localstack()?.with_expires_in(Duration::from_secs(60));
+        assert_eq!(Duration::from_secs(60), repository.expires_in());
+        let urls = repository
+            .produce(1, &KeyContext::new("account", "agent"))
+            .await;
+        assert!(urls[0].to_string().contains("X-Amz-Expires=60&"));
+        Ok(())
+    }
+
+    #[test]
+    fn partitioned_key() {
+        let context = KeyContext {
+            account_id: "account".to_string(),
+            agent_id: "agent/1".to_string(),
+            time: Utc.with_ymd_and_hms(2021, 4, 7, 5, 33, 41).unwrap(),
+            codec: Codec::None,
+        };
+        let key = new_key(&context);
+        assert!(key.starts_with("raw/account/agent_1/2021/04/07/05/"));
+        assert_eq!(8, key.split('/').count());
+        assert_eq!(
+            Utc.with_ymd_and_hms(2021, 4, 7, 6, 0, 0).unwrap(),
+            context.partition_end()
+        );
+
+        let context = KeyContext::new("acc ount", "../agent?x=1#");
+        assert!(new_key(&context).starts_with("raw/acc_ount/.._agent_x_1_/"));
+
+        let context = KeyContext::new("", "agent");
+        assert!(new_key(&context).starts_with("raw/unknown/agent/"));
+
+        let context = KeyContext::new("account", "agent").with_codec(Codec::Zstd);
+        assert!(new_key(&context).ends_with(".zst"));
     }
 
     #[tokio::test]
     #[serial]
     async fn roundtrip() -> Result<()> {
         init();
-        let repository = PresignedUrlRepository::default();
+        let repository = PresignedUrlRepository::localstack()?;
         let queue_id = repository
             .pub_sub
             .create_queue("default_queue_name")
             .await?;
 
-        let urls = repository.produce(1).await;
+        let urls = repository
+            .produce(1, &KeyContext::new("account", "agent"))
+            .await;
         repository.consume(urls.clone()).await.unwrap();
 
         let messages = repository.pub_sub.receive_messages(&queue_id).await?;
@@ -0,0 +1,111 @@
+use crate::agent::protobuf::AgentInfo;
+use chrono::Utc;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+/// An AgentRegistry keeps track of agents and when they were last seen.
+#[derive(Default)]
+pub struct AgentRegistry {
+    agents: RwLock<HashMap<String, AgentInfo>>,
+}
+
+impl AgentRegistry {
+    /// Add an agent, or refresh its hostname, labels and account if known.
+    /// The account comes from the service, not from the agent.
+    pub fn register(
+        &self,
+        agent_id: &str,
+        hostname: &str,
+        labels: HashMap<String, String>,
+        account_id: &str,
+    ) {
+        let now = Utc::now().timestamp_millis();
+        let mut agents = self.agents.write().unwrap();
+        let agent = agents
+            .entry(agent_id.to_string())
+            .or_insert_with(|| AgentInfo {
+                agent_id: agent_id.to_string(),
+                registered_at_ms: now,
+                ..Default::default()
+            });
+        agent.hostname = hostname.to_string();
+        agent.labels = labels;
+        agent.account_id = account_id.to_string();
+        agent.last_seen_ms = now;
+    }
+
+    /// Account of a registered agent.
+    pub fn account_of(&self, agent_id: &str) -> Option<String> {
+        let agents = self.agents.read().unwrap();
+        agents.get(agent_id).map(|agent| agent.account_id.clone())
+    }
+
+    /// Record that an agent is alive. Return false if the agent is not registered.
+    pub fn touch(&self, agent_id: &str) -> bool {
+        let mut agents = self.agents.write().unwrap();
+        match agents.get_mut(agent_id) {
+            Some(agent) => {
+                agent.last_seen_ms = Utc::now().timestamp_millis();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// List agents seen within max_idle_ms, or all agents if max_idle_ms is zero.
+    pub fn list(&self, max_idle_ms: u64) -> Vec<AgentInfo> {
+        let now = Utc::now().timestamp_millis();
+        let agents = self.agents.read().unwrap();
+        let mut list: Vec<AgentInfo> = agents
+            .values()
+            .filter(|agent| max_idle_ms == 0 || now - agent.last_seen_ms <= max_idle_ms as i64)
+            .cloned()
+            .collect();
+        list.sort_by(|a, b| a.agent_id.cmp(&b.agent_id));
+        list
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn register_and_touch() {
+        let registry = AgentRegistry::default();
+        assert!(!registry.touch("agent"));
+
+        registry.register("agent", "host", HashMap::new(), "account");
+        let agents = registry.list(0);
+        assert_eq!(1, agents.len());
+        assert_eq!("host", agents[0].hostname);
+        assert_eq!(Some("account".to_string()), registry.account_of("agent"));
+        let registered_at_ms = agents[0].registered_at_ms;
+
+        assert!(registry.touch("agent"));
+        registry.register("agent", "renamed", HashMap::new(), "account");
+        let agents = registry.list(0);
+        assert_eq!("renamed", agents[0].hostname);
+        assert_eq!(registered_at_ms, agents[0].registered_at_ms);
+        assert!(agents[0].last_seen_ms >= registered_at_ms);
+    }
+
+    #[test]
+    fn list_live_agents() {
+        let registry = AgentRegistry::default();
+        registry.register("agent", "host", HashMap::new(), "account");
+        registry.register("stale", "host", HashMap::new(), "account");
+        registry
+            .agents
+            .write()
+            .unwrap()
+            .get_mut("stale")
+            .unwrap()
+            .last_seen_ms -= 60_000;
+
+        let agents = registry.list(10_000);
+        assert_eq!(1, agents.len());
+        assert_eq!("agent", agents[0].agent_id);
+        assert_eq!(2, registry.list(0).len());
+    }
+}
@@ -1,58 +1,261 @@
 use crate::agent::protobuf::{
     agent_service_server::{AgentService, AgentServiceServer},
-    CreateKeysRequest, CreateKeysResponse, DeleteKeysRequest, DeleteKeysResponse,
-    GetAgentConfigRequest, GetAgentConfigResponse,
+    AgentConfig, Compression, CreateKeysRequest, CreateKeysResponse, DeleteKeysRequest,
+    DeleteKeysResponse, GetAgentConfigRequest, GetAgentConfigResponse, HeartbeatRequest,
+    HeartbeatResponse, Key, ListAgentsRequest, ListAgentsResponse, PutAgentConfigRequest,
+    PutAgentConfigResponse, RegisterAgentRequest, RegisterAgentResponse,
 };
-use crate::agent::server::presigned_url::{PresignedUrl, PresignedUrlRepository};
-use crate::error::Result;
+use crate::agent::server::config_store::{AgentConfigStore, InMemoryAgentConfigStore};
+use crate::agent::server::presigned_url::{
+    key_prefix, KeyContext, PresignedUrl, PresignedUrlRepository,
+};
+use crate::agent::server::registry::AgentRegistry;
+use crate::codec::Codec;
+use crate::config::{ServiceConfig, CONFIG_VAR};
+use crate::error::{woodpecker_error, Result};
+use crate::ingress::schema::SchemaRepository;
+use crate::serde::ingress_task::IngressTask;
 use log::{debug, info};
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::sync::Arc;
 use tonic::{transport::Server, Request, Response, Status};
 
-#[derive(Clone, Default)]
+/// Most keys created per CreateKeys request.
+const MAX_KEYS_PER_REQUEST: u32 = 100;
+
+#[derive(Clone)]
 pub struct WoodpeckerAgentService {
     repository: Arc<PresignedUrlRepository>,
+    config_store: Arc<dyn AgentConfigStore>,
+    registry: Arc<AgentRegistry>,
+    /// Accounts by enrollment token.
+    enrollment_tokens: Arc<HashMap<String, String>>,
+    /// Where to find the regex of schemas, which agents filter events by level with.
+    schema_repository: Option<Arc<SchemaRepository>>,
+}
+
+impl WoodpeckerAgentService {
+    /// Use localstack at port 4566, with configs in memory.
+    pub fn localstack() -> Result<WoodpeckerAgentService> {
+        Ok(WoodpeckerAgentService::new(
+            PresignedUrlRepository::localstack()?,
+            Arc::new(InMemoryAgentConfigStore::default()),
+        ))
+    }
+
+    pub fn new(
+        repository: PresignedUrlRepository,
+        config_store: Arc<dyn AgentConfigStore>,
+    ) -> WoodpeckerAgentService {
+        WoodpeckerAgentService {
+            repository: Arc::new(repository),
+            config_store,
+            registry: Arc::new(AgentRegistry::default()),
+            enrollment_tokens: Arc::new(HashMap::new()),
+            schema_repository: None,
+        }
+    }
+
+    /// Let agents register with these tokens, into the account of their token.
+    pub fn with_enrollment_tokens(mut self, enrollment_tokens: HashMap<String, String>) -> Self {
+        self.enrollment_tokens = Arc::new(enrollment_tokens);
+        self
+    }
+
+    /// Send agents the regex of the schema of files with rules by level.
+    pub fn with_schema_repository(mut self, schema_repository: SchemaRepository) -> Self {
+        self.schema_repository = Some(Arc::new(schema_repository));
+        self
+    }
+
+    /// Set the schema regex of files with rules by level, from their schema_id.
+    async fn resolve_schemas(&self, config: &mut AgentConfig) -> Result<()> {
+        let schema_repository = match &self.schema_repository {
+            Some(schema_repository) => schema_repository,
+            None => return Ok(()),
+        };
+        for file in config.files.iter_mut() {
+            let by_level = file
+                .include
+                .iter()
+                .chain(file.exclude.iter())
+                .any(|rule| !rule.levels.is_empty());
+            if by_level && !file.schema_id.is_empty() {
+                file.schema_regex = schema_repository.get_schema(&file.schema_id).await?.regex;
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the key is one the agent could have been given: in the bucket, under its prefix.
+    fn check_key(&self, key: &str, prefix: &str) -> std::result::Result<PresignedUrl, Status> {
+        let url = PresignedUrl::new(key);
+        let task = IngressTask::try_from(url.clone())
+            .map_err(|e| Status::permission_denied(e.to_string()))?;
+        if task.bucket != self.repository.bucket()
+            || !task.key.starts_with(prefix)
+            || task.key.split('/').any(|part| part == "..")
+        {
+            return Err(Status::permission_denied(format!(
+                "Key {}/{} is not under {}",
+                task.bucket, task.key, prefix
+            )));
+        }
+        Ok(url)
+    }
 }
 
 #[tonic::async_trait]
 impl AgentService for WoodpeckerAgentService {
+    async fn register_agent(
+        &self,
+        request: Request<RegisterAgentRequest>,
+    ) -> std::result::Result<Response<RegisterAgentResponse>, Status> {
+        let request = request.into_inner();
+        let account_id = self
+            .enrollment_tokens
+            .get(&request.enrollment_token)
+            .ok_or_else(|| Status::unauthenticated("Unknown enrollment token"))?;
+        info!(
+            "Registering agent {} on host {} in account {}",
+            request.agent_id, request.hostname, account_id
+        );
+        self.registry.register(
+            &request.agent_id,
+            &request.hostname,
+            request.labels,
+            account_id,
+        );
+        Ok(Response::new(RegisterAgentResponse {}))
+    }
+
+    async fn heartbeat(
+        &self,
+        request: Request<HeartbeatRequest>,
+    ) -> std::result::Result<Response<HeartbeatResponse>, Status> {
+        let registered = self.registry.touch(&request.into_inner().agent_id);
+        Ok(Response::new(HeartbeatResponse { registered }))
+    }
+
+    async fn list_agents(
+        &self,
+        request: Request<ListAgentsRequest>,
+    ) -> std::result::Result<Response<ListAgentsResponse>, Status> {
+        let agents = self.registry.list(request.into_inner().max_idle_ms);
+        Ok(Response::new(ListAgentsResponse { agents }))
+    }
+
     async fn get_agent_config(
         &self,
-        _request: Request<GetAgentConfigRequest>,
+        request: Request<GetAgentConfigRequest>,
     ) -> std::result::Result<Response<GetAgentConfigResponse>, Status> {
-        Ok(Response::new(GetAgentConfigResponse {}))
+        let agent_id = request.into_inner().agent_id;
+        self.registry.touch(&agent_id);
+        let mut config = self.config_store.get_config(&agent_id).await?;
+        if let Some(config) = config.as_mut() {
+            self.resolve_schemas(config).await?;
+        }
+        debug!("Config of agent {}: {:?}", agent_id, config);
+        Ok(Response::new(GetAgentConfigResponse { config }))
+    }
+
+    async fn put_agent_config(
+        &self,
+        request: Request<PutAgentConfigRequest>,
+    ) -> std::result::Result<Response<PutAgentConfigResponse>, Status> {
+        let request = request.into_inner();
+        if request.agent_id.is_empty() {
+            return Err(Status::invalid_argument("Config without agent id"));
+        }
+        let config = request
+            .config
+            .ok_or_else(|| Status::invalid_argument("Agent id without config"))?;
+        info!("Set config of agent {}", request.agent_id);
+        self.config_store
+            .put_config(&request.agent_id, config)
+            .await?;
+        Ok(Response::new(PutAgentConfigResponse {}))
     }
 
     // TODO: rename from keys to presign urls. Q: should api use plural or singular?
     async fn create_keys(
         &self,
-        _request: Request<CreateKeysRequest>,
+        request: Request<CreateKeysRequest>,
     ) -> std::result::Result<Response<CreateKeysResponse>, Status> {
-        let keys = self.repository.produce(5).await;
-        let values = keys.iter().map(PresignedUrl::to_string).collect();
-        debug!("Created keys: {:?}", values);
-        Ok(Response::new(CreateKeysResponse { keys: values }))
+        let request = request.into_inner();
+        self.registry.touch(&request.agent_id);
+        let account_id = self
+            .registry
+            .account_of(&request.agent_id)
+            .ok_or_else(|| Status::failed_precondition("Agent is not registered"))?;
+        let compression =
+            Compression::from_i32(request.compression).unwrap_or(Compression::Unspecified);
+        let context =
+            KeyContext::new(&account_id, &request.agent_id).with_codec(Codec::from(compression));
+        let count = request.count.clamp(1, MAX_KEYS_PER_REQUEST);
+        // Signing happens after the context time, so the urls last a little longer than this.
+        let expires_at_ms =
+            context.time.timestamp_millis() + self.repository.expires_in().as_millis() as i64;
+        // Keys are partitioned by the time they are created, not used.
+        let use_before_ms = context.partition_end().timestamp_millis();
+        let keys = self.repository.produce(count as usize, &context).await;
+        let keys: Vec<Key> = keys
+            .iter()
+            .map(|url| Key {
+                url: url.to_string(),
+                expires_at_ms,
+                use_before_ms,
+            })
+            .collect();
+        debug!("Created keys: {:?}", keys);
+        Ok(Response::new(CreateKeysResponse { keys }))
     }
 
     async fn delete_keys(
         &self,
         request: Request<DeleteKeysRequest>,
     ) -> std::result::Result<Response<DeleteKeysResponse>, Status> {
-        for key in request.into_inner().keys {
-            debug!("Deleting key: {}", key);
-            self.repository
-                .consume(vec![PresignedUrl { value: key }])
-                .await
-                .unwrap();
-        }
+        let request = request.into_inner();
+        self.registry.touch(&request.agent_id);
+        let account_id = self
+            .registry
+            .account_of(&request.agent_id)
+            .ok_or_else(|| Status::permission_denied("Agent is not registered"))?;
+        let prefix = key_prefix(&account_id, &request.agent_id);
+        // Check every key first, so none are consumed when one is refused.
+        let urls = request
+            .keys
+            .iter()
+            .map(|key| self.check_key(key, &prefix))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        debug!("Deleting keys: {:?}", request.keys);
+        self.repository.consume(urls).await?;
         Ok(Response::new(DeleteKeysResponse {}))
     }
 }
 
 // Refactor this out of main to avoid nested tokio runtime when running test.
-pub async fn run_server() -> Result<()> {
-    let addr = "[::1]:50051".parse().unwrap();
-    let service = WoodpeckerAgentService::default();
+pub async fn run_server(config: ServiceConfig) -> Result<()> {
+    let addr = config.agent_service_addr.parse().map_err(|e| {
+        woodpecker_error(format!("Invalid address {}: {}", config.agent_service_addr, e).as_str())
+    })?;
+    if config.enrollment_tokens.is_empty() {
+        return Err(woodpecker_error(
+            format!(
+                "No enrollment_tokens in the file at {}, so no agent can register. \
+                Add one, e.g. {{\"enrollment_tokens\": {{\"<secret token>\": \"<account id>\"}}}}",
+                CONFIG_VAR
+            )
+            .as_str(),
+        ));
+    }
+    let service = WoodpeckerAgentService::new(
+        PresignedUrlRepository::from_config(&config)?,
+        Arc::new(InMemoryAgentConfigStore::default()),
+    )
+    .with_enrollment_tokens(config.enrollment_tokens.clone())
+    .with_schema_repository(SchemaRepository::from_config(&config)?);
     info!("Server listening on {}", addr);
     Server::builder()
         .add_service(AgentServiceServer::new(service))
@@ -64,11 +267,12 @@ pub async fn run_server() -> Result<()> {
 #[tokio::main]
 pub async fn main() -> Result<()> {
     env_logger::init();
-    run_server().await
+    run_server(ServiceConfig::load()?).await
 }
 
 #[cfg(test)]
 mod tests {
+    use chrono::Utc;
     use rusoto_core::Region;
     use serial_test::serial;
     use tokio::task;
@@ -76,12 +280,22 @@ mod tests {
     use tonic::transport::Channel;
     use tonic::Response;
 
+    use super::WoodpeckerAgentService;
     use crate::agent::protobuf::{
-        agent_service_client::AgentServiceClient, CreateKeysRequest, CreateKeysResponse,
-        DeleteKeysRequest, DeleteKeysResponse,
+        agent_service_client::AgentServiceClient, agent_service_server::AgentService, AgentConfig,
+        CreateKeysRequest, CreateKeysResponse, DeleteKeysRequest, DeleteKeysResponse, FileConfig,
+        FilterRule, GetAgentConfigRequest, HeartbeatRequest, ListAgentsRequest,
+        PutAgentConfigRequest, RegisterAgentRequest,
     };
+    use crate::agent::server::config_store::{AgentConfigStore, InMemoryAgentConfigStore};
+    use crate::agent::server::presigned_url::PresignedUrlRepository;
+    use crate::config::ServiceConfig;
     use crate::data::pub_sub::{PubSub, SqsPubSub};
     use crate::error::Result;
+    use crate::ingress::schema::{Schema, SchemaRepository, SYSLOG_SCHEMA_ID};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use tonic::Request;
 
     fn init() {
         let _ = env_logger::builder().is_test(true).try_init();
@@ -102,7 +316,11 @@ mod tests {
             endpoint: "http://localhost:4566".to_string(),
         });
         let queue_id = pub_sub.create_queue("default_queue_name").await?;
-        let task = task::spawn_blocking(|| async { super::run_server().await })
+        let mut config = ServiceConfig::default();
+        config
+            .enrollment_tokens
+            .insert("token".to_string(), "account".to_string());
+        let task = task::spawn_blocking(move || async move { super::run_server(config).await })
             .await
             .unwrap();
         let _server = task::spawn(task);
@@ -110,21 +328,257 @@ mod tests {
         sleep(Duration::from_millis(1000)).await;
 
         let mut client = client().await;
+        client
+            .register_agent(RegisterAgentRequest {
+                agent_id: "agent".to_string(),
+                enrollment_token: "token".to_string(),
+                ..Default::default()
+            })
+            .await?;
 
-        let res: Response<CreateKeysResponse> =
-            client.create_keys(CreateKeysRequest {}).await.unwrap();
+        let res: Response<CreateKeysResponse> = client
+            .create_keys(CreateKeysRequest {
+                agent_id: "agent".to_string(),
+                count: 5,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
         let keys = res.into_inner().keys;
         assert_eq!(5, keys.len());
-        for key in keys.clone() {
-            assert!(key.starts_with("http://localhost:4566/default-bucket/"));
+        for key in keys.iter() {
+            assert!(key
+                .url
+                .starts_with("http://localhost:4566/default-bucket/raw/account/agent/"));
+            assert!(key.expires_at_ms > Utc::now().timestamp_millis());
+            assert!(key.use_before_ms > Utc::now().timestamp_millis());
         }
+        let keys = keys.into_iter().map(|key| key.url).collect();
 
         let _res: Response<DeleteKeysResponse> = client
-            .delete_keys(DeleteKeysRequest { keys })
+            .delete_keys(DeleteKeysRequest {
+                agent_id: "agent".to_string(),
+                keys,
+            })
             .await
             .unwrap();
         // Struct is empty. Nothing to assert on.
         pub_sub.delete_queue(&queue_id).await?;
         Ok(())
     }
+
+    #[tokio::test]
+    async fn refuse_to_start_without_enrollment_tokens() {
+        init();
+        let error = super::run_server(ServiceConfig::default())
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("enrollment_tokens"));
+    }
+
+    #[tokio::test]
+    async fn get_agent_config() -> Result<()> {
+        init();
+        let config = AgentConfig {
+            files: vec![FileConfig {
+                pattern: "/var/log/app/*.log".to_string(),
+                schema_id: "app".to_string(),
+                ..Default::default()
+            }],
+            buffer_size: 1024,
+            ..Default::default()
+        };
+        let store = Arc::new(InMemoryAgentConfigStore::default());
+        store.put_config("agent", config.clone()).await?;
+        let service = WoodpeckerAgentService::new(PresignedUrlRepository::localstack()?, store);
+
+        let request = GetAgentConfigRequest {
+            agent_id: "agent".to_string(),
+        };
+        let response = service.get_agent_config(Request::new(request)).await?;
+        assert_eq!(Some(config), response.into_inner().config);
+
+        let request = GetAgentConfigRequest {
+            agent_id: "unknown".to_string(),
+        };
+        let response = service.get_agent_config(Request::new(request)).await?;
+        assert_eq!(None, response.into_inner().config);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn put_agent_config() -> Result<()> {
+        init();
+        let service = WoodpeckerAgentService::localstack()?;
+        let config = AgentConfig {
+            files: vec![FileConfig {
+                pattern: "/var/log/app/*.log".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let request = PutAgentConfigRequest {
+            agent_id: "agent".to_string(),
+            config: Some(config.clone()),
+        };
+        service.put_agent_config(Request::new(request)).await?;
+
+        let request = GetAgentConfigRequest {
+            agent_id: "agent".to_string(),
+        };
+        let response = service.get_agent_config(Request::new(request)).await?;
+        assert_eq!(Some(config), response.into_inner().config);
+
+        for request in &[
+            PutAgentConfigRequest {
+                agent_id: "agent".to_string(),
+                config: None,
+            },
+            PutAgentConfigRequest {
+                agent_id: String::new(),
+                config: Some(AgentConfig::default()),
+            },
+        ] {
+            let status = service
+                .put_agent_config(Request::new(request.clone()))
+                .await
+                .unwrap_err();
+            assert_eq!(tonic::Code::InvalidArgument, status.code());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_schema_regex_of_rules_by_level() -> Result<()> {
+        init();
+        let file = |schema_id: &str, levels: &[&str]| FileConfig {
+            pattern: "/var/log/*.log".to_string(),
+            schema_id: schema_id.to_string(),
+            exclude: vec![FilterRule {
+                levels: levels.iter().map(|level| level.to_string()).collect(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let config = AgentConfig {
+            files: vec![file(SYSLOG_SCHEMA_ID, &["7"]), file(SYSLOG_SCHEMA_ID, &[])],
+            ..Default::default()
+        };
+        let store = Arc::new(InMemoryAgentConfigStore::new(Some(config)));
+        let service = WoodpeckerAgentService::new(PresignedUrlRepository::localstack()?, store)
+            .with_schema_repository(SchemaRepository::localstack()?);
+
+        let request = GetAgentConfigRequest {
+            agent_id: "agent".to_string(),
+        };
+        let response = service.get_agent_config(Request::new(request)).await?;
+        let files = response.into_inner().config.unwrap().files;
+        // Only files with rules by level need the regex.
+        assert_eq!(Schema::syslog().regex, files[0].schema_regex);
+        assert_eq!("", files[1].schema_regex);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn register_and_list_agents() -> Result<()> {
+        init();
+        let mut tokens = HashMap::new();
+        tokens.insert("token".to_string(), "account".to_string());
+        let service = WoodpeckerAgentService::localstack()?.with_enrollment_tokens(tokens);
+
+        let request = HeartbeatRequest {
+            agent_id: "agent".to_string(),
+        };
+        let response = service.heartbeat(Request::new(request.clone())).await?;
+        assert!(!response.into_inner().registered);
+
+        let mut labels = HashMap::new();
+        labels.insert("env".to_string(), "test".to_string());
+        let register = RegisterAgentRequest {
+            agent_id: "agent".to_string(),
+            hostname: "host".to_string(),
+            labels: labels.clone(),
+            enrollment_token: "unknown".to_string(),
+        };
+        let status = service
+            .register_agent(Request::new(register.clone()))
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::Unauthenticated, status.code());
+        let create_keys = CreateKeysRequest {
+            agent_id: "agent".to_string(),
+            ..Default::default()
+        };
+        let status = service
+            .create_keys(Request::new(create_keys))
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::FailedPrecondition, status.code());
+
+        // The account comes from the token.
+        let register = RegisterAgentRequest {
+            enrollment_token: "token".to_string(),
+            ..register
+        };
+        service.register_agent(Request::new(register)).await?;
+        let response = service.heartbeat(Request::new(request)).await?;
+        assert!(response.into_inner().registered);
+
+        let response = service
+            .list_agents(Request::new(ListAgentsRequest::default()))
+            .await?;
+        let agents = response.into_inner().agents;
+        assert_eq!(1, agents.len());
+        assert_eq!("agent", agents[0].agent_id);
+        assert_eq!("host", agents[0].hostname);
+        assert_eq!(labels, agents[0].labels);
+        assert_eq!("account", agents[0].account_id);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refuse_keys_of_others() -> Result<()> {
+        init();
+        let mut tokens = HashMap::new();
+        tokens.insert("token".to_string(), "account".to_string());
+        let service = WoodpeckerAgentService::localstack()?.with_enrollment_tokens(tokens);
+        for agent_id in &["agent", "other"] {
+            let register = RegisterAgentRequest {
+                agent_id: agent_id.to_string(),
+                enrollment_token: "token".to_string(),
+                ..Default::default()
+            };
+            service.register_agent(Request::new(register)).await?;
+        }
+        let create_keys = CreateKeysRequest {
+            agent_id: "other".to_string(),
+            count: 1,
+            ..Default::default()
+        };
+        let response = service.create_keys(Request::new(create_keys)).await?;
+        let other_key = response.into_inner().keys[0].url.clone();
+        let own_key = other_key.replace("/raw/account/other/", "/raw/account/agent/");
+
+        let delete_keys = |agent_id: &str, key: &str| DeleteKeysRequest {
+            agent_id: agent_id.to_string(),
+            keys: vec![key.to_string()],
+        };
+        for (agent_id, key) in &[
+            ("agent", other_key.clone()),
+            (
+                "agent",
+                own_key.replace("/default-bucket/", "/foreign-bucket/"),
+            ),
+            ("agent", own_key.replace("/agent/", "/agent/../other/")),
+            ("agent", "http://localhost:4566".to_string()),
+            ("unknown", own_key.replace("/agent/", "/unknown/")),
+        ] {
+            let status = service
+                .delete_keys(Request::new(delete_keys(agent_id, key)))
+                .await
+                .unwrap_err();
+            assert_eq!(tonic::Code::PermissionDenied, status.code(), "{}", key);
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,32 @@
+use log::info;
+use prototype::agent::client::agent::Agent;
+use prototype::agent::client::config::{AgentConfig, USAGE};
+use prototype::error::Result;
+use std::env;
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Tail log files and upload them until SIGTERM or SIGINT, or a failure that persists.
+#[tokio::main]
+async fn main() -> Result<()> {
+    env_logger::init();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+    let config = AgentConfig::from_args(args)?;
+    info!("Starting agent with files: {:?}", config.files);
+    let mut agent = Agent::try_new(config).await?;
+    agent.run(shutdown()).await?;
+    info!("Agent stopped");
+    Ok(())
+}
+
+async fn shutdown() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Listen to SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT"),
+        _ = terminate.recv() => info!("Received SIGTERM"),
+    }
+}
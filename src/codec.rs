@@ -0,0 +1,139 @@
+use crate::agent::protobuf::Compression;
+use crate::error::{woodpecker_error, Result};
+use bytes::Bytes;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::Deserialize;
+use std::io::{Read, Write};
+
+/// How buffers are compressed on their way from the agent to ingress.
+/// The codec is marked by the extension of the object key, e.g. .gz
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Codec {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Codec::None => "",
+            Codec::Gzip => ".gz",
+            Codec::Zstd => ".zst",
+        }
+    }
+
+    /// Find the codec of an object by its key.
+    pub fn from_key(key: &str) -> Codec {
+        if key.ends_with(Codec::Gzip.extension()) {
+            Codec::Gzip
+        } else if key.ends_with(Codec::Zstd.extension()) {
+            Codec::Zstd
+        } else {
+            Codec::None
+        }
+    }
+
+    pub fn compress(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Codec::None => Ok(bytes.to_vec()),
+            Codec::Gzip => {
+                let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+                encoder.write_all(bytes)?;
+                Ok(encoder.finish()?)
+            }
+            Codec::Zstd => Ok(zstd::encode_all(bytes, 0)?),
+        }
+    }
+
+    /// Decompress bytes, or fail once the output exceeds max_len, e.g. for a
+    /// decompression bomb.
+    pub fn decompress(&self, bytes: Bytes, max_len: usize) -> Result<Bytes> {
+        match self {
+            Codec::None => Ok(bytes),
+            Codec::Gzip => read_at_most(GzDecoder::new(bytes.as_ref()), max_len),
+            Codec::Zstd => read_at_most(zstd::Decoder::new(bytes.as_ref())?, max_len),
+        }
+    }
+}
+
+fn read_at_most(reader: impl Read, max_len: usize) -> Result<Bytes> {
+    let mut decompressed = vec![];
+    reader
+        .take(max_len as u64 + 1)
+        .read_to_end(&mut decompressed)?;
+    if decompressed.len() > max_len {
+        return Err(woodpecker_error(
+            format!("Decompressed data exceeds {} bytes", max_len).as_str(),
+        ));
+    }
+    Ok(decompressed.into())
+}
+
+impl From<Compression> for Codec {
+    fn from(compression: Compression) -> Self {
+        match compression {
+            Compression::Unspecified | Compression::None => Codec::None,
+            Compression::Gzip => Codec::Gzip,
+            Compression::Zstd => Codec::Zstd,
+        }
+    }
+}
+
+impl From<Codec> for Compression {
+    fn from(codec: Codec) -> Self {
+        match codec {
+            Codec::None => Compression::None,
+            Codec::Gzip => Compression::Gzip,
+            Codec::Zstd => Compression::Zstd,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() -> Result<()> {
+        let content = b"Mary had a little lamb\nLittle lamb, little lamb\n".repeat(10);
+        for codec in [Codec::None, Codec::Gzip, Codec::Zstd].iter() {
+            let compressed = codec.compress(&content)?;
+            if *codec != Codec::None {
+                assert!(compressed.len() < content.len());
+            }
+            let decompressed = codec.decompress(compressed.into(), content.len())?;
+            assert_eq!(content, decompressed.as_ref());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn from_key() {
+        assert_eq!(Codec::Gzip, Codec::from_key("raw/account/agent/uuid.gz"));
+        assert_eq!(Codec::Zstd, Codec::from_key("raw/account/agent/uuid.zst"));
+        assert_eq!(Codec::None, Codec::from_key("raw/account/agent/uuid"));
+    }
+
+    #[test]
+    fn corrupted() {
+        let result = Codec::Gzip.decompress(Bytes::from_static(b"not gzip"), 1024);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn exceed_max_len() -> Result<()> {
+        let content = vec![0; 1024 * 1024];
+        for codec in [Codec::Gzip, Codec::Zstd].iter() {
+            let compressed = Bytes::from(codec.compress(&content)?);
+            assert!(codec
+                .decompress(compressed.clone(), content.len() - 1)
+                .is_err());
+            assert!(codec.decompress(compressed, content.len()).is_ok());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,185 @@
+use crate::error::{woodpecker_error, Result};
+use rusoto_core::Region;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::time::Duration;
+
+/// Environment variable of the config file.
+pub const CONFIG_VAR: &str = "WOODPECKER_CONFIG";
+/// Environment variable of the url agents connect to the agent service with.
+pub const AGENT_SERVICE_URL_VAR: &str = "WOODPECKER_AGENT_SERVICE_URL";
+
+/// Where the services find each other and their AWS resources.
+/// Load from a json file, then override any field but enrollment_tokens with the environment
+/// variable of its json name in upper case, e.g. WOODPECKER_BUCKET for bucket.
+/// Default to use localstack at port 4566.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServiceConfig {
+    /// AWS region, e.g. us-west-2
+    pub region: String,
+    /// Endpoint of AWS services, e.g. localstack. Empty to use the region's endpoints.
+    pub aws_endpoint: String,
+    /// Bucket of raw uploads and parsed files
+    pub bucket: String,
+    /// Queue of ingress tasks
+    pub queue_url: String,
+    /// Table of schemas
+    pub table_name: String,
+    /// Address the agent service listens on
+    pub agent_service_addr: String,
+    /// Url agents connect to the agent service with
+    pub agent_service_url: String,
+    /// How long presigned urls work after the agent service creates them
+    #[serde(rename = "presigned_url_expiry_ms", deserialize_with = "from_millis")]
+    pub presigned_url_expiry: Duration,
+    /// Accounts by the enrollment token agents register with. Agents with another token
+    /// cannot register. Only from the file, to keep tokens out of the environment.
+    /// The agent service refuses to start without any, e.g. set
+    /// {"enrollment_tokens": {"<secret token>": "<account id>"}} and the same token as the
+    /// enrollment_token of the agents in the account.
+    pub enrollment_tokens: HashMap<String, String>,
+}
+
+impl Default for ServiceConfig {
+    fn default() -> Self {
+        ServiceConfig {
+            region: "local".to_string(),
+            aws_endpoint: "http://localhost:4566".to_string(),
+            bucket: "default-bucket".to_string(),
+            queue_url: "http://localhost:4566/000000000000/default_queue_name".to_string(),
+            table_name: "default-table".to_string(),
+            agent_service_addr: "[::1]:50051".to_string(),
+            agent_service_url: "http://[::1]:50051".to_string(),
+            presigned_url_expiry: Duration::from_secs(3600),
+            enrollment_tokens: HashMap::new(),
+        }
+    }
+}
+
+/// Deserialize a duration from milliseconds, for fields named like timeout_ms.
+pub(crate) fn from_millis<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_millis)
+}
+
+impl ServiceConfig {
+    /// Load the file at WOODPECKER_CONFIG if set, then apply environment variables.
+    pub fn load() -> Result<ServiceConfig> {
+        let config = match env::var(CONFIG_VAR) {
+            Ok(path) => ServiceConfig::from_file(&path)?,
+            Err(_) => ServiceConfig::default(),
+        };
+        config.with_env(|name| env::var(name).ok())
+    }
+
+    /// Load a config file in json. Missing fields take the default.
+    pub fn from_file(path: &str) -> Result<ServiceConfig> {
+        let content = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    /// Override fields with variables found by lookup, e.g. WOODPECKER_BUCKET for bucket.
+    /// Durations are in milliseconds, e.g. WOODPECKER_PRESIGNED_URL_EXPIRY_MS.
+    pub fn with_env<F: Fn(&str) -> Option<String>>(mut self, lookup: F) -> Result<Self> {
+        let fields = vec![
+            ("WOODPECKER_REGION", &mut self.region),
+            ("WOODPECKER_AWS_ENDPOINT", &mut self.aws_endpoint),
+            ("WOODPECKER_BUCKET", &mut self.bucket),
+            ("WOODPECKER_QUEUE_URL", &mut self.queue_url),
+            ("WOODPECKER_TABLE_NAME", &mut self.table_name),
+            (
+                "WOODPECKER_AGENT_SERVICE_ADDR",
+                &mut self.agent_service_addr,
+            ),
+            (AGENT_SERVICE_URL_VAR, &mut self.agent_service_url),
+        ];
+        for (name, field) in fields {
+            if let Some(value) = lookup(name) {
+                *field = value;
+            }
+        }
+        let name = "WOODPECKER_PRESIGNED_URL_EXPIRY_MS";
+        if let Some(value) = lookup(name) {
+            let millis = value.parse().map_err(|e| {
+                woodpecker_error(format!("Invalid {} {}: {}", name, value, e).as_str())
+            })?;
+            self.presigned_url_expiry = Duration::from_millis(millis);
+        }
+        Ok(self)
+    }
+
+    pub fn region(&self) -> Result<Region> {
+        if !self.aws_endpoint.is_empty() {
+            return Ok(Region::Custom {
+                name: self.region.clone(),
+                endpoint: self.aws_endpoint.clone(),
+            });
+        }
+        self.region.parse().map_err(|e| {
+            woodpecker_error(format!("Invalid region {}: {}", self.region, e).as_str())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn from_file_with_env() -> Result<()> {
+        let mut file = NamedTempFile::new()?;
+        file.write_all(
+            br#"{"region": "us-west-2", "aws_endpoint": "", "bucket": "logs",
+                "presigned_url_expiry_ms": 600000}"#,
+        )?;
+        let config = ServiceConfig::from_file(file.path().to_str().unwrap())?;
+        assert_eq!("logs", config.bucket);
+        assert_eq!(Region::UsWest2, config.region()?);
+        assert_eq!(Duration::from_secs(600), config.presigned_url_expiry);
+        // Missing fields take the default.
+        assert_eq!(ServiceConfig::default().table_name, config.table_name);
+
+        let mut vars = HashMap::new();
+        vars.insert("WOODPECKER_BUCKET", "other-logs".to_string());
+        vars.insert(
+            AGENT_SERVICE_URL_VAR,
+            "http://agent-service:50051".to_string(),
+        );
+        vars.insert("WOODPECKER_PRESIGNED_URL_EXPIRY_MS", "60000".to_string());
+        let config = config.with_env(|name| vars.get(name).cloned())?;
+        assert_eq!("other-logs", config.bucket);
+        assert_eq!("http://agent-service:50051", config.agent_service_url);
+        assert_eq!(Duration::from_secs(60), config.presigned_url_expiry);
+        assert_eq!("us-west-2", config.region);
+
+        vars.insert("WOODPECKER_PRESIGNED_URL_EXPIRY_MS", "1h".to_string());
+        assert!(config.with_env(|name| vars.get(name).cloned()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn region() -> Result<()> {
+        let config = ServiceConfig::default();
+        assert_eq!(
+            Region::Custom {
+                name: "local".to_string(),
+                endpoint: "http://localhost:4566".to_string(),
+            },
+            config.region()?
+        );
+
+        let config = ServiceConfig {
+            region: "nowhere".to_string(),
+            aws_endpoint: String::new(),
+            ..Default::default()
+        };
+        assert!(config.region().is_err());
+        Ok(())
+    }
+}
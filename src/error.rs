@@ -18,10 +18,12 @@ pub enum WoodpeckerError {
     IoError(io::Error),
     SerdeJsonError(serde_json::Error),
     NotImplemented(String),
+    ReqwestError(reqwest::Error),
     RusotoError(String), // Use String to workaround type parameter in RusotoError.
     SerdeDdbError(serde_dynamodb::Error),
     TokioError(tokio::task::JoinError),
     TonicError(tonic::transport::Error),
+    UploadError(reqwest::StatusCode),
 }
 
 impl<T> From<WoodpeckerError> for Result<T> {
@@ -70,6 +72,12 @@ impl From<io::Error> for WoodpeckerError {
     }
 }
 
+impl From<reqwest::Error> for WoodpeckerError {
+    fn from(e: reqwest::Error) -> Self {
+        WoodpeckerError::ReqwestError(e)
+    }
+}
+
 impl<E: Error + 'static> From<rusoto_core::RusotoError<E>> for WoodpeckerError {
     fn from(e: rusoto_core::RusotoError<E>) -> Self {
         WoodpeckerError::RusotoError(e.to_string())
@@ -103,11 +111,13 @@ impl Display for WoodpeckerError {
             WoodpeckerError::Internal(desc) => write!(f, "Internal error: {}", desc),
             WoodpeckerError::IoError(ref desc) => write!(f, "IO error: {}", desc),
             WoodpeckerError::NotImplemented(ref desc) => write!(f, "Not implemented: {}", desc),
+            WoodpeckerError::ReqwestError(desc) => write!(f, "Reqwest error: {}", desc),
             WoodpeckerError::RusotoError(ref desc) => write!(f, "Rusoto error: {}", desc),
             WoodpeckerError::SerdeDdbError(ref desc) => write!(f, "Serde error: {}", desc),
             WoodpeckerError::SerdeJsonError(ref desc) => write!(f, "Serde error: {}", desc),
             WoodpeckerError::TokioError(desc) => write!(f, "Tokio join error: {}", desc),
             WoodpeckerError::TonicError(desc) => write!(f, "Tonic error: {}", desc),
+            WoodpeckerError::UploadError(status) => write!(f, "Upload error: {}", status),
         }
     }
 }
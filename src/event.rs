@@ -0,0 +1,184 @@
+use crate::error::{woodpecker_error, Result};
+use regex::bytes::Regex as BytesRegex;
+use serde::{Deserialize, Serialize};
+
+/// How a stream of log lines is grouped into events.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub enum EventBoundary {
+    /// Every line is an event.
+    #[default]
+    NewLine,
+    /// A line matching the regex, e.g. a leading timestamp, starts an event.
+    /// Lines that do not match belong to the previous event.
+    StartsWith(String),
+    /// Lines starting with whitespace continue the previous event, e.g. a stack trace.
+    Continuation,
+}
+
+/// Find events in bytes according to an EventBoundary.
+/// Shared by the tailer to align buffers and by the parsers to split them.
+#[derive(Clone, Debug)]
+pub struct EventSplitter {
+    boundary: EventBoundary,
+    regex: Option<BytesRegex>,
+}
+
+impl Default for EventSplitter {
+    fn default() -> Self {
+        EventSplitter {
+            boundary: EventBoundary::NewLine,
+            regex: None,
+        }
+    }
+}
+
+impl EventSplitter {
+    pub fn try_new(boundary: &EventBoundary) -> Result<EventSplitter> {
+        let regex = match boundary {
+            EventBoundary::StartsWith(pattern) => Some(BytesRegex::new(pattern).map_err(|e| {
+                woodpecker_error(format!("Invalid pattern {}: {}", pattern, e).as_str())
+            })?),
+            _ => None,
+        };
+        Ok(EventSplitter {
+            boundary: boundary.clone(),
+            regex,
+        })
+    }
+
+    /// Return the end of the last complete event in buffer, if any.
+    /// A multi-line event is only known to be complete once the next event starts,
+    /// so the last event stays incomplete until then.
+    pub fn end_of_last_event(&self, buffer: &[u8]) -> Option<usize> {
+        let end_of_lines = buffer.iter().rposition(|&b| b == b'\n')? + 1;
+        if self.boundary == EventBoundary::NewLine {
+            return Some(end_of_lines);
+        }
+        line_starts(&buffer[..end_of_lines])
+            .skip(1)
+            .filter(|&start| self.starts_event(&buffer[start..end_of_lines]))
+            .last()
+    }
+
+    /// Split bytes into events, without the trailing new line. Empty lines are skipped.
+    pub fn split<'a>(&self, bytes: &'a [u8]) -> Vec<&'a [u8]> {
+        self.split_inclusive(bytes)
+            .into_iter()
+            .map(trim_new_line)
+            .filter(|event| !event.is_empty())
+            .collect()
+    }
+
+    /// Split bytes into events with their trailing new line, so that they add up to bytes.
+    pub fn split_inclusive<'a>(&self, bytes: &'a [u8]) -> Vec<&'a [u8]> {
+        let mut starts: Vec<usize> = line_starts(bytes)
+            .enumerate()
+            .filter(|&(i, start)| i == 0 || self.starts_event(&bytes[start..]))
+            .map(|(_, start)| start)
+            .collect();
+        starts.push(bytes.len());
+        starts
+            .windows(2)
+            .map(|w| &bytes[w[0]..w[1]])
+            .filter(|event| !event.is_empty())
+            .collect()
+    }
+
+    /// Whether the line at the front of bytes starts a new event.
+    fn starts_event(&self, bytes: &[u8]) -> bool {
+        match &self.boundary {
+            EventBoundary::NewLine => true,
+            EventBoundary::StartsWith(_) => {
+                let line = match bytes.iter().position(|&b| b == b'\n') {
+                    Some(end) => &bytes[..end],
+                    None => bytes,
+                };
+                let regex = self.regex.as_ref().expect("Regex of StartsWith");
+                matches!(regex.find(line), Some(m) if m.start() == 0)
+            }
+            EventBoundary::Continuation => !matches!(bytes.first(), Some(b' ') | Some(b'\t')),
+        }
+    }
+}
+
+/// Offsets where lines begin, including the first line.
+fn line_starts(bytes: &[u8]) -> impl Iterator<Item = usize> + '_ {
+    std::iter::once(0).chain(
+        bytes
+            .iter()
+            .enumerate()
+            .filter(move |&(i, &b)| b == b'\n' && i + 1 < bytes.len())
+            .map(|(i, _)| i + 1),
+    )
+}
+
+fn trim_new_line(event: &[u8]) -> &[u8] {
+    match event.last() {
+        Some(b'\n') => &event[..event.len() - 1],
+        _ => event,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static STACK_TRACE: &[u8] = b"[INFO] Starting
+[ERROR] Exception in thread \"main\" java.lang.NullPointerException
+\tat com.example.Main.run(Main.java:14)
+\tat com.example.Main.main(Main.java:5)
+[INFO] Exiting
+";
+
+    #[test]
+    fn split_new_line() -> Result<()> {
+        let splitter = EventSplitter::try_new(&EventBoundary::NewLine)?;
+        let events = splitter.split(b"a\nb\n\nc");
+        assert_eq!(vec![&b"a"[..], b"b", b"c"], events);
+        let events = splitter.split_inclusive(b"a\nb\n\nc");
+        assert_eq!(vec![&b"a\n"[..], b"b\n", b"\n", b"c"], events);
+        assert_eq!(Some(4), splitter.end_of_last_event(b"a\nb\nc"));
+        assert_eq!(None, splitter.end_of_last_event(b"abc"));
+        Ok(())
+    }
+
+    #[test]
+    fn split_starts_with() -> Result<()> {
+        let splitter = EventSplitter::try_new(&EventBoundary::StartsWith("\\[".to_string()))?;
+        let events = splitter.split(STACK_TRACE);
+        assert_eq!(3, events.len());
+        assert!(events[1].starts_with(b"[ERROR]"));
+        assert!(events[1].ends_with(b"(Main.java:5)"));
+        assert_eq!(b"[INFO] Exiting", events[2]);
+        Ok(())
+    }
+
+    #[test]
+    fn split_continuation() -> Result<()> {
+        let splitter = EventSplitter::try_new(&EventBoundary::Continuation)?;
+        let events = splitter.split(STACK_TRACE);
+        assert_eq!(3, events.len());
+        assert!(events[1].ends_with(b"(Main.java:5)"));
+        Ok(())
+    }
+
+    #[test]
+    fn end_of_last_multi_line_event() -> Result<()> {
+        let splitter = EventSplitter::try_new(&EventBoundary::Continuation)?;
+        // The stack trace may continue, so only the first event is complete.
+        let end = splitter.end_of_last_event(&STACK_TRACE[..STACK_TRACE.len() - 15]);
+        assert_eq!(Some(b"[INFO] Starting\n".len()), end);
+        // Once the next event starts, the stack trace is complete.
+        let end = splitter.end_of_last_event(STACK_TRACE);
+        assert_eq!(Some(STACK_TRACE.len() - 15), end);
+        // The first line always starts an event, even when it looks like a continuation.
+        assert_eq!(None, splitter.end_of_last_event(b"\tat Main.java\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_pattern() {
+        let boundary = EventBoundary::StartsWith("(".to_string());
+        assert!(EventSplitter::try_new(&boundary).is_err());
+    }
+}
@@ -1,18 +1,47 @@
-use arrow::array::{ArrayRef, StringBuilder};
+use crate::event::EventSplitter;
+use crate::serde::envelope::Upload;
+use arrow::array::{ArrayRef, StringBuilder, TimestampMillisecondBuilder, UInt64Builder};
 use arrow::compute::cast;
-use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef, TimeUnit};
 use arrow::record_batch::RecordBatch;
 use bytes::Bytes;
 use log::debug;
 use regex::bytes::Regex as BytesRegex;
 use regex::Regex;
+use std::borrow::Cow;
 use std::str;
 use std::str::from_utf8;
 use std::sync::Arc;
 
+/// Columns about the source of every event, after the columns of the schema.
+/// Null where the upload does not tell, e.g. from an agent without metadata.
+pub fn system_fields() -> Vec<Field> {
+    vec![
+        Field::new("_hostname", DataType::Utf8, true),
+        Field::new("_agent_id", DataType::Utf8, true),
+        Field::new("_path", DataType::Utf8, true),
+        Field::new("_start_offset", DataType::UInt64, true),
+        Field::new("_end_offset", DataType::UInt64, true),
+        Field::new(
+            "_upload_time",
+            DataType::Timestamp(TimeUnit::Millisecond, None),
+            true,
+        ),
+    ]
+}
+
+fn append_non_empty(builder: &mut StringBuilder, value: &str) {
+    if value.is_empty() {
+        builder.append_null().unwrap();
+    } else {
+        builder.append_value(value).unwrap();
+    }
+}
+
 pub struct Parser {
     schema: SchemaRef,
     regex: Regex,
+    splitter: EventSplitter,
 }
 
 impl Parser {
@@ -21,18 +50,76 @@ impl Parser {
         Parser {
             schema,
             regex: Regex::new(pattern).unwrap(),
+            splitter: EventSplitter::default(),
         }
     }
 
+    /// Split events with splitter instead of by line.
+    /// A multi-line event needs the (?s) flag for `.` in the pattern to match new lines.
+    pub fn with_splitter(mut self, splitter: EventSplitter) -> Parser {
+        self.splitter = splitter;
+        self
+    }
+
     pub fn parse(&self, bytes: Bytes) -> RecordBatch {
-        // TODO: split by log type, e.g. NEW_LINE vs START_WITH etc.
-        let utf8 = String::from_utf8(bytes.to_vec()).unwrap();
-        let lines = utf8.split('\n').collect();
-        self.parse_lines(lines)
+        let lines: Vec<_> = self
+            .splitter
+            .split(&bytes)
+            .into_iter()
+            .map(String::from_utf8_lossy)
+            .collect();
+        self.parse_lines(&lines)
+    }
+
+    /// Parse the events of every segment, then add the system columns of their source.
+    /// The offsets of an event are those of the range of the file its segment was read from.
+    /// Bytes of an event that are not UTF-8, e.g. of a syslog datagram, become U+FFFD.
+    pub fn parse_upload(&self, upload: &Upload) -> RecordBatch {
+        let mut lines = vec![];
+        let mut segments = vec![];
+        for (segment, payload) in &upload.segments {
+            for event in self.splitter.split(payload) {
+                lines.push(String::from_utf8_lossy(event));
+                segments.push(segment);
+            }
+        }
+        let batch = self.parse_lines(&lines);
+
+        let rows = segments.len();
+        let header = &upload.header;
+        let mut hostname = StringBuilder::new(rows);
+        let mut agent_id = StringBuilder::new(rows);
+        let mut path = StringBuilder::new(rows);
+        let mut start_offset = UInt64Builder::new(rows);
+        let mut end_offset = UInt64Builder::new(rows);
+        let mut upload_time = TimestampMillisecondBuilder::new(rows);
+        for segment in segments {
+            append_non_empty(&mut hostname, &header.hostname);
+            append_non_empty(&mut agent_id, &header.agent_id);
+            append_non_empty(&mut path, &segment.path);
+            start_offset.append_option(segment.start_offset).unwrap();
+            end_offset.append_option(segment.end_offset).unwrap();
+            if header.upload_time_ms == 0 {
+                upload_time.append_null().unwrap();
+            } else {
+                upload_time.append_value(header.upload_time_ms).unwrap();
+            }
+        }
+
+        let mut fields = self.schema.fields().clone();
+        fields.extend(system_fields());
+        let mut arrays = batch.columns().to_vec();
+        arrays.push(Arc::new(hostname.finish()) as ArrayRef);
+        arrays.push(Arc::new(agent_id.finish()) as ArrayRef);
+        arrays.push(Arc::new(path.finish()) as ArrayRef);
+        arrays.push(Arc::new(start_offset.finish()) as ArrayRef);
+        arrays.push(Arc::new(end_offset.finish()) as ArrayRef);
+        arrays.push(Arc::new(upload_time.finish()) as ArrayRef);
+        RecordBatch::try_new(Arc::new(Schema::new(fields)), arrays).unwrap()
     }
 
     // TODO: refactor to return Result<RecordBatch>
-    fn parse_lines(&self, lines: Vec<&str>) -> RecordBatch {
+    fn parse_lines(&self, lines: &[Cow<str>]) -> RecordBatch {
         // Create builders for each column
         let fields = self.schema.fields();
         let cols = fields.len();
@@ -44,11 +131,11 @@ impl Parser {
         // Write columns to each builder
         for line in lines {
             debug!("Parsing line: {}", line);
-            // TODO: handle when line does not match regex
-            // TODO: add system fields like timestamp and raw
-            let caps = self.regex.captures(line).unwrap();
+            // TODO: add a system field of the raw event
+            // A line that does not match the regex has null fields.
+            let caps = self.regex.captures(line);
             for i in 0..cols {
-                match caps.name(fields[i].name()) {
+                match caps.as_ref().and_then(|caps| caps.name(fields[i].name())) {
                     Some(x) => {
                         string_builders[i].append_value(x.as_str()).unwrap();
                     }
@@ -75,6 +162,7 @@ impl Parser {
 pub struct RegexParser {
     regex: BytesRegex,
     schema: SchemaRef,
+    splitter: EventSplitter,
 }
 
 impl RegexParser {
@@ -88,20 +176,24 @@ impl RegexParser {
         Self {
             regex,
             schema: Arc::new(Schema::new(fields)),
+            splitter: EventSplitter::default(),
         }
     }
 
+    /// Split events with splitter instead of by line.
+    pub fn with_splitter(mut self, splitter: EventSplitter) -> Self {
+        self.splitter = splitter;
+        self
+    }
+
     pub fn parse(&self, bytes: Bytes) -> RecordBatch {
         let cols = self.columns();
         let mut builders: Vec<StringBuilder> = Vec::with_capacity(cols);
         for _ in 0..cols {
             builders.push(StringBuilder::new(10));
         }
-        for line in bytes.split(|&char| char == b'\n') {
-            if line.is_empty() {
-                continue;
-            }
-            self.parse_event(line, &mut builders);
+        for event in self.splitter.split(&bytes) {
+            self.parse_event(event, &mut builders);
         }
 
         let mut arrays = Vec::with_capacity(cols);
@@ -136,6 +228,7 @@ impl RegexParser {
 /// Parser splits log by line into events, then parse each event into whitespace-separated fields.
 pub struct WhitespaceParser {
     schema: SchemaRef,
+    splitter: EventSplitter,
 }
 
 impl WhitespaceParser {
@@ -146,20 +239,24 @@ impl WhitespaceParser {
             .collect();
         Self {
             schema: Arc::new(Schema::new(fields)),
+            splitter: EventSplitter::default(),
         }
     }
 
+    /// Split events with splitter instead of by line.
+    pub fn with_splitter(mut self, splitter: EventSplitter) -> Self {
+        self.splitter = splitter;
+        self
+    }
+
     pub fn parse(&self, bytes: Bytes) -> RecordBatch {
         let cols = self.columns();
         let mut builders: Vec<StringBuilder> = Vec::with_capacity(cols);
         for _ in 0..cols {
             builders.push(StringBuilder::new(10));
         }
-        for line in bytes.split(|&char| char == b'\n') {
-            if line.is_empty() {
-                continue;
-            }
-            self.parse_event(line, &mut builders);
+        for event in self.splitter.split(&bytes) {
+            self.parse_event(event, &mut builders);
         }
 
         let mut arrays = Vec::with_capacity(cols);
@@ -201,9 +298,13 @@ impl WhitespaceParser {
 
 #[cfg(test)]
 mod tests {
-    use super::Parser;
+    use super::{Parser, RegexParser};
     use crate::error::Result;
-    use arrow::array::StringArray;
+    use crate::event::{EventBoundary, EventSplitter};
+    use crate::serde::envelope::{
+        self, encode_segment, encode_upload, SegmentHeader, UploadHeader,
+    };
+    use arrow::array::{StringArray, TimestampMillisecondArray, UInt64Array};
     use arrow::datatypes::{DataType, Field, Schema};
     use arrow::record_batch::RecordBatch;
     use log::debug;
@@ -292,6 +393,96 @@ mod tests {
         assert_eq!(StringArray::from(vec!["oo"]), *col_f);
     }
 
+    #[test]
+    fn parse_invalid_utf8() -> Result<()> {
+        init();
+        let parser = Parser::new(
+            "f=(?P<f>.+)",
+            Arc::from(Schema::new(vec![Field::new("f", DataType::Utf8, true)])),
+        );
+        let input = b"f=o\xff1\nf=o2\n";
+        let record_batch = parser.parse(input.to_vec().into());
+        assert_eq!(
+            StringArray::from(vec!["o\u{fffd}1", "o2"]),
+            *to_string_array(&record_batch, 0)
+        );
+
+        let blob = encode_segment(SegmentHeader::of_source("syslog", ""), input)?;
+        let record_batch = parser.parse_upload(&envelope::decode(&blob)?);
+        assert_eq!(
+            StringArray::from(vec!["o\u{fffd}1", "o2"]),
+            *to_string_array(&record_batch, 0)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_upload() -> Result<()> {
+        init();
+        let parser = Parser::new(
+            "f=(?P<f>\\w+)",
+            Arc::from(Schema::new(vec![Field::new("f", DataType::Utf8, false)])),
+        );
+        let header = UploadHeader {
+            agent_id: "agent".to_string(),
+            hostname: "host".to_string(),
+            upload_time_ms: 1620000000000,
+        };
+        let body = [
+            encode_segment(SegmentHeader::new("a.log", "", 0, 10), b"f=o1\nf=o2\n")?,
+            encode_segment(SegmentHeader::new("b.log", "", 100, 105), b"f=o3\n")?,
+        ]
+        .concat();
+        let blob = encode_upload(&header, &body)?;
+
+        let record_batch = parser.parse_upload(&envelope::decode(&blob)?);
+        debug!("{:#?}", record_batch);
+        assert_eq!(3, record_batch.num_rows());
+        assert_eq!(7, record_batch.num_columns());
+        assert_eq!(
+            StringArray::from(vec!["o1", "o2", "o3"]),
+            *to_string_array(&record_batch, 0)
+        );
+        assert_eq!(
+            StringArray::from(vec!["host", "host", "host"]),
+            *to_string_array(&record_batch, 1)
+        );
+        assert_eq!(
+            StringArray::from(vec!["a.log", "a.log", "b.log"]),
+            *to_string_array(&record_batch, 3)
+        );
+        let start_offset = record_batch
+            .column(4)
+            .as_any()
+            .downcast_ref::<UInt64Array>()
+            .unwrap();
+        assert_eq!(UInt64Array::from(vec![0, 0, 100]), *start_offset);
+        let upload_time = record_batch
+            .column(6)
+            .as_any()
+            .downcast_ref::<TimestampMillisecondArray>()
+            .unwrap();
+        assert_eq!(1620000000000, upload_time.value(2));
+
+        // Without metadata, the system columns are null.
+        let record_batch = parser.parse_upload(&envelope::decode(b"f=o4\n")?);
+        assert_eq!(1, record_batch.num_rows());
+        assert_eq!(7, record_batch.num_columns());
+        assert!(record_batch.column(3).is_null(0));
+        assert!(record_batch.column(6).is_null(0));
+
+        // Sources other than files have a path but no offsets.
+        let blob = encode_segment(SegmentHeader::of_source("journal", ""), b"f=o5\n")?;
+        let record_batch = parser.parse_upload(&envelope::decode(&blob)?);
+        assert_eq!(
+            StringArray::from(vec!["journal"]),
+            *to_string_array(&record_batch, 3)
+        );
+        assert!(record_batch.column(4).is_null(0));
+        assert!(record_batch.column(5).is_null(0));
+        Ok(())
+    }
+
     fn to_string_array(record_batch: &RecordBatch, col: usize) -> &StringArray {
         record_batch
             .column(col)
@@ -338,4 +529,39 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn parse_multi_line() -> Result<()> {
+        init();
+        let log = "[2021-04-07T05:33:41Z ERROR log_gen] thread 'main' panicked at 'oops'
+   0: rust_begin_unwind
+   1: core::panicking::panic_fmt
+[2021-04-07T05:33:42Z INFO  log_gen] Recovered
+";
+        let pattern =
+            "(?s)\\[(?P<timestamp>\\S+) (?P<level>\\w+)\\s+(?P<class>\\w+)\\] (?P<content>.*)";
+        let splitter = EventSplitter::try_new(&EventBoundary::Continuation)?;
+        let parser = Parser::new(
+            pattern,
+            Arc::from(Schema::new(vec![
+                Field::new("level", DataType::Utf8, false),
+                Field::new("content", DataType::Utf8, false),
+            ])),
+        )
+        .with_splitter(splitter.clone());
+
+        let record_batch = parser.parse(log.into());
+        assert_eq!(2, record_batch.num_rows());
+        assert_eq!(
+            StringArray::from(vec![
+                "thread 'main' panicked at 'oops'\n   0: rust_begin_unwind\n   1: core::panicking::panic_fmt",
+                "Recovered",
+            ]),
+            *to_string_array(&record_batch, 1)
+        );
+
+        let parser = RegexParser::new(pattern).with_splitter(splitter);
+        assert_eq!(2, parser.parse(log.into()).num_rows());
+        Ok(())
+    }
 }
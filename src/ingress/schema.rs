@@ -1,12 +1,23 @@
+use crate::config::ServiceConfig;
 use crate::error::{woodpecker_error, Result};
+use crate::event::EventBoundary;
+use arrow::datatypes::DataType;
 use log::debug;
 use rusoto_core::Region;
 use rusoto_dynamodb::{AttributeValue, DynamoDb, DynamoDbClient, GetItemInput, PutItemInput};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::sync::Arc;
 
 type ArrowSchemaRef = arrow::datatypes::SchemaRef;
 
+/// Key of the built-in schema of syslog messages, which needs no lookup.
+pub const SYSLOG_SCHEMA_ID: &str = "syslog";
+/// RFC 5424, e.g. <165>1 2003-10-11T22:14:15.003Z host app 1234 ID47 - message,
+/// or RFC 3164, e.g. <34>Oct 11 22:14:15 host app[1234]: message.
+/// A message in neither format goes to message as a whole.
+const SYSLOG_REGEX: &str = r"^(?:<(?P<priority>\d{1,3})>(?:(?P<version>\d{1,2}) )?(?P<timestamp>[A-Z][a-z]{2} [ \d]\d \d{2}:\d{2}:\d{2}|\S+) (?P<hostname>\S+) (?P<app_name>[^\s\[:]+)(?:[\[ ](?P<procid>[^\s\]]+)\]?)?(?: (?P<msgid>\S+) (?P<structured_data>-|(?:\[(?:[^\]\\]|\\.)*\])+)|:)(?: |$))?(?P<message>.*)$";
+
 /// A schema consist of a regex and an arrow schema.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct Schema {
@@ -14,6 +25,9 @@ pub struct Schema {
     pub regex: String,
     /// An arrow_schema tells us how to read/write the output.
     pub arrow_schema: ArrowSchemaRef,
+    /// A boundary tells us how lines are grouped into events.
+    #[serde(default)]
+    pub boundary: EventBoundary,
 }
 
 impl Schema {
@@ -21,6 +35,36 @@ impl Schema {
         Schema {
             regex: regex.to_string(),
             arrow_schema,
+            boundary: EventBoundary::NewLine,
+        }
+    }
+
+    /// Built-in schema of syslog messages, as received by the syslog sources of the agent.
+    /// The facility is priority / 8, and the severity priority % 8.
+    pub fn syslog() -> Schema {
+        let field = |name, data_type| arrow::datatypes::Field::new(name, data_type, true);
+        let fields = vec![
+            field("priority", DataType::UInt8),
+            field("version", DataType::UInt8),
+            field("timestamp", DataType::Utf8),
+            field("hostname", DataType::Utf8),
+            field("app_name", DataType::Utf8),
+            field("procid", DataType::Utf8),
+            field("msgid", DataType::Utf8),
+            field("structured_data", DataType::Utf8),
+            field("message", DataType::Utf8),
+        ];
+        Schema::new(
+            SYSLOG_REGEX,
+            Arc::new(arrow::datatypes::Schema::new(fields)),
+        )
+    }
+
+    /// Return the built-in schema of key, if any.
+    pub fn builtin(key: &str) -> Option<Schema> {
+        match key {
+            SYSLOG_SCHEMA_ID => Some(Schema::syslog()),
+            _ => None,
         }
     }
 }
@@ -32,17 +76,12 @@ pub struct SchemaRepository {
     client: DynamoDbClient,
 }
 
-impl Default for SchemaRepository {
-    fn default() -> Self {
-        let region = Region::Custom {
-            name: "local".to_string(),
-            endpoint: "http://localhost:4566".to_string(),
-        };
-        SchemaRepository::new("default-table", region)
+impl SchemaRepository {
+    /// Use localstack at port 4566.
+    pub fn localstack() -> Result<SchemaRepository> {
+        SchemaRepository::from_config(&ServiceConfig::default())
     }
-}
 
-impl SchemaRepository {
     pub fn new(table_name: &str, region: Region) -> SchemaRepository {
         SchemaRepository {
             table_name: table_name.to_string(),
@@ -50,6 +89,10 @@ impl SchemaRepository {
         }
     }
 
+    pub fn from_config(config: &ServiceConfig) -> Result<SchemaRepository> {
+        Ok(SchemaRepository::new(&config.table_name, config.region()?))
+    }
+
     pub async fn put_schema(&self, key: &str, schema: Schema) -> Result<()> {
         // TODO: conditional put to avoid accidental overwrites.
         let mut item = serde_dynamodb::to_hashmap(&schema)?;
@@ -70,7 +113,11 @@ impl SchemaRepository {
         Ok(())
     }
 
+    /// Look up the schema of key, unless it is built-in.
     pub async fn get_schema(&self, key: &str) -> Result<Schema> {
+        if let Some(schema) = Schema::builtin(key) {
+            return Ok(schema);
+        }
         let mut item = HashMap::new();
         item.insert(
             KEY.to_string(),
@@ -101,9 +148,10 @@ impl SchemaRepository {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ingress::parser::Parser;
     use crate::resource_util::tests::{create_default_table, delete_default_table};
+    use arrow::array::{StringArray, UInt8Array};
     use serial_test::serial;
-    use std::sync::Arc;
 
     type ArrowSchema = arrow::datatypes::Schema;
 
@@ -118,7 +166,7 @@ mod tests {
         create_default_table().await;
 
         let schema = Schema::new("regex", Arc::new(ArrowSchema::empty()));
-        let repository = SchemaRepository::default();
+        let repository = SchemaRepository::localstack()?;
 
         let key = "id";
         repository.put_schema(key, schema.clone()).await?;
@@ -127,13 +175,91 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn parse_syslog() {
+        init();
+        let schema = Schema::syslog();
+        let parser = Parser::new(&schema.regex, schema.arrow_schema.clone());
+        let batch = parser.parse(
+            "<165>1 2003-10-11T22:14:15.003Z mymachine.example.com evntslog - ID47 \
+            [exampleSDID@32473 iut=\"3\" eventSource=\"App\"] An application event\n\
+            <34>Oct 11 22:14:15 mymachine su[230]: 'su root' failed for lonvick\n\
+            <13>1 - - - - - -\n\
+            Not syslog at all\n"
+                .into(),
+        );
+        assert_eq!(4, batch.num_rows());
+        let column = |name: &str| {
+            let (i, _) = schema.arrow_schema.column_with_name(name).unwrap();
+            batch.column(i).clone()
+        };
+        let priority = column("priority");
+        let priority = priority.as_any().downcast_ref::<UInt8Array>().unwrap();
+        assert_eq!(
+            UInt8Array::from(vec![Some(165), Some(34), Some(13), None]),
+            *priority
+        );
+        let assert_strings = |name: &str, expected: Vec<Option<&str>>| {
+            let array = column(name);
+            let array = array.as_any().downcast_ref::<StringArray>().unwrap();
+            assert_eq!(StringArray::from(expected), *array, "{}", name);
+        };
+        assert_strings(
+            "timestamp",
+            vec![
+                Some("2003-10-11T22:14:15.003Z"),
+                Some("Oct 11 22:14:15"),
+                Some("-"),
+                None,
+            ],
+        );
+        assert_strings(
+            "app_name",
+            vec![Some("evntslog"), Some("su"), Some("-"), None],
+        );
+        assert_strings("procid", vec![Some("-"), Some("230"), Some("-"), None]);
+        assert_strings(
+            "structured_data",
+            vec![
+                Some("[exampleSDID@32473 iut=\"3\" eventSource=\"App\"]"),
+                None,
+                Some("-"),
+                None,
+            ],
+        );
+        assert_strings(
+            "message",
+            vec![
+                Some("An application event"),
+                Some("'su root' failed for lonvick"),
+                Some(""),
+                Some("Not syslog at all"),
+            ],
+        );
+    }
+
+    #[test]
+    fn serde_boundary() -> Result<()> {
+        let mut schema = Schema::new("regex", Arc::new(ArrowSchema::empty()));
+        schema.boundary = EventBoundary::StartsWith("\\[".to_string());
+        let item = serde_dynamodb::to_hashmap(&schema)?;
+        assert_eq!(schema, serde_dynamodb::from_hashmap(item)?);
+
+        // Schemas stored before boundary existed split by line.
+        let mut item = serde_dynamodb::to_hashmap(&schema)?;
+        item.remove("boundary");
+        let schema: Schema = serde_dynamodb::from_hashmap(item)?;
+        assert_eq!(EventBoundary::NewLine, schema.boundary);
+        Ok(())
+    }
+
     #[tokio::test]
     #[serial]
     async fn does_not_exist() -> Result<()> {
         init();
         create_default_table().await;
 
-        let repository = SchemaRepository::default();
+        let repository = SchemaRepository::localstack()?;
         let res = repository.get_schema("does not exist").await;
         assert!(res
             .err()
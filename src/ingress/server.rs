@@ -1,16 +1,26 @@
+use crate::codec::Codec;
+use crate::config::ServiceConfig;
 use crate::data::blob_store::{BlobStore, S3BlobStore};
 use crate::data::pub_sub::{PubSub, SqsPubSub};
 use crate::error::Result;
+use crate::event::EventSplitter;
 use crate::ingress::parser::Parser;
 use crate::ingress::schema::SchemaRepository;
 use crate::ingress::writer::Writer;
 use log::{debug, info};
 use rusoto_core::Region;
 
+use crate::serde::envelope::{self, SegmentHeader, Upload};
 use crate::serde::ingress_task::IngressTask;
 use rusoto_s3::StreamingBody;
 use tokio::time::{sleep, Duration};
 
+/// Schema of segments without one, e.g. from older agents.
+// TODO: make the default schema configurable instead of hardcode
+const DEFAULT_SCHEMA_ID: &str = "INGRESS_SERVER_HARDCODE";
+/// Largest upload to decompress, well above the batches agents upload.
+const MAX_DECOMPRESSED_BYTES: usize = 256 * 1024 * 1024;
+
 /// Receive message from a queue for files to parse.
 /// Then write the parsed files to the bucket.
 pub struct IngressService {
@@ -21,34 +31,36 @@ pub struct IngressService {
     pub_sub: SqsPubSub,
 }
 
-/// Default to use localstack at port 4566.
-// TODO: use config-rs to manage regions, buckets, and queues.
-impl Default for IngressService {
-    fn default() -> Self {
-        let region = Region::Custom {
-            name: "local".to_string(),
-            endpoint: "http://localhost:4566".to_string(),
-        };
-        IngressService::new(
-            "default-bucket".to_string(),
-            "http://localhost:4566/000000000000/default_queue_name".to_string(),
-            region,
-        )
+impl IngressService {
+    /// Use localstack at port 4566.
+    pub fn localstack() -> Result<IngressService> {
+        IngressService::from_config(&ServiceConfig::default())
     }
-}
 
-impl IngressService {
-    pub fn new(bucket: String, queue_url: String, region: Region) -> IngressService {
+    pub fn new(
+        bucket: String,
+        queue_url: String,
+        schema_repository: SchemaRepository,
+        region: Region,
+    ) -> IngressService {
         IngressService {
             bucket,
             queue_url,
-            // TODO: fix this
-            schema_repository: SchemaRepository::default(),
+            schema_repository,
             blob_store: S3BlobStore::new(region.clone()),
             pub_sub: SqsPubSub::new(region),
         }
     }
 
+    pub fn from_config(config: &ServiceConfig) -> Result<IngressService> {
+        Ok(IngressService::new(
+            config.bucket.clone(),
+            config.queue_url.clone(),
+            SchemaRepository::from_config(config)?,
+            config.region()?,
+        ))
+    }
+
     /// Process tasks from queue and delete them afterwards.
     pub async fn process_tasks(&self) -> Result<Vec<String>> {
         let messages = self.pub_sub.receive_messages(&self.queue_url).await?;
@@ -61,8 +73,7 @@ impl IngressService {
         for (id, message) in messages {
             ids.push(id);
             let task: IngressTask = serde_json::from_str(&message)?;
-            let file = self.work(task).await?;
-            files.push(file);
+            files.extend(self.work(task).await?);
         }
 
         self.pub_sub.delete_messages(&self.queue_url, ids).await?;
@@ -70,31 +81,64 @@ impl IngressService {
     }
 
     /// Work on a single task - download, parser, write, and upload.
-    async fn work(&self, task: IngressTask) -> Result<String> {
+    /// Write a file per schema of the segments in the upload.
+    async fn work(&self, task: IngressTask) -> Result<Vec<String>> {
         debug!("Working on task: {:?}", &task);
         let blob = self.blob_store.get_object(&task.bucket, &task.key).await?;
-        // TODO: extract schema from message instead of hardcode
-        let schema = self
-            .schema_repository
-            .get_schema("INGRESS_SERVER_HARDCODE")
-            .await?;
-        let parser = Parser::new(schema.regex.as_str(), schema.arrow_schema.clone());
-        let batch = parser.parse(blob);
-        let writer = Writer::new(schema.arrow_schema.clone());
-        let file = writer.write(batch);
-        self.blob_store
-            .put_object(&self.bucket, &file.name, StreamingBody::from(file.content))
-            .await?;
+        let blob = Codec::from_key(&task.key).decompress(blob, MAX_DECOMPRESSED_BYTES)?;
+        let upload = envelope::decode(&blob)?;
+        let mut files = vec![];
+        for (schema_id, upload) in by_schema(upload) {
+            let schema = self.schema_repository.get_schema(&schema_id).await?;
+            let splitter = EventSplitter::try_new(&schema.boundary)?;
+            let parser = Parser::new(schema.regex.as_str(), schema.arrow_schema.clone())
+                .with_splitter(splitter);
+            let batch = parser.parse_upload(&upload);
+            let writer = Writer::new(batch.schema());
+            let file = writer.write(batch);
+            self.blob_store
+                .put_object(&self.bucket, &file.name, StreamingBody::from(file.content))
+                .await?;
+            files.push(file.name);
+        }
         self.blob_store
             .delete_object(&task.bucket, &task.key)
             .await?;
-        Ok(file.name)
+        Ok(files)
+    }
+}
+
+/// Schema of the segment, by its header, or the default one for segments without.
+fn schema_id(segment: &SegmentHeader) -> &str {
+    if !segment.schema_id.is_empty() {
+        segment.schema_id.as_str()
+    } else {
+        DEFAULT_SCHEMA_ID
     }
 }
 
+/// Split the upload into one per schema, keeping the order of segments.
+fn by_schema(upload: Upload<'_>) -> Vec<(String, Upload<'_>)> {
+    let mut uploads: Vec<(String, Upload)> = vec![];
+    for (segment, payload) in upload.segments {
+        let schema_id = schema_id(&segment).to_string();
+        match uploads.iter_mut().find(|(id, _)| *id == schema_id) {
+            Some((_, upload)) => upload.segments.push((segment, payload)),
+            None => uploads.push((
+                schema_id,
+                Upload {
+                    header: upload.header.clone(),
+                    segments: vec![(segment, payload)],
+                },
+            )),
+        }
+    }
+    uploads
+}
+
 // Refactor this out of main to avoid nested tokio runtime when running test.
-pub async fn run_server() -> Result<()> {
-    let service = IngressService::default();
+pub async fn run_server(config: ServiceConfig) -> Result<()> {
+    let service = IngressService::from_config(&config)?;
     loop {
         let tasks = service.process_tasks().await?;
         info!("Ingested files: {:?}", tasks);
@@ -105,24 +149,29 @@ pub async fn run_server() -> Result<()> {
 #[tokio::main]
 pub async fn main() -> Result<()> {
     env_logger::init();
-    run_server().await?;
+    run_server(ServiceConfig::load()?).await?;
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::agent::client::uploader::Uploader;
-    use crate::agent::server::presigned_url::{PresignedUrl, PresignedUrlRepository};
+    use crate::agent::client::uploader::{RetryPolicy, Uploader};
+    use crate::agent::server::presigned_url::{KeyContext, PresignedUrl, PresignedUrlRepository};
+    use crate::ingress::parser::system_fields;
+    use crate::ingress::schema::{Schema, SYSLOG_SCHEMA_ID};
     use crate::resource_util::tests::{
         create_default_bucket, create_default_queue, create_default_table, delete_default_bucket,
         delete_default_queue, delete_default_table, populate_test_schemas,
     };
     use crate::serde::ingress_task::IngressTask;
+    use arrow::array::StringArray;
+    use arrow::datatypes::{DataType, Field, Schema as ArrowSchema};
     use log::debug;
     use parquet::arrow::{ArrowReader, ParquetFileArrowReader};
     use parquet::file::serialized_reader::{SerializedFileReader, SliceableCursor};
     use serial_test::serial;
+    use std::convert::TryFrom;
     use std::sync::Arc;
 
     fn init() {
@@ -138,16 +187,18 @@ mod tests {
         create_default_table().await;
         populate_test_schemas().await;
 
-        let service = IngressService::default();
-        let key_repository = PresignedUrlRepository::default();
-        let keys = key_repository.produce(1).await;
+        let service = IngressService::localstack()?;
+        let key_repository = PresignedUrlRepository::localstack()?;
+        let keys = key_repository
+            .produce(1, &KeyContext::new("account", "agent"))
+            .await;
         let bytes = b"f=oo";
         let url = keys[0].to_string();
-        let uploader = Uploader::default();
+        let uploader = Uploader::try_new(RetryPolicy::default())?;
         uploader.upload(&url, bytes).await?;
 
         let url = PresignedUrl::new(&url);
-        let task: IngressTask = url.into();
+        let task = IngressTask::try_from(url)?;
         let blob = service
             .blob_store
             .get_object(&task.bucket, &task.key)
@@ -170,7 +221,8 @@ mod tests {
             .next()
             .expect("No batch found")
             .expect("Unable to get batch");
-        assert_eq!(1, actual_batch.num_columns());
+        // The schema's column, then the system columns, null without metadata.
+        assert_eq!(1 + system_fields().len(), actual_batch.num_columns());
         assert_eq!(1, actual_batch.num_rows());
         debug!("Actual_batch: {:#?}", actual_batch);
 
@@ -179,4 +231,70 @@ mod tests {
         delete_default_bucket().await;
         Ok(())
     }
+
+    #[test]
+    fn split_by_schema() -> Result<()> {
+        let blob = [
+            envelope::encode_segment(SegmentHeader::new("a.log", "app", 0, 5), b"f=o1\n")?,
+            envelope::encode_segment(SegmentHeader::new("b.log", "", 0, 5), b"f=o2\n")?,
+            envelope::encode_segment(
+                SegmentHeader::of_source("syslog:udp:0.0.0.0:514", SYSLOG_SCHEMA_ID),
+                b"<34>1\n",
+            )?,
+            envelope::encode_segment(SegmentHeader::new("c.log", "app", 0, 5), b"f=o3\n")?,
+        ]
+        .concat();
+        let uploads = by_schema(envelope::decode(&blob)?);
+        let schema_ids: Vec<&str> = uploads.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(vec!["app", DEFAULT_SCHEMA_ID, SYSLOG_SCHEMA_ID], schema_ids);
+        let paths: Vec<&str> = uploads[0]
+            .1
+            .segments
+            .iter()
+            .map(|(segment, _)| segment.path.as_str())
+            .collect();
+        assert_eq!(vec!["a.log", "c.log"], paths);
+
+        // Uploads without headers parse with the default schema.
+        let uploads = by_schema(envelope::decode(b"f=oo\n")?);
+        assert_eq!(1, uploads.len());
+        assert_eq!(DEFAULT_SCHEMA_ID, uploads[0].0);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_mixed_upload() -> Result<()> {
+        let blob = [
+            envelope::encode_segment(
+                SegmentHeader::of_source("syslog:udp:0.0.0.0:514", SYSLOG_SCHEMA_ID),
+                b"<34>1 2021-10-11T22:14:15.003Z host app 1 ID47 - up\n",
+            )?,
+            envelope::encode_segment(SegmentHeader::new("a.log", "", 0, 5), b"f=oo\n")?,
+        ]
+        .concat();
+        let uploads = by_schema(envelope::decode(&blob)?);
+        assert_eq!(2, uploads.len());
+
+        let syslog = Schema::syslog();
+        let batch = Parser::new(&syslog.regex, syslog.arrow_schema).parse_upload(&uploads[0].1);
+        assert_eq!(1, batch.num_rows());
+        let app_name = batch.column(batch.schema().index_of("app_name")?);
+        let app_name = app_name.as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!("app", app_name.value(0));
+
+        let schema = Arc::new(ArrowSchema::new(vec![Field::new(
+            "f",
+            DataType::Utf8,
+            true,
+        )]));
+        let batch = Parser::new(r"f=(?P<f>\w+)", schema).parse_upload(&uploads[1].1);
+        assert_eq!(1, batch.num_rows());
+        let f = batch
+            .column(0)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        assert_eq!("oo", f.value(0));
+        Ok(())
+    }
 }
@@ -1,6 +1,9 @@
 pub mod agent;
+pub mod codec;
+pub mod config;
 pub mod data;
 pub mod error;
+pub mod event;
 pub mod ingress;
 mod integration_test;
 pub mod query;
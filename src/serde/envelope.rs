@@ -0,0 +1,232 @@
+use crate::error::{woodpecker_error, Result};
+use serde::{Deserialize, Serialize};
+
+/// Marks the header of an upload, followed by its json and a new line.
+/// Markers start with a NUL byte, which text logs do not, so that uploads without headers,
+/// e.g. from older agents, are never mistaken for headers. They end with the version byte.
+const UPLOAD_MARKER: &[u8] = b"\0woodpecker-upload\x01";
+/// Marks the header of a segment, followed by its json, a new line, then the payload.
+const SEGMENT_MARKER: &[u8] = b"\0woodpecker-segment\x01";
+
+/// Where an upload comes from. Written once at the front of an upload.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct UploadHeader {
+    pub agent_id: String,
+    pub hostname: String,
+    /// Milliseconds since epoch
+    pub upload_time_ms: i64,
+}
+
+/// Where a segment of an upload comes from: a range of bytes of a file, or another source.
+/// The payload may differ from the range after filtering and redaction.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct SegmentHeader {
+    /// Path of the file, or name of the source
+    pub path: String,
+    /// Schema to parse the payload with. Empty for the default schema.
+    pub schema_id: String,
+    /// None for sources other than files
+    pub start_offset: Option<u64>,
+    pub end_offset: Option<u64>,
+    /// Bytes of payload following the header
+    pub length: usize,
+}
+
+/// An upload as segments of events with their source.
+/// Uploads without headers, e.g. from older agents, are a single segment of unknown source.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Upload<'a> {
+    pub header: UploadHeader,
+    pub segments: Vec<(SegmentHeader, &'a [u8])>,
+}
+
+fn encode(marker: &[u8], header: &impl Serialize, payload: &[u8]) -> Result<Vec<u8>> {
+    let json = serde_json::to_vec(header)?;
+    let mut bytes = Vec::with_capacity(marker.len() + json.len() + 1 + payload.len());
+    bytes.extend_from_slice(marker);
+    bytes.extend(json);
+    bytes.push(b'\n');
+    bytes.extend_from_slice(payload);
+    Ok(bytes)
+}
+
+/// Put the upload header in front of a body of segments.
+pub fn encode_upload(header: &UploadHeader, body: &[u8]) -> Result<Vec<u8>> {
+    encode(UPLOAD_MARKER, header, body)
+}
+
+impl SegmentHeader {
+    pub fn new(path: &str, schema_id: &str, start_offset: u64, end_offset: u64) -> Self {
+        SegmentHeader {
+            start_offset: Some(start_offset),
+            end_offset: Some(end_offset),
+            ..SegmentHeader::of_source(path, schema_id)
+        }
+    }
+
+    /// A segment of a source other than a file, e.g. the journal, without a range.
+    pub fn of_source(name: &str, schema_id: &str) -> Self {
+        SegmentHeader {
+            path: name.to_string(),
+            schema_id: schema_id.to_string(),
+            start_offset: None,
+            end_offset: None,
+            length: 0,
+        }
+    }
+}
+
+/// Frame the payload read from a range of a file as a segment.
+/// The length of the header is set to the payload's.
+pub fn encode_segment(mut header: SegmentHeader, payload: &[u8]) -> Result<Vec<u8>> {
+    header.length = payload.len();
+    encode(SEGMENT_MARKER, &header, payload)
+}
+
+/// Split the header, if any, from the rest of bytes.
+fn decode_header<'a, T: Deserialize<'a>>(
+    marker: &[u8],
+    bytes: &'a [u8],
+) -> Result<Option<(T, &'a [u8])>> {
+    let (name, version) = marker.split_at(marker.len() - 1);
+    if !bytes.starts_with(name) {
+        return Ok(None);
+    }
+    if !bytes.starts_with(marker) {
+        return Err(woodpecker_error(
+            format!(
+                "Unsupported version {:?} of header, expected {}",
+                bytes.get(name.len()),
+                version[0]
+            )
+            .as_str(),
+        ));
+    }
+    let end = bytes
+        .iter()
+        .position(|&b| b == b'\n')
+        .ok_or_else(|| woodpecker_error("Header without end of line"))?;
+    let header = serde_json::from_slice(&bytes[marker.len()..end])?;
+    Ok(Some((header, &bytes[end + 1..])))
+}
+
+pub fn decode(blob: &[u8]) -> Result<Upload<'_>> {
+    let (header, mut rest) = match decode_header(UPLOAD_MARKER, blob)? {
+        Some((header, rest)) => (header, rest),
+        None => (UploadHeader::default(), blob),
+    };
+    let mut segments = vec![];
+    while !rest.is_empty() {
+        match decode_header::<SegmentHeader>(SEGMENT_MARKER, rest)? {
+            Some((segment, after)) => {
+                if segment.length > after.len() {
+                    return Err(woodpecker_error(
+                        format!(
+                            "Segment of {} bytes exceeds the {} bytes left",
+                            segment.length,
+                            after.len()
+                        )
+                        .as_str(),
+                    ));
+                }
+                segments.push((segment.clone(), &after[..segment.length]));
+                rest = &after[segment.length..];
+            }
+            None => {
+                let segment = SegmentHeader {
+                    length: rest.len(),
+                    ..Default::default()
+                };
+                segments.push((segment, rest));
+                break;
+            }
+        }
+    }
+    Ok(Upload { header, segments })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() -> Result<()> {
+        let header = UploadHeader {
+            agent_id: "agent".to_string(),
+            hostname: "host".to_string(),
+            upload_time_ms: 1620000000000,
+        };
+        // The payload may look like a header itself, or like one of older versions.
+        let body = [
+            encode_segment(
+                SegmentHeader::new("/var/log/a.log", "", 0, 23),
+                b"Mary had a little lamb\n",
+            )?,
+            encode_segment(
+                SegmentHeader::new("/var/log/b.log", "app", 10, 34),
+                b"\0woodpecker-segment\x01{}\n#woodpecker-segment {}\n",
+            )?,
+        ]
+        .concat();
+        let blob = encode_upload(&header, &body)?;
+
+        let upload = decode(&blob)?;
+        assert_eq!(header, upload.header);
+        assert_eq!(2, upload.segments.len());
+        let (segment, payload) = &upload.segments[0];
+        assert_eq!("/var/log/a.log", segment.path);
+        assert_eq!(
+            (Some(0), Some(23)),
+            (segment.start_offset, segment.end_offset)
+        );
+        assert_eq!(b"Mary had a little lamb\n", payload);
+        assert_eq!("app", upload.segments[1].0.schema_id);
+        assert_eq!(
+            b"\0woodpecker-segment\x01{}\n#woodpecker-segment {}\n",
+            upload.segments[1].1
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn without_headers() -> Result<()> {
+        let upload = decode(b"Mary had a little lamb\n")?;
+        assert_eq!(UploadHeader::default(), upload.header);
+        assert_eq!(
+            vec![(
+                SegmentHeader {
+                    length: 23,
+                    ..Default::default()
+                },
+                &b"Mary had a little lamb\n"[..]
+            )],
+            upload.segments
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn truncated() -> Result<()> {
+        let segment = encode_segment(
+            SegmentHeader::new("a.log", "", 0, 23),
+            b"Mary had a little lamb\n",
+        )?;
+        assert!(decode(&segment[..segment.len() - 1]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn legacy_text_like_a_header() -> Result<()> {
+        // Uploads of older agents may start like the text headers of older versions.
+        let blob = b"#woodpecker-segment {\"length\": 0}\nMary had a little lamb\n";
+        let upload = decode(blob)?;
+        assert_eq!(1, upload.segments.len());
+        assert_eq!(&blob[..], upload.segments[0].1);
+
+        // Headers of another version are not read as events.
+        assert!(decode(b"\0woodpecker-segment\x02{}\n").is_err());
+        Ok(())
+    }
+}